@@ -0,0 +1,314 @@
+// Reading deb822 files (Packages, dpkg's status, apt's extended_states and preferences) paragraph
+// by paragraph, as deb822(5) describes them.
+//
+// A paragraph is a run of non-blank lines; blank lines, and lines of nothing but spaces and tabs,
+// separate paragraphs. Each field starts with "Name: value" at the start of a line, and goes on over
+// the following lines that start with a space or a tab. Lines starting with # are comments. A
+// malformed paragraph is reported once, with the line of its first problem, and skipped as a whole:
+// the next paragraph is read as usual.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead};
+
+/// A field of a paragraph, as written: value has the text after the colon, with continuation lines following it
+/// after a newline each. Leading and trailing whitespace is removed from the first line and trailing whitespace from
+/// the continuation lines, which keep their indentation.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Field {
+    pub name : String,
+    pub value : String,
+    pub line : usize, // where the field starts, counting from 1
+}
+
+impl Field {
+    /// Returns the value of a folded field (such as Depends), for which line breaks don't matter: its words, joined
+    /// by single spaces.
+    pub fn folded(&self) -> String {
+        fold(&self.value)
+    }
+}
+
+fn fold(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// A paragraph (or stanza) of a deb822 file, with all of its fields, in order.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Paragraph {
+    pub fields : Vec<Field>,
+    pub line : usize, // where the paragraph starts
+}
+
+impl Paragraph {
+    /// Returns the field called name; field names are case-insensitive.
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns the value of the field called name, as Field::value.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.field(name).map(|f| f.value.as_str())
+    }
+}
+
+/// What is wrong with a paragraph.
+#[derive(Debug)]
+pub enum ParagraphError {
+    Io(io::Error),
+    NoColon(String), // a line that is neither a field nor a continuation line
+    BadFieldName(String), // empty, or with whitespace in it
+    ContinuationWithoutField, // an indented line that doesn't follow a field
+    DuplicateField(String),
+}
+
+impl fmt::Display for ParagraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParagraphError::Io(e) => write!(f, "{}", e),
+            ParagraphError::NoColon(l) => write!(f, "expected a field, found {:?}", l),
+            ParagraphError::BadFieldName(name) => write!(f, "bad field name {:?}", name),
+            ParagraphError::ContinuationWithoutField => write!(f, "continuation line outside a field"),
+            ParagraphError::DuplicateField(name) => write!(f, "duplicate {} field", name)
+        }
+    }
+}
+
+/// A paragraph that couldn't be read, and the line of its first problem.
+#[derive(Debug)]
+pub struct ParseError {
+    pub line : usize,
+    pub error : ParagraphError,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+impl Error for ParseError {}
+
+/// The paragraphs of a deb822 file, in order; see paragraphs.
+pub struct Paragraphs<R> {
+    lines : io::Lines<R>,
+    line : usize, // the number of lines read so far
+    done : bool, // after a read error, nothing more is returned
+}
+
+/// Reads the paragraphs of a deb822 file from reader.
+pub fn paragraphs<R: BufRead>(reader: R) -> Paragraphs<R> {
+    Paragraphs { lines: reader.lines(), line: 0, done: false }
+}
+
+fn is_blank(line: &str) -> bool {
+    line.chars().all(|c| c == ' ' || c == '\t' || c == '\r')
+}
+
+// what a non-blank line of a paragraph is
+enum Line<'a> {
+    Comment,
+    Continuation,
+    Field(&'a str, &'a str), // name and value, both trimmed
+    Bad(ParagraphError),
+}
+
+fn classify(line: &str) -> Line<'_> {
+    if line.starts_with('#') {
+        return Line::Comment;
+    }
+    if line.starts_with(' ') || line.starts_with('\t') {
+        return Line::Continuation;
+    }
+    match line.split_once(':') {
+        None => Line::Bad(ParagraphError::NoColon(String::from(line))),
+        Some((name, _)) if name.trim_end().is_empty() || name.trim_end().contains(char::is_whitespace) => Line::Bad(ParagraphError::BadFieldName(String::from(name))),
+        Some((name, value)) => Line::Field(name.trim_end(), value.trim())
+    }
+}
+
+impl<R: BufRead> Iterator for Paragraphs<R> {
+    type Item = Result<Paragraph, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut paragraph = Paragraph { fields: vec![], line: 0 };
+        let mut error: Option<ParseError> = None;
+        loop {
+            let line = match self.lines.next() {
+                None => break,
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(ParseError { line: self.line + 1, error: ParagraphError::Io(e) }));
+                }
+                Some(Ok(line)) => line
+            };
+            self.line += 1;
+            if is_blank(&line) {
+                if paragraph.line == 0 {
+                    continue;
+                }
+                break;
+            }
+            let kind = classify(&line);
+            if let Line::Comment = kind {
+                continue;
+            }
+            if paragraph.line == 0 {
+                paragraph.line = self.line;
+            }
+            if error.is_some() {
+                // skip the rest of a malformed paragraph
+                continue;
+            }
+            let problem = match kind {
+                Line::Comment => None,
+                Line::Continuation => match paragraph.fields.last_mut() {
+                    None => Some(ParagraphError::ContinuationWithoutField),
+                    Some(field) => {
+                        field.value.push('\n');
+                        field.value.push_str(line.trim_end());
+                        None
+                    }
+                },
+                Line::Field(name, _) if paragraph.field(name).is_some() => Some(ParagraphError::DuplicateField(String::from(name))),
+                Line::Field(name, value) => {
+                    paragraph.fields.push(Field { name: String::from(name), value: String::from(value), line: self.line });
+                    None
+                }
+                Line::Bad(problem) => Some(problem)
+            };
+            if let Some(problem) = problem {
+                error = Some(ParseError { line: self.line, error: problem });
+            }
+        }
+        if let Some(error) = error {
+            return Some(Err(error));
+        }
+        if paragraph.line == 0 {
+            return None;
+        }
+        Some(Ok(paragraph))
+    }
+}
+
+/// A field of a paragraph borrowed from the text it was read from. A field with continuation lines has a value
+/// running from its first line to the end of its last one, newlines and indentation included, so a value is only
+/// the same as Field::value if no line of it ends in whitespace.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BorrowedField<'a> {
+    pub name : &'a str,
+    pub value : &'a str,
+    pub line : usize,
+}
+
+/// A paragraph of fields borrowed from the text it was read from; see text_paragraphs.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BorrowedParagraph<'a> {
+    pub fields : Vec<BorrowedField<'a>>,
+    pub line : usize,
+}
+
+impl BorrowedField<'_> {
+    /// Returns the value of a folded field, as Field::folded.
+    pub fn folded(&self) -> String {
+        fold(self.value)
+    }
+}
+
+impl<'a> BorrowedParagraph<'a> {
+    /// Returns the field called name; field names are case-insensitive.
+    pub fn field(&self, name: &str) -> Option<&BorrowedField<'a>> {
+        self.fields.iter().find(|f| f.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns the value of the field called name, as BorrowedField::value.
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.field(name).map(|f| f.value)
+    }
+}
+
+impl Paragraph {
+    /// Returns a view of this paragraph as a BorrowedParagraph, so that code can handle both alike.
+    pub fn borrow(&self) -> BorrowedParagraph<'_> {
+        let fields = self.fields.iter().map(|f| BorrowedField { name: &f.name, value: &f.value, line: f.line }).collect();
+        BorrowedParagraph { fields, line: self.line }
+    }
+}
+
+/// The paragraphs of a deb822 text held in memory, in order, without copying it; see text_paragraphs.
+pub struct TextParagraphs<'a> {
+    text : &'a str,
+    pos : usize, // where the next line starts
+    line : usize, // the number of the next line
+}
+
+/// Reads the paragraphs of text, as paragraphs does, but borrowing their fields from text. Lines are numbered from
+/// first_line, so that a piece of a larger text can be read on its own.
+pub fn text_paragraphs(text: &str, first_line: usize) -> TextParagraphs<'_> {
+    TextParagraphs { text, pos: 0, line: first_line }
+}
+
+impl<'a> Iterator for TextParagraphs<'a> {
+    type Item = Result<BorrowedParagraph<'a>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let text = self.text;
+        let mut paragraph = BorrowedParagraph { fields: vec![], line: 0 };
+        let mut error: Option<ParseError> = None;
+        let mut value_start = 0; // where the value of the last field starts in text, so continuation lines can extend it
+        while self.pos < text.len() {
+            let start = self.pos;
+            let end = text[start..].find('\n').map_or(text.len(), |i| start + i);
+            let line = &text[start..end];
+            let lineno = self.line;
+            self.pos = end + 1;
+            self.line += 1;
+            if is_blank(line) {
+                if paragraph.line == 0 {
+                    continue;
+                }
+                break;
+            }
+            let kind = classify(line);
+            if let Line::Comment = kind {
+                continue;
+            }
+            if paragraph.line == 0 {
+                paragraph.line = lineno;
+            }
+            if error.is_some() {
+                continue;
+            }
+            let problem = match kind {
+                Line::Comment => None,
+                Line::Continuation => match paragraph.fields.last_mut() {
+                    None => Some(ParagraphError::ContinuationWithoutField),
+                    Some(field) => {
+                        field.value = &text[value_start..start + line.trim_end().len()];
+                        None
+                    }
+                },
+                Line::Field(name, _) if paragraph.field(name).is_some() => Some(ParagraphError::DuplicateField(String::from(name))),
+                Line::Field(name, value) => {
+                    value_start = value.as_ptr() as usize - text.as_ptr() as usize;
+                    paragraph.fields.push(BorrowedField { name, value, line: lineno });
+                    None
+                }
+                Line::Bad(problem) => Some(problem)
+            };
+            if let Some(problem) = problem {
+                error = Some(ParseError { line: lineno, error: problem });
+            }
+        }
+        if let Some(error) = error {
+            return Some(Err(error));
+        }
+        if paragraph.line == 0 {
+            return None;
+        }
+        Some(Ok(paragraph))
+    }
+}
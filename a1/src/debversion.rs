@@ -1,8 +1,40 @@
+use std::error::Error;
 use std::fmt;
-use std::string::ParseError;
+use std::cmp::Ordering;
 use std::cmp::Ordering::{Less, Equal, Greater};
 use std::str::FromStr;
 
+use serde::{Deserialize, Serialize};
+
+/// Why a version number or version relation couldn't be parsed.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum VersionError {
+    BadRelation(String), // not one of <<, <=, =, >=, >>
+    Empty,
+    BadEpoch(String), // the part before the first colon isn't an unsigned integer
+    EmptyUpstream,
+    UpstreamStartsWithNonDigit(String),
+    BadCharacter(char, &'static str), // the character and the part of the version it appears in
+    EmptyRevision, // a trailing hyphen
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VersionError::BadRelation(s) => write!(f, "bad version relation {:?}", s),
+            VersionError::Empty => write!(f, "empty version"),
+            VersionError::BadEpoch(e) => write!(f, "epoch {:?} is not a number", e),
+            VersionError::EmptyUpstream => write!(f, "empty upstream version"),
+            VersionError::UpstreamStartsWithNonDigit(u) => write!(f, "upstream version {:?} doesn't start with a digit", u),
+            VersionError::BadCharacter(c, part) => write!(f, "invalid character {:?} in {}", c, part),
+            VersionError::EmptyRevision => write!(f, "empty Debian revision")
+        }
+    }
+}
+
+impl Error for VersionError {}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum VersionRelation {
     StrictlyLess, // <<
     LessOrEqual, // <=
@@ -12,6 +44,7 @@ pub enum VersionRelation {
 }
 
 impl fmt::Display for VersionRelation {
+    #[allow(clippy::needless_return)]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         return match self {
             VersionRelation::StrictlyLess =>    write!(f, "<<"),
@@ -24,7 +57,7 @@ impl fmt::Display for VersionRelation {
 }
 
 impl FromStr for VersionRelation {
-    type Err = ParseError;
+    type Err = VersionError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
@@ -33,13 +66,14 @@ impl FromStr for VersionRelation {
             "=" =>  Ok(VersionRelation::Equal),
             ">=" => Ok(VersionRelation::GreaterOrEqual),
             ">>" => Ok(VersionRelation::StrictlyGreater),
-            _ => panic!("bad version relation {}", s) // gah this should be a ParseError but I don't know how to do that, accepting PRs
+            _ => Err(VersionError::BadRelation(String::from(s)))
         }
     }
 }
 
 
-#[derive(PartialEq,Eq)]
+// Default gives the empty version, which never parses but serves as a placeholder until a Version field is seen
+#[derive(Clone,Debug,Default,Serialize,Deserialize)]
 pub struct DebianVersionNum {
     epoch : String,
     upstream : String,
@@ -54,10 +88,42 @@ impl fmt::Display for DebianVersionNum {
     }
 }
 
+// checks the parts of a version against Debian Policy 5.6.12: the epoch is an unsigned integer, the upstream
+// version starts with a digit and has only alphanumerics and . + - ~ : (hyphens and colons can only be there
+// if there is a revision or an epoch, since the version is split at the last hyphen and first colon), and the
+// revision has only alphanumerics and . + ~
+fn validate(epoch: Option<&str>, upstream: &str, debian: Option<&str>) -> Result<(), VersionError> {
+    if let Some(e) = epoch {
+        if e.is_empty() || !e.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(VersionError::BadEpoch(String::from(e)));
+        }
+    }
+    match upstream.chars().next() {
+        None => return Err(VersionError::EmptyUpstream),
+        Some(c) if !c.is_ascii_digit() => return Err(VersionError::UpstreamStartsWithNonDigit(String::from(upstream))),
+        _ => ()
+    }
+    if let Some(c) = upstream.chars().find(|c| !c.is_ascii_alphanumeric() && !".+-~:".contains(*c)) {
+        return Err(VersionError::BadCharacter(c, "upstream version"));
+    }
+    if let Some(d) = debian {
+        if d.is_empty() {
+            return Err(VersionError::EmptyRevision);
+        }
+        if let Some(c) = d.chars().find(|c| !c.is_ascii_alphanumeric() && !".+~".contains(*c)) {
+            return Err(VersionError::BadCharacter(c, "Debian revision"));
+        }
+    }
+    Ok(())
+}
+
 impl FromStr for DebianVersionNum {
-    type Err = ParseError;
+    type Err = VersionError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(VersionError::Empty);
+        }
         let (ep, rest0) = match s.find(':') {
             None => ("", s),
             Some(e) => { let (_e, _r) = s.split_at(e); (_e, &_r[1..]) }
@@ -66,6 +132,7 @@ impl FromStr for DebianVersionNum {
             None => (rest0, ""),
             Some(d) => { let (_u, _d) = rest0.split_at(d); (_u, &_d[1..]) }
         };
+        validate(s.find(':').map(|_| ep), up, rest0.rfind('-').map(|_| deb))?;
         Ok(DebianVersionNum {
             epoch : ep.to_string(),
             upstream: up.to_string(),
@@ -74,112 +141,86 @@ impl FromStr for DebianVersionNum {
     }
 }
 
-impl Ord for DebianVersionNum {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+// the weight of a character in the non-digit part of a version, as in dpkg's order(): the end of the part and
+// digits (which end it) weigh 0, ~ sorts before even that, letters sort before everything else
+fn order(c: Option<&u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_alphabetic() => i32::from(*c),
+        Some(c) => i32::from(*c) + 256
     }
 }
 
-impl PartialOrd for DebianVersionNum {
-    // https://www.debian.org/doc/debian-policy/ch-controlfields.html#version
-    // wow this is painful
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        fn extract_nonnum(s: &str) -> (&str,&str) {
-            let first_digit = s.find(|c:char| c.is_ascii_digit());
-            match first_digit {
-                None => (s, ""),
-                Some(fd) => {
-                    let (nonnum, rest) = s.split_at(fd);
-                    return (nonnum, rest);
-                }
+// dpkg's verrevcmp: compares alternating non-digit and digit runs. Digit runs are compared as numbers of any
+// length, by skipping leading zeros and then looking at the number of digits, then the first differing digit.
+fn verrevcmp(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    let digit_at = |s: &[u8], k: usize| s.get(k).is_some_and(u8::is_ascii_digit);
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !digit_at(a, i)) || (j < b.len() && !digit_at(b, j)) {
+            let (ac, bc) = (order(a.get(i)), order(b.get(j)));
+            if ac != bc {
+                return ac.cmp(&bc);
             }
+            i += 1;
+            j += 1;
         }
-        fn extract_num(s: &str) -> (&str,&str) {
-            let first_nondigit = s.find(|c:char| !c.is_ascii_digit());
-            match first_nondigit {
-                None => (s, ""),
-                Some(fd) => {
-                    let (num, rest) = s.split_at(fd);
-                    return (num, rest);
-                }
-            }
+        while a.get(i) == Some(&b'0') {
+            i += 1;
         }
-
-        fn to_debian_chars(s: &str) -> Vec<i32> {
-            let mut v = vec![];
-            // all the letters sort earlier than all the non-letters and so that a tilde sorts before anything, even the end of a part
-            // . + - ~
-            for c in s.bytes() {
-                let cc:i32 = match c {
-                    46 /* '.' */ => 256+46,
-                    43 /* '+' */ => 256+43,
-                    45 /* '-' */ => 256+45,
-                    126 /* '~' */ => -1,
-                    _ => i32::from(c) };
-                v.push(cc);
-            }
-            v
+        while b.get(j) == Some(&b'0') {
+            j += 1;
         }
-
-        fn debian_nonnum_cmp(s: &str, o: &str) -> std::cmp::Ordering {
-            let (d_s, d_o) = (to_debian_chars(s), to_debian_chars(o));
-            for it in d_s.iter().zip(d_o.iter()) {
-                let (c_s, c_o) = it;
-                if c_s < c_o { return Less; }
-                if c_s > c_o { return Greater; }
+        let mut first_diff = Equal;
+        while digit_at(a, i) && digit_at(b, j) {
+            if first_diff == Equal {
+                first_diff = a[i].cmp(&b[j]);
             }
-            // aa < aaa
-            if s.len() < o.len() { return Less; }
-            // aa~ < aa
-            if s.len() > o.len() && s.ends_with('~') { return Less; }
-            // aaa > aa
-            if s.len() > o.len() { return Greater; }
-            Equal
-        }
-
-        fn debian_cmp(self_vers: &str, other_vers: &str) -> std::cmp::Ordering {
-            let mut sv = self_vers;
-            let mut ov = other_vers;
-            loop {
-                if sv.is_empty() && ov.is_empty() {
-                    return Equal;
-                }
-                let (self_nonnum, self_rest) = extract_nonnum(sv);
-                let (other_nonnum, other_rest) = extract_nonnum(ov);
-                match debian_nonnum_cmp(self_nonnum, other_nonnum) {
-                    Less => return Less,
-                    Greater => return Greater,
-                    _ => ()
-                }
+            i += 1;
+            j += 1;
+        }
+        if digit_at(a, i) {
+            return Greater;
+        }
+        if digit_at(b, j) {
+            return Less;
+        }
+        if first_diff != Equal {
+            return first_diff;
+        }
+    }
+    Equal
+}
 
-                let (self_num, self_rest1) = extract_num(self_rest);
-                let (other_num, other_rest1) = extract_num(other_rest);
+// versions which compare equal are equal, even if written differently (1.0 and 1.00, 0:1 and 1)
+impl PartialEq for DebianVersionNum {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Equal
+    }
+}
 
-                let (sn_i, on_i) = (
-                    match self_num.parse::<i32>() { Err(_) => 0, Ok(e) => e },
-                    match other_num.parse::<i32>() { Err(_) => 0, Ok(e) => e });
+impl Eq for DebianVersionNum {}
 
-                if sn_i != on_i {
-                    return sn_i.partial_cmp(&on_i).unwrap();
-                }
-                sv = self_rest1; ov = other_rest1;
-            }
-        }
+impl Ord for DebianVersionNum {
+    // https://www.debian.org/doc/debian-policy/ch-controlfields.html#version
+    // the epochs are compared as numbers, then the upstream versions and the revisions with verrevcmp, as dpkg does
+    fn cmp(&self, other: &Self) -> Ordering {
+        verrevcmp(&self.epoch, &other.epoch)
+            .then_with(|| verrevcmp(&self.upstream, &other.upstream))
+            .then_with(|| verrevcmp(&self.debian, &other.debian))
+    }
+}
 
-        let (epoch, other_epoch) = (
-            match self.epoch.parse::<i32>() { Err(_) => 0, Ok(e) => e },
-            match other.epoch.parse::<i32>() { Err(_) => 0, Ok(e) => e });
-        if epoch != other_epoch {
-            return Some(epoch.partial_cmp(&other_epoch).unwrap());
-        }
-        let ups = debian_cmp(&self.upstream, &other.upstream);
-        if ups != Equal {
-            return Some(ups);
-        }
-        return Some(debian_cmp(&self.debian, &other.debian));
+impl PartialOrd for DebianVersionNum {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
+#[allow(clippy::needless_return)]
 pub fn cmp_debversion_with_op(op:&VersionRelation, first: &DebianVersionNum, second: &DebianVersionNum) -> bool {
     return match op {
         VersionRelation::StrictlyLess => first < second,
@@ -189,3 +230,208 @@ pub fn cmp_debversion_with_op(op:&VersionRelation, first: &DebianVersionNum, sec
         VersionRelation::StrictlyGreater => first > second
     }
 }
+
+/// One end of a VersionRange.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Bound {
+    Unbounded,
+    Inclusive(DebianVersionNum),
+    Exclusive(DebianVersionNum),
+}
+
+// orders lower bounds by how much they let in from below, least restrictive first
+fn cmp_lower(a: &Bound, b: &Bound) -> Ordering {
+    match (a, b) {
+        (Bound::Unbounded, Bound::Unbounded) => Equal,
+        (Bound::Unbounded, _) => Less,
+        (_, Bound::Unbounded) => Greater,
+        (Bound::Inclusive(v), Bound::Inclusive(w)) | (Bound::Exclusive(v), Bound::Exclusive(w)) => v.cmp(w),
+        (Bound::Inclusive(v), Bound::Exclusive(w)) => v.cmp(w).then(Less),
+        (Bound::Exclusive(v), Bound::Inclusive(w)) => v.cmp(w).then(Greater)
+    }
+}
+
+// orders upper bounds by how much they let in from above, most restrictive first
+fn cmp_upper(a: &Bound, b: &Bound) -> Ordering {
+    match (a, b) {
+        (Bound::Unbounded, Bound::Unbounded) => Equal,
+        (Bound::Unbounded, _) => Greater,
+        (_, Bound::Unbounded) => Less,
+        (Bound::Inclusive(v), Bound::Inclusive(w)) | (Bound::Exclusive(v), Bound::Exclusive(w)) => v.cmp(w),
+        (Bound::Inclusive(v), Bound::Exclusive(w)) => v.cmp(w).then(Greater),
+        (Bound::Exclusive(v), Bound::Inclusive(w)) => v.cmp(w).then(Less)
+    }
+}
+
+// whether a range ending at upper leaves no gap before a range starting at lower
+fn meets(upper: &Bound, lower: &Bound) -> bool {
+    match (upper, lower) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => true,
+        (Bound::Exclusive(v), Bound::Exclusive(w)) => v > w,
+        (Bound::Inclusive(v), Bound::Inclusive(w)) | (Bound::Inclusive(v), Bound::Exclusive(w))
+            | (Bound::Exclusive(v), Bound::Inclusive(w)) => v >= w
+    }
+}
+
+/// The versions between two bounds. Versions are treated as dense: there is taken to be a version strictly between
+/// any two different ones, so (>> 1.0, << 1.0+) is not empty.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VersionRange {
+    pub lower : Bound,
+    pub upper : Bound,
+}
+
+impl VersionRange {
+    pub fn any() -> VersionRange {
+        VersionRange { lower: Bound::Unbounded, upper: Bound::Unbounded }
+    }
+
+    /// The versions v for which "v op version" holds, e.g. [1.2, infinity) for >= 1.2.
+    pub fn from_relation(op: &VersionRelation, version: &DebianVersionNum) -> VersionRange {
+        let (lower, upper) = match op {
+            VersionRelation::StrictlyLess => (Bound::Unbounded, Bound::Exclusive(version.clone())),
+            VersionRelation::LessOrEqual => (Bound::Unbounded, Bound::Inclusive(version.clone())),
+            VersionRelation::Equal => (Bound::Inclusive(version.clone()), Bound::Inclusive(version.clone())),
+            VersionRelation::GreaterOrEqual => (Bound::Inclusive(version.clone()), Bound::Unbounded),
+            VersionRelation::StrictlyGreater => (Bound::Exclusive(version.clone()), Bound::Unbounded)
+        };
+        VersionRange { lower, upper }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match (&self.lower, &self.upper) {
+            (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+            (Bound::Inclusive(v), Bound::Inclusive(w)) => v > w,
+            (Bound::Inclusive(v), Bound::Exclusive(w)) | (Bound::Exclusive(v), Bound::Inclusive(w))
+                | (Bound::Exclusive(v), Bound::Exclusive(w)) => v >= w
+        }
+    }
+
+    pub fn contains(&self, version: &DebianVersionNum) -> bool {
+        let above = match &self.lower {
+            Bound::Unbounded => true,
+            Bound::Inclusive(v) => version >= v,
+            Bound::Exclusive(v) => version > v
+        };
+        let below = match &self.upper {
+            Bound::Unbounded => true,
+            Bound::Inclusive(v) => version <= v,
+            Bound::Exclusive(v) => version < v
+        };
+        above && below
+    }
+
+    /// The versions in both ranges; the result may be empty.
+    pub fn intersect(&self, other: &VersionRange) -> VersionRange {
+        let lower = if cmp_lower(&self.lower, &other.lower) == Greater { &self.lower } else { &other.lower };
+        let upper = if cmp_upper(&self.upper, &other.upper) == Less { &self.upper } else { &other.upper };
+        VersionRange { lower: lower.clone(), upper: upper.clone() }
+    }
+}
+
+// formats like the version relations that make up the range: "= 1.5-1", ">= 1.2, << 2.0", "any"
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let (Bound::Inclusive(v), Bound::Inclusive(w)) = (&self.lower, &self.upper) {
+            if v == w {
+                return write!(f, "= {}", v);
+            }
+        }
+        let lower = match &self.lower {
+            Bound::Unbounded => None,
+            Bound::Inclusive(v) => Some(format!(">= {}", v)),
+            Bound::Exclusive(v) => Some(format!(">> {}", v))
+        };
+        let upper = match &self.upper {
+            Bound::Unbounded => None,
+            Bound::Inclusive(v) => Some(format!("<= {}", v)),
+            Bound::Exclusive(v) => Some(format!("<< {}", v))
+        };
+        match (lower, upper) {
+            (None, None) => write!(f, "any"),
+            (Some(l), None) => write!(f, "{}", l),
+            (None, Some(u)) => write!(f, "{}", u),
+            (Some(l), Some(u)) => write!(f, "{}, {}", l, u)
+        }
+    }
+}
+
+/// A set of versions, kept as a union of disjoint, non-empty ranges in increasing order. Intersecting the sets of
+/// several version constraints tells whether any version can satisfy all of them.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VersionSet {
+    ranges : Vec<VersionRange>,
+}
+
+impl VersionSet {
+    pub fn any() -> VersionSet {
+        VersionSet { ranges: vec![VersionRange::any()] }
+    }
+
+    pub fn none() -> VersionSet {
+        VersionSet { ranges: vec![] }
+    }
+
+    pub fn from_range(range: VersionRange) -> VersionSet {
+        VersionSet::from_ranges(vec![range])
+    }
+
+    pub fn from_relation(op: &VersionRelation, version: &DebianVersionNum) -> VersionSet {
+        VersionSet::from_range(VersionRange::from_relation(op, version))
+    }
+
+    // sorts the ranges, drops empty ones and merges the ones that overlap or touch
+    fn from_ranges(mut ranges: Vec<VersionRange>) -> VersionSet {
+        ranges.retain(|r| !r.is_empty());
+        ranges.sort_by(|a, b| cmp_lower(&a.lower, &b.lower));
+        let mut merged: Vec<VersionRange> = vec![];
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if meets(&last.upper, &range.lower) => {
+                    if cmp_upper(&range.upper, &last.upper) == Greater {
+                        last.upper = range.upper;
+                    }
+                }
+                _ => merged.push(range)
+            }
+        }
+        VersionSet { ranges: merged }
+    }
+
+    pub fn ranges(&self) -> &[VersionRange] {
+        &self.ranges
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn contains(&self, version: &DebianVersionNum) -> bool {
+        self.ranges.iter().any(|r| r.contains(version))
+    }
+
+    pub fn intersect(&self, other: &VersionSet) -> VersionSet {
+        let mut ranges = vec![];
+        for a in &self.ranges {
+            for b in &other.ranges {
+                ranges.push(a.intersect(b));
+            }
+        }
+        VersionSet::from_ranges(ranges)
+    }
+
+    pub fn union(&self, other: &VersionSet) -> VersionSet {
+        VersionSet::from_ranges(self.ranges.iter().chain(other.ranges.iter()).cloned().collect())
+    }
+}
+
+// formats as its ranges separated by " | ", or "none"
+impl fmt::Display for VersionSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.ranges.is_empty() {
+            return write!(f, "none");
+        }
+        let ranges: Vec<String> = self.ranges.iter().map(|r| r.to_string()).collect();
+        write!(f, "{}", ranges.join(" | "))
+    }
+}
@@ -1 +1,2 @@
+pub mod deb822;
 pub mod debversion;
@@ -2,10 +2,14 @@ use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 use rpkg::debversion;
-use crate::packages::Packages;
+use crate::packages::{GraphFormat, InstallStrategy, Packages, RelationPolicy, CACHE_FILE, DEFAULT_PRIORITY, STATS_LIMIT};
 
 mod packages;
 
+// the Packages and status files load-defaults loads, in that order
+const DEFAULT_FILES: [&str; 2] = ["data/mirror.csclub.uwaterloo.ca_debian_dists_sid_main_binary-amd64_Packages", "data/installed-packages"];
+
+#[allow(clippy::needless_return, clippy::get_first, clippy::needless_borrow)]
 fn check_syntax(n: usize, cmd_fragments:&Vec<&str>, arg: &str) -> bool {
     let cmd : &str = &cmd_fragments.get(0).unwrap();
     if cmd_fragments.len() != n {
@@ -15,6 +19,57 @@ fn check_syntax(n: usize, cmd_fragments:&Vec<&str>, arg: &str) -> bool {
     return true
 }
 
+// separates --options from the command and its positional arguments
+fn split_options<'a>(cmd_fragments: &[&'a str]) -> (Vec<&'a str>, Vec<&'a str>) {
+    cmd_fragments.iter().partition(|f| !f.starts_with("--"))
+}
+
+// removes the --name=value options from options, returning the last value
+fn take_option<'a>(options: &mut Vec<&'a str>, name: &str) -> Option<&'a str> {
+    let prefix = format!("--{}=", name);
+    let values: Vec<&str> = options.iter().filter_map(|o| o.strip_prefix(prefix.as_str())).collect();
+    options.retain(|o| !o.starts_with(&prefix));
+    values.last().copied()
+}
+
+// parses a size such as 1500000, 1500kB or 1.5MB (powers of 1000, as printed) into bytes
+fn parse_size(cmd: &str, size: &str) -> Option<u64> {
+    let split = size.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier = match unit.to_ascii_lowercase().trim_end_matches('b') {
+        "" => 1.0,
+        "k" => 1e3,
+        "m" => 1e6,
+        "g" => 1e9,
+        _ => f64::NAN
+    };
+    match number.parse::<f64>() {
+        Ok(n) if !multiplier.is_nan() => Some((n * multiplier) as u64),
+        _ => {
+            println!("{}: bad size {}", cmd, size);
+            None
+        }
+    }
+}
+
+// applies --with-/--no- options to the given starting policy
+fn parse_policy(cmd: &str, options: &[&str], mut policy: RelationPolicy) -> Option<RelationPolicy> {
+    for option in options {
+        match *option {
+            "--with-recommends" => policy.recommends = true,
+            "--no-recommends" => policy.recommends = false,
+            "--with-suggests" => policy.suggests = true,
+            "--no-suggests" => policy.suggests = false,
+            _ => {
+                println!("{}: unknown option {}", cmd, option);
+                return None
+            }
+        }
+    }
+    Some(policy)
+}
+
+#[allow(clippy::needless_return, clippy::get_first, clippy::needless_borrow)]
 fn process_command(state: &mut Packages, cmdline: &str) -> bool {
     let cmd_fragments: Vec<&str> = cmdline.split(" ").collect();
     if cmdline.is_empty() { return false }
@@ -30,31 +85,96 @@ fn process_command(state: &mut Packages, cmdline: &str) -> bool {
         }
         // parsers.rs
         "load-packages" | "lp" => {
-            if !check_syntax(2, &cmd_fragments, "<pkgfile-name>") { return false; }
+            let priority = match cmd_fragments.get(3).map(|p| p.parse::<i32>()) {
+                None => Some(DEFAULT_PRIORITY),
+                Some(p) => p.ok()
+            };
+            if cmd_fragments.len() < 2 || cmd_fragments.len() > 4 || priority.is_none() {
+                println!("syntax: {} <pkgfile-name> [<suite> [<priority>]]", cmd);
+                return false
+            }
+            let arg = cmd_fragments.get(1).unwrap();
+            let source = state.add_source(cmd_fragments.get(2).unwrap_or(arg), priority.unwrap());
+            state.parse_packages(arg, source)
+        }
+        "bench-load" => {
+            let runs = match cmd_fragments.get(2).map(|n| n.parse::<usize>()) {
+                None => Some(3),
+                Some(n) => n.ok().filter(|n| *n > 0)
+            };
+            if cmd_fragments.len() < 2 || cmd_fragments.len() > 3 || runs.is_none() {
+                println!("syntax: {} <pkgfile-name> [<runs>]", cmd);
+                return false
+            }
             let arg = cmd_fragments.get(1).unwrap();
-            state.parse_packages(arg)
+            Packages::bench_load(arg, runs.unwrap())
+        }
+        "load-mirror" | "lm" => {
+            if cmd_fragments.len() < 3 {
+                println!("syntax: {} <mirror-dir> <suite> [<arch>...]", cmd);
+                return false
+            }
+            state.load_mirror(cmd_fragments[1], cmd_fragments[2], &cmd_fragments[3..])
+        }
+        "set-arch" => {
+            if !check_syntax(2, &cmd_fragments, "<native-architecture>") { return false; }
+            let arch = cmd_fragments.get(1).unwrap();
+            state.set_native_arch(arch)
+        }
+        "load-sources" | "ls" => {
+            if !check_syntax(2, &cmd_fragments, "<sources-file>") { return false; }
+            let arg = cmd_fragments.get(1).unwrap();
+            state.parse_sources(arg)
+        }
+        "load-preferences" | "lpref" => {
+            if !check_syntax(2, &cmd_fragments, "<preferences-file>") { return false; }
+            let arg = cmd_fragments.get(1).unwrap();
+            state.parse_preferences(arg)
         }
         "load-installed" | "li" => {
             if !check_syntax(2, &cmd_fragments, "<pkgfile-name>") { return false; }
             let arg = cmd_fragments.get(1).unwrap();
             state.parse_installed(arg)
         }
+        "load-extended-states" | "les" => {
+            if !check_syntax(2, &cmd_fragments, "<extended-states-file>") { return false; }
+            let arg = cmd_fragments.get(1).unwrap();
+            state.parse_extended_states(arg)
+        }
         // convenience function, also depends on parsers.rs
         "load-defaults" | "ld" => {
-            state.parse_packages("data/mirror.csclub.uwaterloo.ca_debian_dists_sid_main_binary-amd64_Packages");
-            state.parse_installed("data/installed-packages")
+            state.load_cached(CACHE_FILE, &DEFAULT_FILES, |state| {
+                let source = state.add_source("sid", DEFAULT_PRIORITY);
+                state.parse_packages(DEFAULT_FILES[0], source);
+                state.parse_installed(DEFAULT_FILES[1])
+            })
+        }
+        "cache-status" => {
+            if !check_syntax(1, &cmd_fragments, "") { return false; }
+            state.print_cache_status(CACHE_FILE, &DEFAULT_FILES)
         }
 
         "info" => {
+            if cmd_fragments.len() < 2 || cmd_fragments.len() > 3 {
+                println!("syntax: {} <pkg> [<version>]", cmd);
+                return false
+            }
+            let pkg = cmd_fragments.get(1).unwrap();
+            state.print_info(pkg, cmd_fragments.get(2).copied())
+        }
+        "policy" => {
             if !check_syntax(2, &cmd_fragments, "<pkg>") { return false; }
             let pkg = cmd_fragments.get(1).unwrap();
-            state.print_info(pkg)
+            state.print_policy(pkg)
         }
         "deps" => {
             // test: deps 0ad
-            if !check_syntax(2, &cmd_fragments, "<pkg>") { return false; }
+            if cmd_fragments.len() < 2 || cmd_fragments.len() > 3 {
+                println!("syntax: {} <pkg> [<version>]", cmd);
+                return false
+            }
             let pkg = cmd_fragments.get(1).unwrap();
-            state.print_deps(pkg)
+            state.print_deps(pkg, cmd_fragments.get(2).copied())
         }
 
         // deps-available.rs
@@ -68,14 +188,115 @@ fn process_command(state: &mut Packages, cmdline: &str) -> bool {
         // solvers.rs, and deps-available.rs for how-to-install
         "transitive-dep-solution" => {
             // test: transitive-dep-solution 0ad
+            let (args, options) = split_options(&cmd_fragments);
+            if !check_syntax(2, &args, "[--with-recommends] [--with-suggests] <pkg>") { return false; }
+            let policy = match parse_policy(cmd, &options, RelationPolicy::default()) { None => return false, Some(p) => p };
+            let pkg = args.get(1).unwrap();
+            state.print_transitive_dep_solution(pkg, &policy)
+        }
+        "how-to-install" => {
+            let (args, mut options) = split_options(&cmd_fragments);
+            if !check_syntax(2, &args, "[--with-recommends] [--with-suggests] [--strategy=<strategy>] [--max-size=<size>] <pkg>") { return false; }
+            let strategy = match take_option(&mut options, "strategy").map(|s| s.parse::<InstallStrategy>()) {
+                None => InstallStrategy::default(),
+                Some(Ok(strategy)) => strategy,
+                Some(Err(e)) => {
+                    println!("{}: {}", cmd, e);
+                    return false
+                }
+            };
+            let max_size = match take_option(&mut options, "max-size").map(|s| parse_size(cmd, s)) {
+                None => None,
+                Some(None) => return false,
+                Some(size) => size
+            };
+            let policy = match parse_policy(cmd, &options, RelationPolicy::default()) { None => return false, Some(p) => p };
+            let pkg = args.get(1).unwrap();
+            state.print_how_to_install(pkg, &policy, strategy, max_size)
+        }
+
+        // why.rs
+        "why" => {
+            let (args, options) = split_options(&cmd_fragments);
+            if args.len() < 2 || args.len() > 3 {
+                println!("syntax: {} [--with-recommends] [--with-suggests] <pkg> [<target>]", cmd);
+                return false
+            }
+            let policy = match parse_policy(cmd, &options, RelationPolicy::default()) { None => return false, Some(p) => p };
+            let pkg = args.get(1).unwrap();
+            state.print_why(pkg, args.get(2).copied(), &policy)
+        }
+        "why-not" => {
             if !check_syntax(2, &cmd_fragments, "<pkg>") { return false; }
             let pkg = cmd_fragments.get(1).unwrap();
-            state.print_transitive_dep_solution(pkg)
+            state.print_why_not(pkg)
         }
-        "how-to-install" => {
+
+        // graph.rs
+        "graph" => {
+            let (args, mut options) = split_options(&cmd_fragments);
+            if !check_syntax(3, &args, "[--with-recommends] [--with-suggests] [--format=<dot|json>] <pkg> <output-file>") { return false; }
+            let format = match take_option(&mut options, "format").map(|s| s.parse::<GraphFormat>()) {
+                None => None,
+                Some(Ok(format)) => Some(format),
+                Some(Err(e)) => {
+                    println!("{}: {}", cmd, e);
+                    return false
+                }
+            };
+            let policy = match parse_policy(cmd, &options, RelationPolicy::default()) { None => return false, Some(p) => p };
+            let pkg = args.get(1).unwrap();
+            let fname = args.get(2).unwrap();
+            state.export_graph(pkg, fname, format.unwrap_or_else(|| GraphFormat::for_file(fname)), &policy)
+        }
+
+        // stats.rs
+        "stats" => {
+            let limit = match cmd_fragments.get(2).map(|n| n.parse::<usize>()) {
+                None => Some(STATS_LIMIT),
+                Some(n) => n.ok()
+            };
+            if cmd_fragments.len() > 3 || limit.is_none() {
+                println!("syntax: {} [cycles|most-depended|depth|unsatisfiable [<n>]]", cmd);
+                return false
+            }
+            state.print_stats(cmd_fragments.get(1).copied(), limit.unwrap())
+        }
+
+        // rdepends.rs
+        "rdepends" => {
             if !check_syntax(2, &cmd_fragments, "<pkg>") { return false; }
             let pkg = cmd_fragments.get(1).unwrap();
-            state.print_how_to_install(pkg)
+            state.print_rdepends(pkg)
+        }
+        "remove-plan" => {
+            if !check_syntax(2, &cmd_fragments, "<pkg>") { return false; }
+            let pkg = cmd_fragments.get(1).unwrap();
+            state.print_remove_plan(pkg)
+        }
+        "upgrade-plan" => {
+            let (args, mut options) = split_options(&cmd_fragments);
+            if !check_syntax(1, &args, "[--max-size=<size>]") { return false; }
+            let max_size = match take_option(&mut options, "max-size").map(|s| parse_size(cmd, s)) {
+                None => None,
+                Some(None) => return false,
+                Some(size) => size
+            };
+            if let Some(option) = options.first() {
+                println!("{}: unknown option {}", cmd, option);
+                return false
+            }
+            state.print_upgrade_plan(max_size)
+        }
+
+        // autoremove.rs
+        "autoremove-plan" => {
+            let (args, options) = split_options(&cmd_fragments);
+            if !check_syntax(1, &args, "[--no-recommends] [--no-suggests]") { return false; }
+            // like apt, Recommends and Suggests keep packages installed unless told otherwise
+            let default = RelationPolicy { recommends: true, suggests: true };
+            let policy = match parse_policy(cmd, &options, default) { None => return false, Some(p) => p };
+            state.print_autoremove_plan(&policy)
         }
 
         "set-server" => {
@@ -108,9 +329,15 @@ fn process_command(state: &mut Packages, cmdline: &str) -> bool {
         }
         "test-version-compare" => {
             if !check_syntax(3, &cmd_fragments, "<version1> <version2>") { return false; }
-            let v1 = cmd_fragments.get(1).unwrap().parse::<debversion::DebianVersionNum>().unwrap();
-            let v2 = cmd_fragments.get(2).unwrap().parse::<debversion::DebianVersionNum>().unwrap();
-            println!("{} and {}: {:?}", v1, v2, v1.cmp(&v2));
+            let mut versions = vec![];
+            for v in &cmd_fragments[1..3] {
+                match v.parse::<debversion::DebianVersionNum>() {
+                    Ok(version) => versions.push(version),
+                    Err(e) => { println!("bad version {}: {}", v, e); return false; }
+                }
+            }
+            let (v1, v2) = (&versions[0], &versions[1]);
+            println!("{} and {}: {:?}", v1, v2, v1.cmp(v2));
             // 1:0.4.5+cvs20030824-9 vs 1:0.4.5+cvs20030824-10
             // a vs b
             // a vs a
@@ -126,6 +353,7 @@ fn process_command(state: &mut Packages, cmdline: &str) -> bool {
     return false;
 }
 
+#[allow(clippy::needless_ifs)]
 fn main() {
     let mut state : Packages = Packages::new();
 
@@ -9,8 +9,10 @@ use std::time::Duration;
 
 use crate::Packages;
 
+#[allow(clippy::box_collection)]
 struct Collector(Box<String>);
 impl Handler for Collector {
+    #[allow(clippy::unnecessary_to_owned)]
     fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
         (*self.0).push_str(str::from_utf8(&data.to_vec()).unwrap());
         Ok(data.len())
@@ -50,6 +52,7 @@ impl Packages {
     }
 
     /// Retrieves the version number of pkg and calls enq_verify_with_version with that version number.
+    #[allow(clippy::needless_return)]
     pub fn enq_verify(&mut self, pkg: &str) {
         let version = self.get_available_debver(pkg);
         match version {
@@ -65,6 +68,7 @@ impl Packages {
     }
 
     /// Enqueues a request for the provided version/package information. Stores any needed state to async_state so that execute() can handle the results and print out needed output.
+    #[allow(clippy::box_default)]
     pub fn enq_verify_with_version(&mut self, pkg: &str, version: &str) {
         let url = format!(
             "http://{}/rest/v1/checksums/{}/{}",
@@ -88,6 +92,14 @@ impl Packages {
         println!("queueing request {}", url);
     }
 
+    // md5sum of the candidate with the requested version, falling back to the best candidate's
+    fn local_md5sum(&self, pkg_num: i32, version: &str) -> Option<&str> {
+        let cand = self.get_candidates(pkg_num).iter().copied()
+            .find(|c| self.get_candidate(*c).version.to_string() == version)
+            .or_else(|| self.best_candidate(pkg_num))?;
+        self.get_candidate(cand).md5sum.as_deref()
+    }
+
     /// Asks curl to perform all enqueued requests. For requests that succeed with response code 200, compares received MD5sum with local MD5sum (perhaps stored earlier). For requests that fail with 400+, prints error message.
     pub fn execute(&mut self) {
         EASYKEY_COUNTER.store(0, Ordering::SeqCst);
@@ -101,14 +113,15 @@ impl Packages {
                 .unwrap();
         }
 
-        for easy in self.async_state.easy_vec.drain(..) {
+        let easy_handles: Vec<Easy2Handle<Collector>> = self.async_state.easy_vec.drain(..).collect();
+        for easy in easy_handles {
             let easy_key = EASYKEY_COUNTER.fetch_add(1, Ordering::SeqCst);
-            let mut easy_after = self.async_state.multi.remove2(easy).unwrap();
+            let easy_after = self.async_state.multi.remove2(easy).unwrap();
             let response_code = easy_after.response_code().unwrap();
             let (pkg, version, pkg_num) = self.async_state.combined_data.get(&easy_key).unwrap();
 
             if response_code == 200 {
-                let md5sum_local: &str = self.md5sums.get(&pkg_num).unwrap();
+                let md5sum_local: &str = self.local_md5sum(*pkg_num, version).unwrap();
                 let md5sum_response: &str = easy_after.get_ref().0.as_str();
                 let matches = md5sum_local == md5sum_response;
                 println!("verifying {}, matches: {:?}", pkg, matches);
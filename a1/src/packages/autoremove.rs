@@ -0,0 +1,200 @@
+// Finding automatically installed packages which are no longer needed, as apt-get autoremove does.
+//
+// Every installed package that wasn't marked Auto-Installed in extended_states (or that is Essential)
+// is a root. Starting from the roots, the relations of each reached package's installed version mark
+// every installed package satisfying one of its alternatives; auto-installed packages left unmarked
+// are orphans.
+
+use std::collections::{HashSet, VecDeque};
+
+use itertools::Itertools;
+
+use crate::packages::RelationPolicy;
+use crate::Packages;
+
+impl Packages {
+    /// Returns the installed packages reachable from the manually installed and Essential ones through the relations
+    /// policy says to follow. An installed package that no loaded Packages file lists has the relations of its status
+    /// stanza.
+    pub fn reachable_from_manual(&self, policy: &RelationPolicy) -> HashSet<i32> {
+        let mut marked: HashSet<i32> = self.installed_debvers.keys().copied()
+            .filter(|p| !self.auto_installed.contains(p) || self.essential.contains(p))
+            .collect();
+        let mut worklist: VecDeque<i32> = marked.iter().copied().collect();
+        while let Some(p) = worklist.pop_front() {
+            let iv = self.installed_version(p).unwrap();
+            let arch = self.package_arch(p);
+            for (_, dep) in self.followed_relations(iv.candidate, policy) {
+                for alternative in dep {
+                    for q in self.alternative_packages(alternative) {
+                        if marked.contains(&q) {
+                            continue;
+                        }
+                        if let Some(qv) = self.installed_version(q) {
+                            if self.satisfies(alternative, Some(arch), &qv) {
+                                marked.insert(q);
+                                worklist.push_back(q);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        marked
+    }
+
+    /// Returns the auto-installed packages that nothing manually installed needs any more, sorted by name.
+    pub fn compute_autoremove_plan(&self, policy: &RelationPolicy) -> Vec<i32> {
+        let marked = self.reachable_from_manual(policy);
+        self.auto_installed.iter().copied()
+            .filter(|p| self.installed_debvers.contains_key(p) && !marked.contains(p))
+            .sorted_by(|a, b| self.get_package_name(*a).cmp(self.get_package_name(*b)))
+            .collect_vec()
+    }
+
+    pub fn print_autoremove_plan(&self, policy: &RelationPolicy) {
+        let orphans = self.compute_autoremove_plan(policy);
+        if !orphans.is_empty() {
+            println!("The following packages were automatically installed and are no longer required:");
+            println!("  {}", orphans.iter().map(|p| self.get_package_name(*p)).format(" "));
+        }
+        println!("{} to remove.", orphans.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packages::RelationPolicy;
+    use crate::Packages;
+
+    const PACKAGES: &str = "\
+Package: editor
+Version: 1.0
+Depends: libedit
+Recommends: spell
+Suggests: editor-doc
+
+Package: libedit
+Version: 1.0
+
+Package: spell
+Version: 1.0
+Depends: dict
+
+Package: dict
+Version: 1.0
+
+Package: editor-doc
+Version: 1.0
+
+Package: oldlib
+Version: 1.0
+
+Package: base
+Version: 1.0
+Depends: libbase
+
+Package: libbase
+Version: 1.0
+
+Package: liblegacy
+Version: 1.0
+";
+
+    // everything above installed, and two packages that no Packages file lists any more: legacy, which still
+    // depends on liblegacy, and leftover
+    const STATUS: &str = "\
+Package: editor
+Status: install ok installed
+Version: 1.0
+
+Package: libedit
+Status: install ok installed
+Version: 1.0
+
+Package: spell
+Status: install ok installed
+Version: 1.0
+
+Package: dict
+Status: install ok installed
+Version: 1.0
+
+Package: editor-doc
+Status: install ok installed
+Version: 1.0
+
+Package: oldlib
+Status: install ok installed
+Version: 1.0
+
+Package: base
+Status: install ok installed
+Essential: yes
+Version: 1.0
+
+Package: libbase
+Status: install ok installed
+Version: 1.0
+
+Package: liblegacy
+Status: install ok installed
+Version: 1.0
+
+Package: legacy
+Status: install ok installed
+Version: 0.9
+Depends: liblegacy (>= 1.0)
+
+Package: leftover
+Status: install ok installed
+Version: 0.1
+";
+
+    // the fixture, with everything but editor and legacy marked auto-installed
+    fn packages() -> Packages {
+        let mut packages = Packages::from_text(PACKAGES, STATUS);
+        for name in ["libedit", "spell", "dict", "editor-doc", "oldlib", "base", "libbase", "liblegacy", "leftover"] {
+            let package_num = *packages.get_package_num(name);
+            packages.auto_installed.insert(package_num);
+        }
+        packages
+    }
+
+    fn plan(packages: &Packages, recommends: bool, suggests: bool) -> Vec<&str> {
+        packages.compute_autoremove_plan(&RelationPolicy { recommends, suggests })
+            .iter().map(|p| packages.get_package_name(*p)).collect()
+    }
+
+    #[test]
+    fn keeps_what_manual_and_essential_packages_need() {
+        let packages = packages();
+        let marked = packages.reachable_from_manual(&RelationPolicy { recommends: true, suggests: true });
+        for name in ["editor", "legacy", "base", "libedit", "libbase"] {
+            assert!(marked.contains(packages.get_package_num(name)), "{}", name);
+        }
+        assert_eq!(plan(&packages, true, true), vec!["leftover", "oldlib"]);
+    }
+
+    #[test]
+    fn recommends_and_suggests_keep_packages_unless_told_otherwise() {
+        let packages = packages();
+        assert_eq!(plan(&packages, false, true), vec!["dict", "leftover", "oldlib", "spell"]);
+        assert_eq!(plan(&packages, true, false), vec!["editor-doc", "leftover", "oldlib"]);
+        assert_eq!(plan(&packages, false, false), vec!["dict", "editor-doc", "leftover", "oldlib", "spell"]);
+    }
+
+    #[test]
+    fn follows_the_status_file_for_packages_no_packages_file_lists() {
+        let packages = packages();
+        let legacy = *packages.get_package_num("legacy");
+        assert!(packages.get_candidates(legacy).is_empty());
+        assert!(!plan(&packages, false, false).contains(&"liblegacy"));
+        // the version of liblegacy matters, as in a Packages file
+        let mut packages = Packages::from_text(PACKAGES, &STATUS.replace("Package: liblegacy\nStatus: install ok installed\nVersion: 1.0",
+            "Package: liblegacy\nStatus: install ok installed\nVersion: 0.5"));
+        let liblegacy = *packages.get_package_num("liblegacy");
+        packages.auto_installed.insert(liblegacy);
+        assert_eq!(plan(&packages, true, true), vec!["liblegacy"]);
+    }
+}
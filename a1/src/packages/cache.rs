@@ -0,0 +1,266 @@
+// A binary cache of the whole index, like apt's pkgcache.bin, so that load-defaults doesn't parse
+// the same Packages and status files at every start.
+//
+// The cache starts with a magic number and a format version, followed by the index encoded with
+// bincode. It records the size and modification time of each file the index was loaded from, and
+// is only used if none of them has changed, its format version is the current one and it was built
+// for the same native architecture; otherwise the files are loaded as usual and the cache is
+// written again. Pins aren't part of the index, so preferences are loaded as usual.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::packages::pinning::Source;
+use crate::packages::{Candidate, PACKAGE_COUNTER};
+use crate::Packages;
+
+use rpkg::debversion::DebianVersionNum;
+
+/// Where load-defaults keeps its cache, next to the history file.
+pub const CACHE_FILE: &str = "pkgcache.bin";
+
+const MAGIC: &[u8] = b"RPKGCACH";
+// bump this whenever the layout of Index, or of anything in it, changes
+const CACHE_VERSION: u32 = 2;
+
+// a file the index was loaded from, as it was before it was loaded
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Input {
+    path : String,
+    size : u64,
+    modified : Duration, // since the epoch
+}
+
+fn stamp(path: &str) -> io::Result<Input> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok(Input { path: String::from(path), size: metadata.len(), modified })
+}
+
+// why input can't be trusted any more, if it can't
+fn staleness(input: &Input) -> Option<String> {
+    match stamp(&input.path) {
+        Err(e) => Some(e.to_string()),
+        Ok(now) if now != *input => Some(String::from("changed since the cache was built")),
+        Ok(_) => None
+    }
+}
+
+// everything in Packages that loading index files fills in; borrowed when saving it, owned when loading it
+#[derive(Serialize, Deserialize)]
+struct Index<'a> {
+    inputs : Cow<'a, [Input]>,
+    native_arch : Cow<'a, str>,
+    package_num_to_name : Cow<'a, HashMap<i32, String>>,
+    candidates : Cow<'a, [Candidate]>,
+    available : Cow<'a, HashMap<i32, Vec<usize>>>,
+    providers : Cow<'a, HashMap<i32, Vec<usize>>>,
+    conflicts_on : Cow<'a, HashMap<i32, Vec<usize>>>,
+    depended_on : Cow<'a, HashMap<i32, Vec<usize>>>,
+    arch_variants : Cow<'a, HashMap<i32, Vec<i32>>>,
+    installed_relations : Cow<'a, HashMap<i32, usize>>,
+    installed_debvers : Cow<'a, HashMap<i32, DebianVersionNum>>,
+    auto_installed : Cow<'a, HashSet<i32>>,
+    installed_sizes : Cow<'a, HashMap<i32, u64>>,
+    essential : Cow<'a, HashSet<i32>>,
+    sources : Cow<'a, [Source]>,
+}
+
+// the index saved in cache_file, whatever it was built from, or why it can't be read
+fn read_index(cache_file: &str) -> Result<Index<'static>, String> {
+    let bytes = match fs::read(cache_file) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(String::from("there is no cache yet")),
+        Err(e) => return Err(e.to_string()),
+        Ok(bytes) => bytes
+    };
+    let header_len = MAGIC.len() + 4;
+    if bytes.len() < header_len || !bytes.starts_with(MAGIC) {
+        return Err(String::from("not an rpkg cache"));
+    }
+    let version = u32::from_le_bytes(bytes[MAGIC.len()..header_len].try_into().unwrap());
+    if version != CACHE_VERSION {
+        return Err(format!("format version {}, not {}", version, CACHE_VERSION));
+    }
+    bincode::deserialize(&bytes[header_len..]).map_err(|e| format!("corrupt cache: {}", e))
+}
+
+// why index, built from its inputs, isn't the index that loading inputs for native_arch would build now, if it isn't
+fn check(index: &Index, inputs: &[&str], native_arch: &str) -> Result<(), String> {
+    if index.inputs.iter().map(|input| input.path.as_str()).ne(inputs.iter().copied()) {
+        return Err(String::from("it was built from other files"));
+    }
+    if let Some((input, why)) = index.inputs.iter().find_map(|input| staleness(input).map(|why| (input, why))) {
+        return Err(format!("{}: {}", input.path, why));
+    }
+    if index.native_arch != native_arch {
+        return Err(format!("it was built for architecture {}", index.native_arch));
+    }
+    Ok(())
+}
+
+impl Packages {
+    /// Loads the index from cache_file if it was built from the files inputs, none of which has changed since, and
+    /// otherwise runs load, which should load those files, and saves the index it builds in cache_file. As the cache
+    /// holds the whole index, it is only used (or written) if nothing has been loaded yet.
+    pub fn load_cached(&mut self, cache_file: &str, inputs: &[&str], load: impl FnOnce(&mut Packages)) {
+        if !self.package_name_to_num.is_empty() || !self.sources.is_empty() {
+            println!("not using {}: packages are already loaded", cache_file);
+            load(self);
+            return;
+        }
+        match self.read_cache(cache_file, inputs) {
+            Ok(()) => {
+                println!("Loaded {}", cache_file);
+                println!("Packages available: {}", self.available.keys().len());
+                println!("Packages installed: {}", self.installed_debvers.keys().len());
+                return;
+            }
+            Err(why) => println!("not using {}: {}", cache_file, why)
+        }
+        // taken before loading, so that a file changed while it is loaded is loaded again next time
+        let stamps: io::Result<Vec<Input>> = inputs.iter().map(|path| stamp(path)).collect();
+        load(self);
+        let written = stamps.and_then(|stamps| self.write_cache(cache_file, &stamps));
+        if let Err(e) = written {
+            println!("can't write {}: {}", cache_file, e);
+        }
+    }
+
+    // replaces the index with the one in cache_file, if it is up to date
+    fn read_cache(&mut self, cache_file: &str, inputs: &[&str]) -> Result<(), String> {
+        let index = read_index(cache_file)?;
+        check(&index, inputs, &self.native_arch)?;
+        let package_num_to_name = index.package_num_to_name.into_owned();
+        self.package_name_to_num = package_num_to_name.iter().map(|(num, name)| (name.clone(), *num)).collect();
+        // numbers handed out from now on mustn't clash with the cached ones
+        let next = package_num_to_name.keys().max().map_or(0, |num| num + 1);
+        PACKAGE_COUNTER.fetch_max(next, Ordering::SeqCst);
+        self.package_num_to_name = package_num_to_name;
+        self.candidates = index.candidates.into_owned();
+        self.available = index.available.into_owned();
+        self.providers = index.providers.into_owned();
+        self.conflicts_on = index.conflicts_on.into_owned();
+        self.depended_on = index.depended_on.into_owned();
+        self.arch_variants = index.arch_variants.into_owned();
+        self.installed_relations = index.installed_relations.into_owned();
+        self.installed_debvers = index.installed_debvers.into_owned();
+        self.auto_installed = index.auto_installed.into_owned();
+        self.installed_sizes = index.installed_sizes.into_owned();
+        self.essential = index.essential.into_owned();
+        self.sources = index.sources.into_owned();
+        Ok(())
+    }
+
+    fn write_cache(&self, cache_file: &str, inputs: &[Input]) -> io::Result<()> {
+        let index = Index {
+            inputs: Cow::Borrowed(inputs),
+            native_arch: Cow::Borrowed(&self.native_arch),
+            package_num_to_name: Cow::Borrowed(&self.package_num_to_name),
+            candidates: Cow::Borrowed(&self.candidates),
+            available: Cow::Borrowed(&self.available),
+            providers: Cow::Borrowed(&self.providers),
+            conflicts_on: Cow::Borrowed(&self.conflicts_on),
+            depended_on: Cow::Borrowed(&self.depended_on),
+            arch_variants: Cow::Borrowed(&self.arch_variants),
+            installed_relations: Cow::Borrowed(&self.installed_relations),
+            installed_debvers: Cow::Borrowed(&self.installed_debvers),
+            auto_installed: Cow::Borrowed(&self.auto_installed),
+            installed_sizes: Cow::Borrowed(&self.installed_sizes),
+            essential: Cow::Borrowed(&self.essential),
+            sources: Cow::Borrowed(&self.sources),
+        };
+        // written next to the cache and renamed over it, so that an interrupted write leaves the old cache intact
+        let partial = format!("{}.partial", cache_file);
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&CACHE_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, &index).map_err(io::Error::other)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&partial, cache_file)
+    }
+
+    /// Describes cache_file: its format version, what it holds, and whether each file it was built from is unchanged,
+    /// so whether load_cached with inputs would use it.
+    pub fn print_cache_status(&self, cache_file: &str, inputs: &[&str]) {
+        let index = match read_index(cache_file) {
+            Err(why) => { println!("{}: unusable: {}", cache_file, why); return; }
+            Ok(index) => index
+        };
+        let size = fs::metadata(cache_file).map_or(0, |m| m.len());
+        println!("{}: format version {}, {} bytes, architecture {}", cache_file, CACHE_VERSION, size, index.native_arch);
+        println!("{} package names, {} candidates, {} installed", index.package_num_to_name.len(), index.candidates.len(), index.installed_debvers.len());
+        for input in index.inputs.iter() {
+            match staleness(input) {
+                None => println!("{}: unchanged ({} bytes)", input.path, input.size),
+                Some(why) => println!("{}: {}", input.path, why)
+            }
+        }
+        match check(&index, inputs, &self.native_arch) {
+            Ok(()) => println!("load-defaults will use it"),
+            Err(why) => println!("load-defaults will rebuild it: {}", why)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::time::{Duration, SystemTime};
+
+    use crate::packages::DEFAULT_PRIORITY;
+    use crate::Packages;
+
+    // an index loaded from inputs (a Packages file and a status file), as load-defaults loads them
+    fn load(packages: &mut Packages, inputs: &[&str]) {
+        let source = packages.add_source(inputs[0], DEFAULT_PRIORITY);
+        packages.parse_packages(inputs[0], source);
+        packages.parse_installed(inputs[1]);
+    }
+
+    #[test]
+    fn is_rebuilt_when_an_input_changes() {
+        let dir = std::env::temp_dir().join(format!("rpkg-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| String::from(dir.join(name).to_str().unwrap());
+        let (packages_file, status_file, cache_file) = (path("Packages"), path("status"), path("pkgcache.bin"));
+        fs::write(&packages_file, "Package: hello\nVersion: 2.10-3\nDepends: libc6\n").unwrap();
+        fs::write(&status_file, "Package: libc6\nStatus: install ok installed\nVersion: 2.36-9\n").unwrap();
+        let inputs = [packages_file.as_str(), status_file.as_str()];
+
+        let mut built = Packages::new();
+        built.load_cached(&cache_file, &inputs, |packages| load(packages, &inputs));
+        let mut cached = Packages::new();
+        assert_eq!(cached.read_cache(&cache_file, &inputs), Ok(()));
+        assert!(cached.package_exists("hello") && cached.installed_debvers.len() == 1);
+        assert_eq!(cached.candidates.len(), built.candidates.len());
+        assert!(Packages::new().read_cache(&cache_file, &inputs[..1]).is_err());
+
+        // the same size, but modified later
+        let later = SystemTime::now() + Duration::from_secs(60);
+        OpenOptions::new().write(true).open(&status_file).unwrap().set_modified(later).unwrap();
+        let why = Packages::new().read_cache(&cache_file, &inputs).unwrap_err();
+        assert_eq!(why, format!("{}: changed since the cache was built", status_file));
+        let mut rebuilt = Packages::new();
+        rebuilt.load_cached(&cache_file, &inputs, |packages| load(packages, &inputs));
+        assert_eq!(Packages::new().read_cache(&cache_file, &inputs), Ok(()));
+
+        // the same modification time, but longer
+        let modified = fs::metadata(&packages_file).unwrap().modified().unwrap();
+        let mut file = OpenOptions::new().append(true).open(&packages_file).unwrap();
+        file.write_all(b"\nPackage: libc6\nVersion: 2.37-1\n").unwrap();
+        file.set_modified(modified).unwrap();
+        let why = Packages::new().read_cache(&cache_file, &inputs).unwrap_err();
+        assert_eq!(why, format!("{}: changed since the cache was built", packages_file));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
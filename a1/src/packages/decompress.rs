@@ -0,0 +1,137 @@
+// Opening index files that may be compressed, as mirrors ship them (Packages.gz, Packages.xz,
+// Packages.zst).
+//
+// The compression is told by the first bytes of the file rather than by its name, so that a file
+// which was renamed, or decompressed in place, is still read correctly. Compressed files are
+// decompressed as they are read, never all at once.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+
+use flate2::bufread::MultiGzDecoder;
+use xz2::bufread::XzDecoder;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// The compression of a file starting with bytes; anything not recognised is taken to be plain text.
+pub fn detect(bytes: &[u8]) -> Compression {
+    if bytes.starts_with(GZIP_MAGIC) {
+        return Compression::Gzip;
+    }
+    if bytes.starts_with(XZ_MAGIC) {
+        return Compression::Xz;
+    }
+    if bytes.starts_with(ZSTD_MAGIC) {
+        return Compression::Zstd;
+    }
+    Compression::None
+}
+
+/// The compression of the file called filename.
+pub fn compression_of(filename: &str) -> io::Result<Compression> {
+    let mut magic = vec![];
+    File::open(filename)?.take(XZ_MAGIC.len() as u64).read_to_end(&mut magic)?;
+    Ok(detect(&magic))
+}
+
+/// Opens filename for reading, decompressing it on the fly if it is compressed with gzip, xz or zstd. Streams of
+/// several concatenated members are read to the end.
+pub fn open(filename: &str) -> io::Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(File::open(filename)?);
+    // the first read of a file fills the buffer, which is enough to hold any magic number
+    let compression = detect(reader.fill_buf()?);
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        Compression::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+
+    use flate2::write::GzEncoder;
+    use xz2::write::XzEncoder;
+
+    use super::{compression_of, detect, open, Compression};
+
+    const TEXT: &str = "Package: hello\nVersion: 2.10-3\n\nPackage: hello-data\nVersion: 2.10-3\n";
+
+    fn gzip(text: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn xz(text: &str) -> Vec<u8> {
+        let mut encoder = XzEncoder::new(vec![], 6);
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(text: &str) -> Vec<u8> {
+        zstd::encode_all(text.as_bytes(), 0).unwrap()
+    }
+
+    // what open reads from a file holding bytes, and the compression it was detected as
+    fn read_back(name: &str, bytes: &[u8]) -> (Compression, Vec<u8>) {
+        let path = std::env::temp_dir().join(format!("rpkg-decompress-{}-{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        let filename = path.to_str().unwrap();
+        let compression = compression_of(filename).unwrap();
+        let mut contents = vec![];
+        open(filename).unwrap().read_to_end(&mut contents).unwrap();
+        fs::remove_file(&path).unwrap();
+        (compression, contents)
+    }
+
+    #[test]
+    fn detects_magic_numbers() {
+        assert_eq!(detect(&gzip(TEXT)), Compression::Gzip);
+        assert_eq!(detect(&xz(TEXT)), Compression::Xz);
+        assert_eq!(detect(&zstd(TEXT)), Compression::Zstd);
+        assert_eq!(detect(TEXT.as_bytes()), Compression::None);
+        assert_eq!(detect(b""), Compression::None);
+        assert_eq!(detect(&[0x1f]), Compression::None);
+    }
+
+    #[test]
+    fn decompresses_gzip_xz_and_zstd() {
+        assert_eq!(read_back("gz", &gzip(TEXT)), (Compression::Gzip, TEXT.as_bytes().to_vec()));
+        assert_eq!(read_back("xz", &xz(TEXT)), (Compression::Xz, TEXT.as_bytes().to_vec()));
+        assert_eq!(read_back("zst", &zstd(TEXT)), (Compression::Zstd, TEXT.as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn reads_concatenated_members_to_the_end() {
+        let (first, second) = TEXT.split_at(20);
+        for (name, compress) in [("gz2", gzip as fn(&str) -> Vec<u8>), ("xz2", xz), ("zst2", zstd)] {
+            let mut bytes = compress(first);
+            bytes.extend(compress(second));
+            assert_eq!(read_back(name, &bytes).1, TEXT.as_bytes(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn passes_plain_text_through_unchanged() {
+        assert_eq!(read_back("plain", TEXT.as_bytes()), (Compression::None, TEXT.as_bytes().to_vec()));
+        // even if it isn't UTF-8, or is too short to hold a magic number
+        let binary = [0xff, 0xfe, b'\n', 0x00, 0x1f];
+        assert_eq!(read_back("binary", &binary), (Compression::None, binary.to_vec()));
+        assert_eq!(read_back("short", &[0x1f]), (Compression::None, vec![0x1f]));
+        assert_eq!(read_back("empty", b""), (Compression::None, vec![]));
+    }
+}
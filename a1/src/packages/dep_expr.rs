@@ -0,0 +1,320 @@
+// A parser for relation fields (Depends, Build-Depends, Provides, ...), following Debian Policy 7.1:
+//
+//   field      := dependency ("," dependency)*
+//   dependency := relation ("|" relation)*
+//   relation   := name [":" archqual] ["(" op version ")"] ["[" arch+ "]"] ("<" profile+ ">")*
+//
+// where each arch or profile may be prefixed by "!". The result is a typed tree of names;
+// Packages::intern_relations turns the names into package numbers. Formatting a parsed relation
+// (see Restrictions's Display and Packages::dep2str) gives back the canonical text, with single
+// spaces between the parts.
+
+use std::fmt;
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use rpkg::debversion::{DebianVersionNum, VersionRelation};
+
+/// An architecture or build profile in a restriction list, possibly negated (!i386, !nocheck).
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Term {
+    pub negated : bool,
+    pub name : String,
+}
+
+/// The architecture restriction list and build-profile formula of a relation.
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Restrictions {
+    pub arches : Vec<Term>, // [amd64 arm64]; empty if there is no list
+    pub profiles : Vec<Vec<Term>>, // <!nocheck cross> <stage1>: one of the lists must hold in full
+}
+
+/// One alternative of a dependency, with names still unresolved.
+pub struct RelationExpr {
+    pub name : String,
+    pub arch_qualifier : Option<String>,
+    pub rel_version : Option<(VersionRelation, String)>,
+    pub restrictions : Restrictions,
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", if self.negated { "!" } else { "" }, self.name)
+    }
+}
+
+// formats as the text following the version relation, including its leading space
+impl fmt::Display for Restrictions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.arches.is_empty() {
+            write!(f, " [{}]", self.arches.iter().format(" "))?;
+        }
+        for terms in &self.profiles {
+            write!(f, " <{}>", terms.iter().format(" "))?;
+        }
+        Ok(())
+    }
+}
+
+// splits an architecture name into (os, cpu), as in kfreebsd-amd64; plain names are linux
+fn arch_tuple(arch: &str) -> (&str, &str) {
+    arch.split_once('-').unwrap_or(("linux", arch))
+}
+
+// whether arch matches a name from a restriction list, which may be a wildcard: any, linux-any, any-amd64
+fn arch_is(arch: &str, pattern: &str) -> bool {
+    if pattern == "any" || pattern == arch {
+        return true;
+    }
+    let (os, cpu) = arch_tuple(arch);
+    match pattern.split_once('-') {
+        Some(("any", p_cpu)) => p_cpu == cpu,
+        Some((p_os, "any")) => p_os == os,
+        _ => false
+    }
+}
+
+impl Restrictions {
+    /// Returns true if a relation with these restrictions applies on arch with the given build profiles active.
+    /// A list of negated architectures applies unless arch is one of them, any other list only if it is.
+    pub fn applies(&self, arch: &str, active_profiles: &[&str]) -> bool {
+        if !self.arches.is_empty() {
+            let listed = self.arches.iter().any(|t| arch_is(arch, &t.name));
+            let negated = self.arches.iter().all(|t| t.negated);
+            if listed == negated {
+                return false;
+            }
+        }
+        if !self.profiles.is_empty() {
+            return self.profiles.iter()
+                .any(|terms| terms.iter().all(|t| active_profiles.contains(&t.name.as_str()) != t.negated));
+        }
+        true
+    }
+}
+
+struct Parser<'a> {
+    text : &'a str,
+    pos : usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, expected: &str) -> String {
+        match self.peek() {
+            None => format!("expected {} at end of {:?}", expected, self.text),
+            Some(c) => format!("expected {} but found {:?} at column {} of {:?}", expected, c, self.pos + 1, self.text)
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    // skips whitespace, then consumes c if it comes next
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            return Ok(());
+        }
+        Err(self.error(&format!("{:?}", c)))
+    }
+
+    // consumes a run of characters satisfying pred, after skipping whitespace
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        self.skip_whitespace();
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if !pred(c) {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+        &self.text[start..self.pos]
+    }
+
+    fn word(&mut self, what: &str) -> Result<&'a str, String> {
+        let word = self.take_while(|c| c.is_alphanumeric() || "+-._".contains(c));
+        if word.is_empty() {
+            return Err(self.error(what));
+        }
+        Ok(word)
+    }
+
+    fn term(&mut self, what: &str) -> Result<Term, String> {
+        let negated = self.eat('!');
+        Ok(Term { negated, name: String::from(self.word(what)?) })
+    }
+
+    fn rel_version(&mut self) -> Result<(VersionRelation, String), String> {
+        let op = self.take_while(|c| "<=>".contains(c));
+        let op = op.parse::<VersionRelation>().map_err(|_| self.error("one of <<, <=, =, >=, >>"))?;
+        let version = self.take_while(|c| c != ')' && !c.is_whitespace());
+        if version.is_empty() {
+            return Err(self.error("a version"));
+        }
+        // checked here so that comparisons against it later can't fail
+        if let Err(e) = version.parse::<DebianVersionNum>() {
+            return Err(format!("{} in {:?}", e, self.text));
+        }
+        self.expect(')')?;
+        Ok((op, String::from(version)))
+    }
+
+    fn relation(&mut self) -> Result<RelationExpr, String> {
+        let name = String::from(self.word("a package name")?);
+        let arch_qualifier = if self.peek() == Some(':') {
+            self.pos += 1;
+            Some(String::from(self.word("an architecture")?))
+        } else {
+            None
+        };
+        let rel_version = if self.eat('(') { Some(self.rel_version()?) } else { None };
+        let mut restrictions = Restrictions::default();
+        if self.eat('[') {
+            while !self.eat(']') {
+                restrictions.arches.push(self.term("an architecture")?);
+            }
+            if restrictions.arches.is_empty() {
+                return Err(self.error("an architecture"));
+            }
+            let negated = restrictions.arches.iter().filter(|t| t.negated).count();
+            if negated != 0 && negated != restrictions.arches.len() {
+                return Err(format!("architecture list mixes negated and plain entries in {:?}", self.text));
+            }
+        }
+        while self.eat('<') {
+            let mut terms = vec![];
+            while !self.eat('>') {
+                terms.push(self.term("a build profile")?);
+            }
+            if terms.is_empty() {
+                return Err(self.error("a build profile"));
+            }
+            restrictions.profiles.push(terms);
+        }
+        Ok(RelationExpr { name, arch_qualifier, rel_version, restrictions })
+    }
+}
+
+/// Parses a relation field into its dependencies, each a list of alternatives. Empty entries (as left by a
+/// trailing comma) are skipped.
+pub fn parse_field(text: &str) -> Result<Vec<Vec<RelationExpr>>, String> {
+    let mut parser = Parser { text, pos: 0 };
+    let mut dependencies = vec![];
+    loop {
+        if parser.eat(',') {
+            continue;
+        }
+        parser.skip_whitespace();
+        if parser.peek().is_none() {
+            break;
+        }
+        let mut alternatives = vec![parser.relation()?];
+        while parser.eat('|') {
+            alternatives.push(parser.relation()?);
+        }
+        dependencies.push(alternatives);
+        parser.skip_whitespace();
+        if parser.peek().is_some() && !parser.eat(',') {
+            return Err(parser.error("',' or '|'"));
+        }
+    }
+    Ok(dependencies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_field, RelationExpr, Restrictions, Term};
+    use crate::Packages;
+
+    use rpkg::debversion::VersionRelation;
+
+    fn names(field: &[Vec<RelationExpr>]) -> Vec<Vec<&str>> {
+        field.iter().map(|dep| dep.iter().map(|rel| rel.name.as_str()).collect()).collect()
+    }
+
+    #[test]
+    fn parses_dependencies_and_alternatives() {
+        let field = parse_field("libc6 (>= 2.34), default-mta | mail-transport-agent,python3:any").unwrap();
+        assert_eq!(names(&field), vec![vec!["libc6"], vec!["default-mta", "mail-transport-agent"], vec!["python3"]]);
+        let (op, version) = field[0][0].rel_version.as_ref().unwrap();
+        assert!(matches!(op, VersionRelation::GreaterOrEqual));
+        assert_eq!(version, "2.34");
+        assert_eq!(field[2][0].arch_qualifier.as_deref(), Some("any"));
+        assert!(field[1][1].rel_version.is_none());
+    }
+
+    #[test]
+    fn parses_restrictions() {
+        let field = parse_field("gcc [!armel !armhf] <!nocheck cross> <stage1>").unwrap();
+        let restrictions = &field[0][0].restrictions;
+        let term = |negated, name: &str| Term { negated, name: String::from(name) };
+        assert_eq!(*restrictions, Restrictions {
+            arches: vec![term(true, "armel"), term(true, "armhf")],
+            profiles: vec![vec![term(true, "nocheck"), term(false, "cross")], vec![term(false, "stage1")]],
+        });
+        assert!(restrictions.applies("amd64", &["cross"]));
+        assert!(!restrictions.applies("armel", &["cross"]));
+        assert!(!restrictions.applies("amd64", &[]));
+        assert!(!restrictions.applies("amd64", &["nocheck", "cross"]));
+        assert!(restrictions.applies("amd64", &["nocheck", "stage1"]));
+    }
+
+    #[test]
+    fn skips_whitespace_and_empty_entries() {
+        let field = parse_field(" foo ,\n  bar\t(<< 1.0) ,, baz ,").unwrap();
+        assert_eq!(names(&field), vec![vec!["foo"], vec!["bar"], vec!["baz"]]);
+        assert!(parse_field("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn skips_non_ascii_whitespace() {
+        let field = parse_field("bar\u{a0}(>= 1.0),\u{3000}baz\u{2003}|\u{2003}qux").unwrap();
+        assert_eq!(names(&field), vec![vec!["bar"], vec!["baz", "qux"]]);
+        assert_eq!(field[0][0].rel_version.as_ref().unwrap().1, "1.0");
+        assert!(parse_field("bar\u{a0}\u{a0}").is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_fields() {
+        for text in ["foo (>= 1.0", "foo (~ 1.0)", "foo (>= )", "foo (>= 1.0~~a b)", "foo bar", "foo |", "foo [amd64 !i386]",
+                     "foo []", "foo <>", "foo:", "(>= 1.0)", "foo\u{a0}é"] {
+            assert!(parse_field(text).is_err(), "{:?} should be rejected", text);
+        }
+    }
+
+    #[test]
+    fn dep2str_round_trips_exactly() {
+        let fields = [
+            "libc6 (>= 2.34)",
+            "default-mta | mail-transport-agent",
+            "python3:any (>= 3.9~), perl:native",
+            "gcc [!armel !armhf] <!nocheck cross> <stage1>",
+            "libfoo1 (= 1:2.0-1+b1) [linux-any] | libfoo-dev (<< 3) <!nocheck>",
+            "a (<< 1), b (<= 1), c (= 1), d (>= 1), e (>> 1)",
+        ];
+        let stanza = fields.iter().enumerate()
+            .map(|(i, field)| format!("Package: p{}\nVersion: 1\nDepends: {}\n", i, field))
+            .collect::<Vec<_>>().join("\n");
+        let packages = Packages::from_text(&stanza, "");
+        for (i, field) in fields.iter().enumerate() {
+            let c = packages.best_candidate(*packages.get_package_num(&format!("p{}", i))).unwrap();
+            assert_eq!(packages.deps2str(&packages.get_candidate(c).dependencies), *field);
+        }
+    }
+}
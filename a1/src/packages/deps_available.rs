@@ -1,6 +1,6 @@
-use crate::packages::Dependency;
+use crate::packages::{Dependency, RelationKind};
 use crate::Packages;
-use rpkg::debversion;
+use std::collections::HashSet;
 
 impl Packages {
     /// Gets the dependencies of package_name, and prints out whether they are satisfied (and by which library/version) or not.
@@ -11,13 +11,15 @@ impl Packages {
         }
         println!("Package {}:", package_name);
 
-        let dependencies: &Vec<Dependency> = self
-            .dependencies
-            .get(self.get_package_num(package_name))
-            .unwrap();
+        let package_num = *self.get_package_num(package_name);
+        let dependencies: &[Dependency] = self.get_relations(package_num, RelationKind::Depends);
         for dependency in dependencies {
             println!("- dependency {:?}", self.dep2str(dependency));
-            match self.dep_is_satisfied(dependency) {
+            if !self.dep_applies(dependency, self.package_arch(package_num)) {
+                println!("-> does not apply to {}", self.package_arch(package_num));
+                continue;
+            }
+            match self.dep_is_satisfied(dependency, self.package_arch(package_num)) {
                 None => {
                     println!("-> not satisfied");
                 }
@@ -33,52 +35,44 @@ impl Packages {
     }
 
     /// Returns Some(package) which satisfies dependency dd, or None if not satisfied.
-    pub fn dep_is_satisfied(&self, dd: &Dependency) -> Option<&str> {
+    /// The package may be the named alternative itself or an installed package that Provides it; from_arch is the
+    /// architecture of the package declaring the dependency.
+    pub fn dep_is_satisfied(&self, dd: &Dependency, from_arch: &str) -> Option<&str> {
+        self.installed_satisfier(dd, from_arch, &HashSet::new()).map(|p| self.get_package_name(p))
+    }
+
+    /// Like dep_is_satisfied, but pretends that the packages in removed are not installed.
+    pub fn installed_satisfier(&self, dd: &Dependency, from_arch: &str, removed: &HashSet<i32>) -> Option<i32> {
         for alternative in dd {
-            if self
-                .installed_debvers
-                .contains_key(&alternative.package_num)
-            {
-                match &alternative.rel_version {
-                    None => {
-                        continue;
-                    }
-                    Some((op, required_version)) => {
-                        let iv = self
-                            .installed_debvers
-                            .get(&alternative.package_num)
-                            .unwrap();
-                        let v = required_version
-                            .parse::<debversion::DebianVersionNum>()
-                            .unwrap();
-                        if debversion::cmp_debversion_with_op(op, iv, &v) {
-                            return Some(self.get_package_name(alternative.package_num));
-                        }
+            for package_num in self.alternative_packages(alternative) {
+                if removed.contains(&package_num) {
+                    continue;
+                }
+                if let Some(iv) = self.installed_version(package_num) {
+                    if self.satisfies(alternative, Some(from_arch), &iv) {
+                        return Some(package_num);
                     }
                 }
             }
         }
-        return None;
+        None
     }
 
     /// Returns a Vec of packages which would satisfy dependency dd but for the version.
     /// Used by the how-to-install command, which calls compute_how_to_install().
-    pub fn dep_satisfied_by_wrong_version(&self, dd: &Dependency) -> Vec<&str> {
-        assert!(self.dep_is_satisfied(dd).is_none());
+    #[allow(clippy::needless_return)]
+    pub fn dep_satisfied_by_wrong_version(&self, dd: &Dependency, from_arch: &str) -> Vec<&str> {
+        assert!(self.dep_is_satisfied(dd, from_arch).is_none());
         let mut result = vec![];
         for alternative in dd {
-            match &alternative.rel_version {
-                None => {
-                    continue;
-                }
-                Some((op, required_version)) => {
-                    let v = required_version
-                        .parse::<debversion::DebianVersionNum>()
-                        .unwrap();
-                    if let Some(iv) = self.installed_debvers.get(&alternative.package_num) {
-                        if !debversion::cmp_debversion_with_op(op, iv, &v) {
-                            result.push(self.get_package_name(alternative.package_num));
-                        }
+            if alternative.rel_version.is_none() {
+                continue;
+            }
+            for package_num in self.all_arch_variants(alternative.package_num) {
+                if let Some(iv) = self.installed_version(package_num) {
+                    // the right package for this architecture, so it must be the version that's wrong
+                    if self.arch_satisfies(alternative, Some(from_arch), &iv) {
+                        result.push(self.get_package_name(package_num));
                     }
                 }
             }
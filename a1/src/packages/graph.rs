@@ -0,0 +1,340 @@
+// The dependency relation as a petgraph Graph, and exporting the part of it a package pulls in.
+//
+// Each known package is a node, and each alternative of a dependency of its candidate (the one apt
+// would pick) is an edge to every package with a version satisfying that alternative: the named
+// package, its other architectures, or a provider. Edges keep the dependency they come from, so the
+// alternatives of one dependency and their version relations can be told apart when exporting.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use itertools::Itertools;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::Dfs;
+use petgraph::Graph;
+use serde::Serialize;
+
+use crate::packages::{Dependency, RelationKind, RelationPolicy};
+use crate::Packages;
+
+/// An edge of the dependency graph: alternative number alternative of dependency dep, in a field of kind kind.
+#[derive(Clone, Copy)]
+pub struct DependencyEdge<'a> {
+    pub kind : RelationKind,
+    pub dep : &'a Dependency,
+    pub alternative : usize,
+}
+
+/// The dependency graph, with nodes weighted by package number.
+pub struct DependencyGraph<'a> {
+    pub graph : Graph<i32, DependencyEdge<'a>>,
+    pub node_of : HashMap<i32, NodeIndex>,
+}
+
+/// The file formats the graph command writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot, // Graphviz DOT
+    Json,
+}
+
+impl GraphFormat {
+    /// The format a file name calls for: JSON if it ends in .json, and otherwise DOT.
+    pub fn for_file(filename: &str) -> GraphFormat {
+        if filename.ends_with(".json") { GraphFormat::Json } else { GraphFormat::Dot }
+    }
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(format!("unknown format {} (dot or json)", s))
+        }
+    }
+}
+
+// the JSON graph2json writes
+#[derive(Serialize)]
+struct JsonGraph<'a> {
+    root : &'a str,
+    nodes : Vec<JsonNode<'a>>,
+    edges : Vec<JsonEdge<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonNode<'a> {
+    name : &'a str,
+    version : String,
+}
+
+#[derive(Serialize)]
+struct JsonEdge<'a> {
+    from : &'a str,
+    to : &'a str,
+    kind : &'static str,
+    dependency : String,
+    alternative : usize,
+    alternatives : usize,
+    version : Option<String>,
+}
+
+// quotes s as a Graphviz ID, where \n in a label is a line break
+fn quote(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c)
+        }
+    }
+    result.push('"');
+    result
+}
+
+impl Packages {
+    // the candidate whose relations the graph follows: the best one, or else the installed version's
+    fn graph_candidate(&self, package_num: i32) -> Option<usize> {
+        self.best_candidate(package_num).or_else(|| self.installed_version(package_num).and_then(|iv| iv.candidate))
+    }
+
+    // the version a node of the graph stands for
+    fn graph_version(&self, package_num: i32) -> String {
+        match self.graph_candidate(package_num) {
+            Some(c) => self.candidates[c].version.to_string(),
+            None => self.installed_debvers.get(&package_num).map_or(String::new(), |v| v.to_string())
+        }
+    }
+
+    /// Builds the graph of the dependencies policy says to follow, over every package that has a candidate or an
+    /// installed version. Nodes are added in order of package name.
+    pub fn dependencies(&self, policy: &RelationPolicy) -> DependencyGraph<'_> {
+        let mut graph = Graph::new();
+        let node_of: HashMap<i32, NodeIndex> = self.available.keys().chain(self.installed_debvers.keys()).copied().unique()
+            .sorted_by(|a, b| self.get_package_name(*a).cmp(self.get_package_name(*b)))
+            .map(|p| (p, graph.add_node(p)))
+            .collect();
+        for (&p, &node) in node_of.iter().sorted_by_key(|(_, node)| **node) {
+            let arch = self.package_arch(p);
+            for (kind, dep) in self.followed_relations(self.graph_candidate(p), policy) {
+                for (alternative, rel) in dep.iter().enumerate() {
+                    for q in self.alternative_packages(rel) {
+                        let satisfied = self.get_candidates(q).iter().any(|c| self.satisfies(rel, Some(arch), &self.available_version(*c)))
+                            || self.installed_version(q).is_some_and(|qv| self.satisfies(rel, Some(arch), &qv));
+                        if satisfied && q != p {
+                            graph.add_edge(node, node_of[&q], DependencyEdge { kind, dep, alternative });
+                        }
+                    }
+                }
+            }
+        }
+        DependencyGraph { graph, node_of }
+    }
+
+    /// Returns the subgraph of graph reachable from package_num, package_num included.
+    pub fn dependency_closure<'a>(&self, graph: &DependencyGraph<'a>, package_num: i32) -> Graph<i32, DependencyEdge<'a>> {
+        let mut reached = vec![false; graph.graph.node_count()];
+        let mut dfs = Dfs::new(&graph.graph, graph.node_of[&package_num]);
+        while let Some(n) = dfs.next(&graph.graph) {
+            reached[n.index()] = true;
+        }
+        graph.graph.filter_map(|n, p| if reached[n.index()] { Some(*p) } else { None }, |_, e| Some(*e))
+    }
+
+    // the version relation of an edge's alternative, as in ">= 1.0", if it has one
+    fn edge_version(edge: &DependencyEdge) -> Option<String> {
+        edge.dep[edge.alternative].rel_version.as_ref().map(|(rel, version)| format!("{} {}", rel, version))
+    }
+
+    /// Writes graph in Graphviz DOT. Nodes are labelled with their versions and edges with their version relations;
+    /// edges from a dependency with alternatives are dashed, Pre-Depends are bold, and Recommends and Suggests dotted.
+    /// The whole dependency is the edge's tooltip.
+    pub fn graph2dot(&self, name: &str, graph: &Graph<i32, DependencyEdge>) -> String {
+        let mut result = format!("digraph {} {{\n", quote(name));
+        for n in graph.node_indices() {
+            let p = graph[n];
+            result.push_str(&format!("  {} [label={}];\n", quote(self.get_package_name(p)),
+                quote(&format!("{}\n{}", self.get_package_name(p), self.graph_version(p)))));
+        }
+        for edge in graph.raw_edges() {
+            let weight = &edge.weight;
+            let mut styles = vec![];
+            match weight.kind {
+                RelationKind::PreDepends => styles.push("bold"),
+                RelationKind::Depends => (),
+                RelationKind::Recommends | RelationKind::Suggests => styles.push("dotted")
+            }
+            if weight.dep.len() > 1 {
+                styles.push("dashed");
+            }
+            let mut attributes = vec![format!("tooltip={}", quote(&format!("{} {}", weight.kind.verb(), self.dep2str(weight.dep))))];
+            if let Some(version) = Packages::edge_version(weight) {
+                attributes.push(format!("label={}", quote(&version)));
+            }
+            if !styles.is_empty() {
+                attributes.push(format!("style={}", quote(&styles.join(","))));
+            }
+            result.push_str(&format!("  {} -> {} [{}];\n", quote(self.get_package_name(graph[edge.source()])),
+                quote(self.get_package_name(graph[edge.target()])), attributes.join(", ")));
+        }
+        result.push_str("}\n");
+        result
+    }
+
+    /// Writes graph as a JSON object with "root", "nodes" (name and version) and "edges": from, to, the kind of field,
+    /// the whole dependency, which of its alternatives this is and how many it has, and the version relation or null.
+    pub fn graph2json(&self, name: &str, graph: &Graph<i32, DependencyEdge>) -> String {
+        let nodes = graph.node_indices().map(|n| {
+            let p = graph[n];
+            JsonNode { name: self.get_package_name(p), version: self.graph_version(p) }
+        }).collect();
+        let edges = graph.raw_edges().iter().map(|edge| {
+            let weight = &edge.weight;
+            JsonEdge {
+                from: self.get_package_name(graph[edge.source()]),
+                to: self.get_package_name(graph[edge.target()]),
+                kind: weight.kind.field_name(),
+                dependency: self.dep2str(weight.dep),
+                alternative: weight.alternative,
+                alternatives: weight.dep.len(),
+                version: Packages::edge_version(weight),
+            }
+        }).collect();
+        let json = JsonGraph { root: name, nodes, edges };
+        // the graph holds nothing but strings and numbers
+        serde_json::to_string_pretty(&json).unwrap() + "\n"
+    }
+
+    /// Writes the dependencies package_name pulls in, transitively, to filename in the given format.
+    pub fn export_graph(&self, package_name: &str, filename: &str, format: GraphFormat, policy: &RelationPolicy) {
+        if !self.package_exists(package_name) {
+            println!("no such package {}", package_name);
+            return;
+        }
+        let graph = self.dependencies(policy);
+        let package_num = *self.get_package_num(package_name);
+        if !graph.node_of.contains_key(&package_num) {
+            println!("no installable version of {} is known", package_name);
+            return;
+        }
+        let closure = self.dependency_closure(&graph, package_num);
+        let contents = match format {
+            GraphFormat::Dot => self.graph2dot(package_name, &closure),
+            GraphFormat::Json => self.graph2json(package_name, &closure)
+        };
+        match std::fs::write(filename, contents) {
+            Ok(()) => println!("{:?} dependency graph: {} packages, {} edges written to {}", package_name, closure.node_count(), closure.edge_count(), filename),
+            Err(e) => println!("can't write {}: {}", filename, e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use petgraph::Graph;
+
+    use super::{DependencyEdge, GraphFormat};
+    use crate::packages::RelationPolicy;
+    use crate::Packages;
+
+    const PACKAGES: &str = "\
+Package: app
+Version: 1
+Depends: libz (>= 1.2) | liby, mta
+Recommends: extra
+
+Package: libz
+Version: 1.3
+Pre-Depends: libc
+
+Package: liby
+Version: 1
+Depends: libc
+
+Package: libc
+Version: 2
+
+Package: postfix
+Version: 3
+Provides: mta
+
+Package: extra
+Version: 1
+
+Package: user
+Version: 1
+Depends: app
+";
+
+    fn node_names(packages: &Packages, graph: &Graph<i32, DependencyEdge>) -> Vec<String> {
+        graph.node_indices().map(|n| String::from(packages.get_package_name(graph[n]))).collect()
+    }
+
+    #[test]
+    fn closures_hold_what_a_package_pulls_in() {
+        let packages = Packages::from_text(PACKAGES, "");
+        let graph = packages.dependencies(&RelationPolicy::default());
+        let closure = |name: &str| packages.dependency_closure(&graph, *packages.get_package_num(name));
+        assert_eq!(node_names(&packages, &closure("app")), vec!["app", "libc", "liby", "libz", "postfix"]);
+        assert_eq!(closure("app").edge_count(), 5);
+        assert_eq!(node_names(&packages, &closure("libz")), vec!["libc", "libz"]);
+        assert_eq!(node_names(&packages, &closure("libc")), vec!["libc"]);
+        assert_eq!(closure("libc").edge_count(), 0);
+        let graph = packages.dependencies(&RelationPolicy { recommends: true, suggests: false });
+        let closure = packages.dependency_closure(&graph, *packages.get_package_num("app"));
+        assert_eq!(node_names(&packages, &closure), vec!["app", "extra", "libc", "liby", "libz", "postfix"]);
+        assert_eq!(closure.edge_count(), 6);
+    }
+
+    #[test]
+    fn writes_json() {
+        let packages = Packages::from_text(PACKAGES, "");
+        let graph = packages.dependencies(&RelationPolicy::default());
+        let closure = packages.dependency_closure(&graph, *packages.get_package_num("libz"));
+        let json: serde_json::Value = serde_json::from_str(&packages.graph2json("libz", &closure)).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "root": "libz",
+            "nodes": [{"name": "libc", "version": "2"}, {"name": "libz", "version": "1.3"}],
+            "edges": [{"from": "libz", "to": "libc", "kind": "Pre-Depends", "dependency": "libc", "alternative": 0,
+                       "alternatives": 1, "version": null}]
+        }));
+        let closure = packages.dependency_closure(&graph, *packages.get_package_num("app"));
+        let json: serde_json::Value = serde_json::from_str(&packages.graph2json("app", &closure)).unwrap();
+        let edges = json["edges"].as_array().unwrap();
+        assert_eq!(edges[0], serde_json::json!({"from": "app", "to": "libz", "kind": "Depends", "dependency": "libz (>= 1.2) | liby",
+                                                "alternative": 0, "alternatives": 2, "version": ">= 1.2"}));
+        assert_eq!(edges[1]["to"], "liby");
+        assert_eq!(edges[1]["alternative"], 1);
+        assert_eq!(edges[2]["to"], "postfix");
+    }
+
+    #[test]
+    fn writes_dot() {
+        let packages = Packages::from_text(PACKAGES, "");
+        let graph = packages.dependencies(&RelationPolicy::default());
+        let closure = packages.dependency_closure(&graph, *packages.get_package_num("libz"));
+        assert_eq!(packages.graph2dot("libz", &closure), "\
+digraph \"libz\" {
+  \"libc\" [label=\"libc\\n2\"];
+  \"libz\" [label=\"libz\\n1.3\"];
+  \"libz\" -> \"libc\" [tooltip=\"pre-depends on libc\", style=\"bold\"];
+}
+");
+    }
+
+    #[test]
+    fn formats_are_chosen_explicitly_or_by_file_name() {
+        assert_eq!("dot".parse::<GraphFormat>(), Ok(GraphFormat::Dot));
+        assert_eq!("json".parse::<GraphFormat>(), Ok(GraphFormat::Json));
+        assert!("svg".parse::<GraphFormat>().is_err());
+        assert_eq!(GraphFormat::for_file("app.json"), GraphFormat::Json);
+        assert_eq!(GraphFormat::for_file("app.dot"), GraphFormat::Dot);
+        assert_eq!(GraphFormat::for_file("app"), GraphFormat::Dot);
+    }
+}
@@ -0,0 +1,318 @@
+// Loading a suite of a local Debian mirror, laid out as apt expects it:
+//
+//   <dir>/dists/<suite>/Release
+//   <dir>/dists/<suite>/<component>/binary-<arch>/Packages (or Packages.xz, .gz, .zst)
+//
+// The Release file (or InRelease, whose signature isn't checked) names the suite's components and
+// architectures, and lists the SHA256 and size of every index file. Each index is checked against
+// it before it is loaded, and one that doesn't match (or isn't listed) is never loaded. Every
+// candidate is recorded as coming from a source for the suite, with the Origin of the Release file,
+// so that pins by origin or release can select it.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+
+use crate::packages::DEFAULT_PRIORITY;
+use crate::Packages;
+
+use rpkg::deb822::{self, Paragraph};
+
+// the index files that may hold a component's packages for an architecture, in order of preference: a plain one
+// can be loaded in parallel
+const INDEX_NAMES: [&str; 4] = ["Packages", "Packages.xz", "Packages.gz", "Packages.zst"];
+
+// an index file listed in the SHA256 field of a Release file
+struct IndexEntry {
+    sha256 : String,
+    size : u64,
+    path : String, // relative to the directory of the Release file
+}
+
+// the fields of a Release file that load-mirror uses
+struct Release {
+    origin : String,
+    suite : String,
+    components : Vec<String>,
+    architectures : Vec<String>,
+    indexes : Vec<IndexEntry>,
+}
+
+// the text of a clearsigned (InRelease) file, without its armour and signature
+fn clearsigned_text(text: &str) -> Option<String> {
+    let mut lines = text.lines();
+    lines.find(|l| l.trim_end() == "-----BEGIN PGP SIGNED MESSAGE-----")?;
+    // armour headers (Hash: SHA512) end at the first blank line
+    lines.find(|l| l.trim_end().is_empty())?;
+    let body = lines.take_while(|l| l.trim_end() != "-----BEGIN PGP SIGNATURE-----")
+        .map(|l| l.strip_prefix("- ").unwrap_or(l))
+        .join("\n");
+    Some(body)
+}
+
+// the first paragraph of the Release (or else InRelease) file in dir
+fn read_release(dir: &Path) -> Result<(PathBuf, Paragraph), String> {
+    let release = dir.join("Release");
+    let (path, text) = match std::fs::read_to_string(&release) {
+        Ok(text) => (release, text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let inrelease = dir.join("InRelease");
+            match std::fs::read_to_string(&inrelease) {
+                Err(_) => return Err(format!("can't read {}: {}", release.display(), e)),
+                Ok(text) => match clearsigned_text(&text) {
+                    None => return Err(format!("{}: not a clearsigned file", inrelease.display())),
+                    Some(text) => (inrelease, text)
+                }
+            }
+        }
+        Err(e) => return Err(format!("can't read {}: {}", release.display(), e))
+    };
+    match deb822::paragraphs(text.as_bytes()).next() {
+        None => Err(format!("{}: empty file", path.display())),
+        Some(Err(e)) => Err(format!("{}:{}: {}", path.display(), e.line, e.error)),
+        Some(Ok(paragraph)) => Ok((path, paragraph))
+    }
+}
+
+// the Release file as a whole, or what is wrong with it
+fn parse_release(paragraph: &Paragraph, suite: &str) -> Result<Release, String> {
+    let words = |name: &str| paragraph.get(name).map_or(vec![], |v| v.split_whitespace().map(String::from).collect_vec());
+    let sha256 = paragraph.field("SHA256").ok_or("no SHA256 field")?;
+    let mut indexes = vec![];
+    for (i, line) in sha256.value.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let entry = match line.split_whitespace().collect_vec()[..] {
+            [sha256, size, path] => size.parse::<u64>().ok().map(|size| IndexEntry { sha256: sha256.to_ascii_lowercase(), size, path: String::from(path) }),
+            _ => None
+        };
+        match entry {
+            None => return Err(format!("line {}: expected <sha256> <size> <path>, found {:?}", sha256.line + i, line.trim())),
+            Some(entry) => indexes.push(entry)
+        }
+    }
+    let release = Release {
+        origin: String::from(paragraph.get("Origin").unwrap_or("")),
+        suite: String::from(paragraph.get("Suite").unwrap_or(suite)),
+        components: words("Components"),
+        architectures: words("Architectures"),
+        indexes,
+    };
+    if release.components.is_empty() || release.architectures.is_empty() {
+        return Err(String::from("no Components or Architectures"));
+    }
+    Ok(release)
+}
+
+// the SHA256 of the file at path, in hex
+fn sha256_of(path: &Path) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// whether the file at path is the one entry describes, or why not
+fn verify(path: &Path, entry: &IndexEntry) -> Result<(), String> {
+    let size = std::fs::metadata(path).map_err(|e| e.to_string())?.len();
+    if size != entry.size {
+        return Err(format!("size {}, but Release says {}", size, entry.size));
+    }
+    let sha256 = sha256_of(path).map_err(|e| e.to_string())?;
+    if sha256 != entry.sha256 {
+        return Err(format!("SHA256 {}, but Release says {}", sha256, entry.sha256));
+    }
+    Ok(())
+}
+
+impl Packages {
+    /// Loads suite from the local mirror in mirror_dir: for each component listed in its Release file, the Packages
+    /// index of each architecture in arches (by default the native one) and of all, if the Release file lists them.
+    /// Each index is loaded only if its size and SHA256 are those the Release file gives; if the first one present
+    /// of Packages, Packages.xz, Packages.gz and Packages.zst isn't, the next is tried. The candidates are recorded as
+    /// coming from a source named after the Suite field of the Release file, with its Origin.
+    pub fn load_mirror(&mut self, mirror_dir: &str, suite: &str, arches: &[&str]) {
+        let dir = Path::new(mirror_dir).join("dists").join(suite);
+        let (release_path, paragraph) = match read_release(&dir) {
+            Err(e) => { println!("{}", e); return; }
+            Ok(release) => release
+        };
+        let release = match parse_release(&paragraph, suite) {
+            Err(e) => { println!("{}: {}", release_path.display(), e); return; }
+            Ok(release) => release
+        };
+        println!("{}: origin {}, suite {}, components {}, architectures {}", release_path.display(),
+                 if release.origin.is_empty() { "unknown" } else { &release.origin }, release.suite,
+                 release.components.iter().format(" "), release.architectures.iter().format(" "));
+        let native = [self.native_arch.as_str()];
+        let wanted = if arches.is_empty() { &native[..] } else { arches };
+        let mut load_arches = vec![];
+        for arch in wanted.iter().copied().chain(std::iter::once("all")) {
+            if release.architectures.iter().any(|a| a == arch) {
+                load_arches.push(String::from(arch));
+            } else if arch != "all" {
+                println!("{}: architecture {} isn't in the Release file", release_path.display(), arch);
+            }
+        }
+        let source = self.add_source(&release.suite, DEFAULT_PRIORITY);
+        self.set_source_origin(source, &release.origin);
+        let mut loaded = 0;
+        for component in &release.components {
+            for arch in &load_arches {
+                let index_dir = format!("{}/binary-{}", component, arch);
+                match self.load_verified_index(&dir, &index_dir, &release.indexes, source) {
+                    Err(e) => println!("{}/{}: {}", dir.display(), index_dir, e),
+                    Ok(()) => loaded += 1
+                }
+            }
+        }
+        println!("Indexes loaded: {} of {}", loaded, release.components.len() * load_arches.len());
+        println!("Packages available: {}", self.available.keys().len());
+    }
+
+    // loads the first index file of index_dir (under dir) listed in indexes which is present and checks out
+    fn load_verified_index(&mut self, dir: &Path, index_dir: &str, indexes: &[IndexEntry], source: usize) -> Result<(), String> {
+        let mut listed = false;
+        for name in INDEX_NAMES {
+            let relative = format!("{}/{}", index_dir, name);
+            let entry = match indexes.iter().find(|e| e.path == relative) {
+                None => continue,
+                Some(entry) => entry
+            };
+            listed = true;
+            let path = dir.join(&relative);
+            if !path.exists() {
+                continue;
+            }
+            if let Err(e) = verify(&path, entry) {
+                println!("{}: not loading: {}", path.display(), e);
+                continue;
+            }
+            let filename = path.to_string_lossy();
+            return match self.read_packages_parallel(&filename, source) {
+                Err(e) => Err(format!("can't read {}: {}", filename, e)),
+                Ok(messages) => {
+                    for message in messages {
+                        println!("{}", message);
+                    }
+                    Ok(())
+                }
+            };
+        }
+        Err(String::from(if listed { "no index file that matches the Release file" } else { "no index file in the Release file" }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use flate2::write::GzEncoder;
+
+    use super::{read_release, sha256_of, verify, IndexEntry};
+    use crate::Packages;
+
+    const PACKAGES: &str = "Package: hello\nVersion: 2.10-3\nArchitecture: amd64\n\nPackage: hello-data\nVersion: 2.10-3\nArchitecture: all\n";
+
+    // an empty mirror directory of its own for test name
+    fn mirror(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rpkg-mirror-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("dists/sid/main/binary-amd64")).unwrap();
+        dir
+    }
+
+    fn gzip(text: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // a Release file listing the given index files of suite_dir, as they are now
+    fn release(suite_dir: &Path, indexes: &[&str]) -> String {
+        let mut text = String::from("Origin: Debian\nSuite: unstable\nComponents: main\nArchitectures: amd64\nSHA256:\n");
+        for index in indexes {
+            let path = suite_dir.join(index);
+            let size = fs::metadata(&path).unwrap().len();
+            text.push_str(&format!(" {} {} {}\n", sha256_of(&path).unwrap(), size, index));
+        }
+        text
+    }
+
+    fn entry(path: &Path) -> IndexEntry {
+        let size = fs::metadata(path).unwrap().len();
+        IndexEntry { sha256: sha256_of(path).unwrap(), size, path: String::from("Packages") }
+    }
+
+    #[test]
+    fn verifies_size_and_sha256() {
+        let dir = mirror("verify");
+        let path = dir.join("Packages");
+        fs::write(&path, PACKAGES).unwrap();
+        let good = entry(&path);
+        assert_eq!(verify(&path, &good), Ok(()));
+        let longer = IndexEntry { size: good.size + 1, ..entry(&path) };
+        assert!(verify(&path, &longer).unwrap_err().starts_with("size "));
+        // the same size, but one byte changed
+        fs::write(&path, PACKAGES.replace("2.10-3", "2.10-4")).unwrap();
+        assert!(verify(&path, &good).unwrap_err().starts_with("SHA256 "));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_inrelease_when_there_is_no_release() {
+        let dir = mirror("inrelease");
+        let suite_dir = dir.join("dists/sid");
+        fs::write(suite_dir.join("main/binary-amd64/Packages"), PACKAGES).unwrap();
+        let body = release(&suite_dir, &["main/binary-amd64/Packages"]);
+        let signed = format!("-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA512\n\n{}-----BEGIN PGP SIGNATURE-----\n\nxyz\n-----END PGP SIGNATURE-----\n", body);
+        fs::write(suite_dir.join("InRelease"), signed).unwrap();
+        let (path, paragraph) = read_release(&suite_dir).unwrap();
+        assert_eq!(path, suite_dir.join("InRelease"));
+        assert_eq!(paragraph.get("Suite"), Some("unstable"));
+        let mut packages = Packages::new();
+        packages.load_mirror(dir.to_str().unwrap(), "sid", &[]);
+        assert!(packages.package_exists("hello") && packages.package_exists("hello-data"));
+        assert_eq!(packages.sources[0].suite, "unstable");
+        assert_eq!(packages.sources[0].origin, "Debian");
+        // a Release file, if there is one, comes first
+        fs::write(suite_dir.join("Release"), body.replace("Suite: unstable", "Suite: sid")).unwrap();
+        assert_eq!(read_release(&suite_dir).unwrap().1.get("Suite"), Some("sid"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn never_loads_a_corrupted_index() {
+        let dir = mirror("corrupted");
+        let suite_dir = dir.join("dists/sid");
+        let index_dir = suite_dir.join("main/binary-amd64");
+        fs::write(index_dir.join("Packages"), PACKAGES).unwrap();
+        fs::write(index_dir.join("Packages.gz"), gzip(PACKAGES)).unwrap();
+        fs::write(suite_dir.join("Release"), release(&suite_dir, &["main/binary-amd64/Packages", "main/binary-amd64/Packages.gz"])).unwrap();
+        // the plain index is corrupted, so the compressed one is loaded instead
+        fs::write(index_dir.join("Packages"), PACKAGES.replace("hello-data", "hello-evil")).unwrap();
+        let mut packages = Packages::new();
+        packages.load_mirror(dir.to_str().unwrap(), "sid", &[]);
+        assert!(packages.package_exists("hello-data"));
+        assert!(!packages.package_exists("hello-evil"));
+        // and once that is corrupted too, nothing is
+        let mut gz = gzip(PACKAGES);
+        let last = gz.len() - 1;
+        gz[last] ^= 0xff;
+        fs::write(index_dir.join("Packages.gz"), gz).unwrap();
+        let mut packages = Packages::new();
+        packages.load_mirror(dir.to_str().unwrap(), "sid", &[]);
+        assert!(!packages.package_exists("hello"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,111 +1,502 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicI32, Ordering};
 
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 
 use rpkg::debversion;
 use rpkg::debversion::{DebianVersionNum,VersionRelation};
 
 mod deps_available;
 mod solvers;
+mod sat;
+mod relations;
 mod parsers;
 mod async_fns;
+mod pinning;
+mod dep_expr;
+mod rdepends;
+mod autoremove;
+mod why;
+mod order;
+mod parallel_load;
+mod decompress;
+mod cache;
+mod mirror;
+mod graph;
+mod stats;
 
 use crate::packages::async_fns::AsyncState;
+use crate::packages::solvers::{PlanSize, Removal, UpgradePlan};
+use crate::packages::pinning::{Pin, Source};
+use crate::packages::dep_expr::Restrictions;
+
+pub use crate::packages::pinning::DEFAULT_PRIORITY;
+pub use crate::packages::stats::STATS_LIMIT;
+pub use crate::packages::cache::CACHE_FILE;
+pub use crate::packages::graph::GraphFormat;
 
 static PACKAGE_COUNTER: AtomicI32 = AtomicI32::new(0);
 
 pub struct Packages {
-    dependencies : HashMap<i32,Vec<Dependency>>,
-    md5sums : HashMap<i32,String>,
-    available_debvers : HashMap<i32,DebianVersionNum>,
+    candidates : Vec<Candidate>,
+    available : HashMap<i32,Vec<usize>>, // package -> its candidates, highest version first
+    providers : HashMap<i32,Vec<usize>>, // virtual package -> candidates that provide it
+    conflicts_on : HashMap<i32,Vec<usize>>, // package or virtual package -> candidates declaring Conflicts or Breaks on it
+    depended_on : HashMap<i32,Vec<usize>>, // package or virtual package -> candidates naming it in a dependency field
+    arch_variants : HashMap<i32,Vec<i32>>, // package -> the same package for foreign architectures (name:arch)
+    installed_relations : HashMap<i32,usize>, // installed package -> an unavailable candidate with its status stanza's relations
+    native_arch : String,
     installed_debvers : HashMap<i32,DebianVersionNum>,
+    auto_installed : HashSet<i32>, // installed only to satisfy dependencies, according to extended_states
+    installed_sizes : HashMap<i32,u64>, // Installed-Size (KiB) of installed packages, from the status file
+    essential : HashSet<i32>, // installed packages marked Essential: yes
     package_name_to_num : HashMap<String, i32>,
     package_num_to_name : HashMap<i32, String>,
+    sources : Vec<Source>,
+    pins : Vec<Pin>,
     async_state : AsyncState,
 }
 
 // Dependency([X, Y, Z]) means X|Y|Z
+#[derive(Clone, Serialize, Deserialize)]
 pub struct RelVersionedPackageNum {
     package_num : i32,
-    rel_version : Option<(VersionRelation, String)>
+    arch_qualifier : Option<String>, // "any", "native" or an architecture, as in foo:any
+    rel_version : Option<(VersionRelation, String)>,
+    restrictions : Restrictions, // [amd64 arm64] <!nocheck>, as in source package relations
 }
 pub type Dependency = Vec<RelVersionedPackageNum>;
 
+/// One (name, version, architecture) entry of a Packages file, with the fields that belong to that version.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Candidate {
+    pub package_num : i32,
+    pub version : DebianVersionNum,
+    pub architecture : String,
+    pub multi_arch : MultiArch,
+    pub md5sum : Option<String>,
+    pub size : Option<u64>, // of the .deb in bytes, from the Size field
+    pub installed_size : Option<u64>, // in KiB, from the Installed-Size field
+    pub sources : Vec<usize>, // the sources listing this entry
+    pre_dependencies : Vec<Dependency>,
+    dependencies : Vec<Dependency>,
+    recommends : Vec<Dependency>,
+    suggests : Vec<Dependency>,
+    provides : Vec<RelVersionedPackageNum>,
+    conflicts : Vec<RelVersionedPackageNum>,
+    breaks : Vec<RelVersionedPackageNum>,
+    replaces : Vec<RelVersionedPackageNum>,
+}
+
+impl Candidate {
+    pub fn new(package_num: i32, version: DebianVersionNum) -> Candidate {
+        Candidate {
+            package_num,
+            version,
+            architecture : String::new(),
+            multi_arch : MultiArch::No,
+            md5sum : None,
+            size : None,
+            installed_size : None,
+            sources : vec![],
+            pre_dependencies : vec![],
+            dependencies : vec![],
+            recommends : vec![],
+            suggests : vec![],
+            provides : vec![],
+            conflicts : vec![],
+            breaks : vec![],
+            replaces : vec![],
+        }
+    }
+
+    pub fn relations(&self, kind: RelationKind) -> &[Dependency] {
+        match kind {
+            RelationKind::PreDepends => &self.pre_dependencies,
+            RelationKind::Depends => &self.dependencies,
+            RelationKind::Recommends => &self.recommends,
+            RelationKind::Suggests => &self.suggests,
+        }
+    }
+}
+
+/// The Multi-Arch field: whether a package can be co-installed with itself for other architectures (same), satisfies
+/// dependencies from packages of any architecture (foreign), or satisfies pkg:any dependencies (allowed).
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum MultiArch {
+    No,
+    Same,
+    Foreign,
+    Allowed,
+}
+
+impl MultiArch {
+    pub fn from_field(value: &str) -> MultiArch {
+        match value.trim() {
+            "same" => MultiArch::Same,
+            "foreign" => MultiArch::Foreign,
+            "allowed" => MultiArch::Allowed,
+            _ => MultiArch::No,
+        }
+    }
+
+    pub fn field_value(&self) -> &'static str {
+        match self {
+            MultiArch::No => "no",
+            MultiArch::Same => "same",
+            MultiArch::Foreign => "foreign",
+            MultiArch::Allowed => "allowed",
+        }
+    }
+}
+
+/// A package at a particular version (available or installed), plus the candidate which describes its
+/// relations, if there is one.
+#[derive(Clone, Copy)]
+pub struct VersionedPackage<'a> {
+    pub package_num : i32,
+    pub version : &'a DebianVersionNum,
+    pub candidate : Option<usize>,
+}
+
+// The fields of a stanza which list dependencies, all with the same A | B, C syntax.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RelationKind {
+    PreDepends,
+    Depends,
+    Recommends,
+    Suggests,
+}
+
+impl RelationKind {
+    pub const ALL: [RelationKind; 4] =
+        [RelationKind::PreDepends, RelationKind::Depends, RelationKind::Recommends, RelationKind::Suggests];
+
+    pub fn field_name(&self) -> &'static str {
+        match self {
+            RelationKind::PreDepends => "Pre-Depends",
+            RelationKind::Depends => "Depends",
+            RelationKind::Recommends => "Recommends",
+            RelationKind::Suggests => "Suggests",
+        }
+    }
+
+    // as in "foo pre-depends on bar"
+    pub fn verb(&self) -> &'static str {
+        match self {
+            RelationKind::PreDepends => "pre-depends on",
+            RelationKind::Depends => "depends on",
+            RelationKind::Recommends => "recommends",
+            RelationKind::Suggests => "suggests",
+        }
+    }
+}
+
+/// Which relation kinds the solvers follow. Pre-Depends and Depends are always followed;
+/// Recommends and Suggests only on request (--with-recommends, --with-suggests).
+#[derive(Clone, Copy, Default)]
+pub struct RelationPolicy {
+    pub recommends : bool,
+    pub suggests : bool,
+}
+
+impl RelationPolicy {
+    pub fn follows(&self, kind: RelationKind) -> bool {
+        match kind {
+            RelationKind::PreDepends | RelationKind::Depends => true,
+            RelationKind::Recommends => self.recommends,
+            RelationKind::Suggests => self.suggests,
+        }
+    }
+}
+
+/// How how-to-install picks among the candidates satisfying a dependency. Whatever the strategy, candidates which
+/// don't conflict with installed packages come first, and remaining ties go to the higher pin priority, then the
+/// higher version, then the earlier alternative, then the package name.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum InstallStrategy {
+    #[default]
+    PreferInstalled, // upgrade an alternative that is installed at the wrong version
+    Newest, // the highest version, installed or not
+    MinNew, // the fewest packages newly installed, counting what the candidate pulls in
+    MinSize, // the smallest total download Size, counting what the candidate pulls in
+}
+
+impl FromStr for InstallStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefer-installed" => Ok(InstallStrategy::PreferInstalled),
+            "newest" => Ok(InstallStrategy::Newest),
+            "min-new" => Ok(InstallStrategy::MinNew),
+            "min-size" => Ok(InstallStrategy::MinSize),
+            _ => Err(format!("unknown strategy {} (prefer-installed, newest, min-new or min-size)", s))
+        }
+    }
+}
+
+// the names under which a candidate is listed in providers, conflicts_on and depended_on, in that order, each once
+fn relation_keys(cand: &Candidate) -> [Vec<i32>; 3] {
+    let provided = cand.provides.iter().map(|rel| rel.package_num).unique().collect_vec();
+    let conflicting = cand.conflicts.iter().chain(cand.breaks.iter()).map(|rel| rel.package_num).unique().collect_vec();
+    let depended = RelationKind::ALL.iter().flat_map(|kind| cand.relations(*kind).iter().flatten())
+        .map(|rel| rel.package_num).unique().collect_vec();
+    [provided, conflicting, depended]
+}
+
 impl Packages {
     pub fn new() -> Packages {
         Packages { 
-            dependencies : HashMap::new(), 
-            md5sums : HashMap::new(),
-            available_debvers : HashMap::new(),
+            candidates : vec![],
+            available : HashMap::new(),
+            providers : HashMap::new(),
+            conflicts_on : HashMap::new(),
+            depended_on : HashMap::new(),
+            arch_variants : HashMap::new(),
+            installed_relations : HashMap::new(),
+            native_arch : String::from("amd64"),
             installed_debvers : HashMap::new(),
+            auto_installed : HashSet::new(),
+            installed_sizes : HashMap::new(),
+            essential : HashSet::new(),
             package_name_to_num : HashMap::new(), 
             package_num_to_name : HashMap::new(),
+            sources : vec![],
+            pins : vec![],
             async_state : AsyncState::new(),
         }
     }
 
     // next few functions manipulate the list of packages and the name/number interface
-    pub fn get_package_names(&self) -> Vec<&str> {
-        self.package_name_to_num.keys().map(|x| &x[..]).collect()
-    }
-
+    #[allow(clippy::needless_return)]
     fn get_package_name(&self, package_num: i32) -> &str {
         return self.package_num_to_name.get(&package_num).unwrap();
     }
 
     // panics if package_name doesn't already exist
+    #[allow(clippy::needless_return)]
     fn get_package_num(&self, package_name: &str) -> &i32 {
         return self.package_name_to_num.get(package_name).unwrap();
     }
 
     // inserts package_name into package_name_to_num if it doesn't already exist
+    #[allow(clippy::needless_return)]
     fn get_package_num_inserting(&mut self, package_name: &str) -> i32 {
-        if !self.package_name_to_num.contains_key(package_name) {
-            let pnum = PACKAGE_COUNTER.load(Ordering::SeqCst);
-            self.package_name_to_num.insert(String::from(package_name), pnum);
-            self.package_num_to_name.insert(pnum, String::from(package_name));
-            self.dependencies.insert(pnum, vec![]);
-            PACKAGE_COUNTER.fetch_add(1, Ordering::SeqCst);
-            return pnum;
-        } else {
-            return *self.package_name_to_num.get(package_name).unwrap();
+        if let Some(pnum) = self.package_name_to_num.get(package_name) {
+            return *pnum;
+        }
+        let pnum = PACKAGE_COUNTER.load(Ordering::SeqCst);
+        self.package_name_to_num.insert(String::from(package_name), pnum);
+        self.package_num_to_name.insert(pnum, String::from(package_name));
+        PACKAGE_COUNTER.fetch_add(1, Ordering::SeqCst);
+        return pnum;
+    }
+
+    /// Returns the number of package_name built for arch: the plain name for the native architecture and for
+    /// Architecture: all, and name:arch (a package of its own) for a foreign one.
+    fn get_package_num_for_arch_inserting(&mut self, package_name: &str, arch: &str) -> i32 {
+        let base = self.get_package_num_inserting(package_name);
+        if arch.is_empty() || arch == "all" || arch == self.native_arch {
+            return base;
+        }
+        let package_num = self.get_package_num_inserting(&format!("{}:{}", package_name, arch));
+        let variants = self.arch_variants.entry(base).or_default();
+        if !variants.contains(&package_num) {
+            variants.push(package_num);
         }
+        package_num
+    }
+
+    /// Sets the native architecture. Packages for other architectures are named name:arch, so this should be
+    /// done before loading anything.
+    pub fn set_native_arch(&mut self, arch: &str) {
+        if !self.package_name_to_num.is_empty() {
+            println!("warning: packages already loaded keep the names they got for architecture {}", self.native_arch);
+        }
+        self.native_arch = String::from(arch);
+    }
+
+    /// Returns the architecture of package_num: the one in its name:arch, or else the native one.
+    pub fn package_arch(&self, package_num: i32) -> &str {
+        match self.get_package_name(package_num).split_once(':') {
+            None => &self.native_arch,
+            Some((_, arch)) => arch
+        }
+    }
+
+    /// Returns the package that package_num is an architecture variant of, or package_num itself.
+    pub fn base_package_num(&self, package_num: i32) -> i32 {
+        match self.get_package_name(package_num).split_once(':') {
+            None => package_num,
+            Some((name, _)) => *self.get_package_num(name)
+        }
+    }
+
+    /// Returns package_num and its variants for every other architecture.
+    pub fn all_arch_variants(&self, package_num: i32) -> Vec<i32> {
+        let base = self.base_package_num(package_num);
+        let mut result = vec![base];
+        result.extend(self.arch_variants.get(&base).into_iter().flatten());
+        result
     }
 
+    #[allow(clippy::needless_return)]
     pub fn package_exists(&self, package_name: &str) -> bool {
         return self.package_name_to_num.contains_key(package_name);
     }
 
-    // accessor methods for various maps
-    pub fn get_available_debver(&self, package_name: &str) -> Option<&DebianVersionNum> {
-        let package_num = self.package_name_to_num.get(package_name);
-        return match package_num {
-            None => None,
-            Some(x) => match self.available_debvers.get(x) {
-                None => None,
-                Some(y) => Some(y)
+    /// Adds cand to the index and returns its candidate number. A candidate with the same name, version and
+    /// architecture as an existing one replaces it (as when the same Packages file is loaded twice), but keeps
+    /// the sources it was listed in.
+    fn insert_candidate(&mut self, mut cand: Candidate) -> usize {
+        let existing = self.get_candidates(cand.package_num).iter().copied()
+            .find(|c| self.candidates[*c].version == cand.version && self.candidates[*c].architecture == cand.architecture);
+        let old_keys = existing.map_or_else(Default::default, |idx| relation_keys(&self.candidates[idx]));
+        let idx = match existing {
+            Some(idx) => {
+                for s in &self.candidates[idx].sources {
+                    if !cand.sources.contains(s) {
+                        cand.sources.push(*s);
+                    }
+                }
+                self.candidates[idx] = cand;
+                idx
+            }
+            None => {
+                let idx = self.candidates.len();
+                let package_num = cand.package_num;
+                self.candidates.push(cand);
+                let candidates = &self.candidates;
+                let versions = self.available.entry(package_num).or_default();
+                let pos = versions.iter().position(|c| candidates[*c].version < candidates[idx].version).unwrap_or(versions.len());
+                versions.insert(pos, idx);
+                idx
+            }
+        };
+        self.reindex_relations(idx, old_keys);
+        idx
+    }
+
+    /// Records the relations that the status stanza of an installed package lists, in a candidate of their own.
+    /// No source lists that candidate, so it can't be installed; it only stands for the installed version when
+    /// no Packages file lists the package. Loading a status file again replaces it.
+    fn insert_installed_relations(&mut self, cand: Candidate) {
+        match self.installed_relations.get(&cand.package_num) {
+            Some(&idx) => {
+                let old_keys = relation_keys(&self.candidates[idx]);
+                self.candidates[idx] = cand;
+                self.reindex_relations(idx, old_keys);
+            }
+            None => {
+                let idx = self.candidates.len();
+                self.installed_relations.insert(cand.package_num, idx);
+                self.candidates.push(cand);
+                self.reindex_relations(idx, Default::default());
             }
         }
     }
 
-    pub fn get_installed_debver(&self, package_name: &str) -> Option<&DebianVersionNum> {
-        let package_num = self.package_name_to_num.get(package_name);
-        return match package_num {
-            None => None,
-            Some(x) => match self.installed_debvers.get(x) {
-                None => None,
-                Some(y) => Some(y)
+    // moves candidate idx, whose relations used to mention the names old_keys, to the entries of providers,
+    // conflicts_on and depended_on for those they mention now
+    fn reindex_relations(&mut self, idx: usize, old_keys: [Vec<i32>; 3]) {
+        // a replaced candidate only leaves (or joins) the entries of the names its relations no longer (or newly)
+        // mention, so that reloading a file doesn't go through the long lists of popular names again
+        let new_keys = relation_keys(&self.candidates[idx]);
+        let mut indexes = [&mut self.providers, &mut self.conflicts_on, &mut self.depended_on];
+        for ((index, old), new) in indexes.iter_mut().zip(&old_keys).zip(&new_keys) {
+            for name in old.iter().filter(|name| !new.contains(name)) {
+                if let Some(cands) = index.get_mut(name) {
+                    cands.retain(|c| *c != idx);
+                }
+            }
+            for name in new.iter().filter(|name| !old.contains(name)) {
+                index.entry(*name).or_default().push(idx);
+            }
+        }
+    }
+
+    // accessor methods for various maps
+    pub fn get_candidate(&self, candidate: usize) -> &Candidate {
+        &self.candidates[candidate]
+    }
+
+    /// Returns the candidates for package_num, highest version first.
+    pub fn get_candidates(&self, package_num: i32) -> &[usize] {
+        match self.available.get(&package_num) {
+            None => &[],
+            Some(cands) => cands
+        }
+    }
+
+    /// Returns the candidate that is installed when nothing else constrains the choice: the one apt's policy picks
+    /// or, if that is an installed version which is no longer available, the most preferred one.
+    pub fn best_candidate(&self, package_num: i32) -> Option<usize> {
+        self.policy_candidate(package_num)
+            .or_else(|| self.candidates_by_preference(package_num).first().copied())
+    }
+
+    pub fn find_candidate(&self, package_num: i32, version: &DebianVersionNum) -> Option<usize> {
+        self.get_candidates(package_num).iter().copied().find(|c| self.candidates[*c].version == *version)
+    }
+
+    pub fn available_version(&self, candidate: usize) -> VersionedPackage<'_> {
+        let cand = &self.candidates[candidate];
+        VersionedPackage { package_num: cand.package_num, version: &cand.version, candidate: Some(candidate) }
+    }
+
+    /// Returns the installed version of package_num. Its relations are those of the candidate with the same version
+    /// or, if the installed version is no longer available, those of the highest version, or, if no Packages file
+    /// lists the package at all, those its status stanza lists.
+    pub fn installed_version(&self, package_num: i32) -> Option<VersionedPackage<'_>> {
+        let version = self.installed_debvers.get(&package_num)?;
+        let candidate = self.find_candidate(package_num, version)
+            .or_else(|| self.get_candidates(package_num).first().copied())
+            .or_else(|| self.installed_relations.get(&package_num).copied());
+        Some(VersionedPackage { package_num, version, candidate })
+    }
+
+    // relations of the best candidate of package_num, if any
+    fn get_relations(&self, package_num: i32, kind: RelationKind) -> &[Dependency] {
+        match self.best_candidate(package_num) {
+            None => &[],
+            Some(c) => self.candidates[c].relations(kind)
+        }
+    }
+
+    /// Returns the dependencies of the given candidate that policy says to follow, tagged with their kind,
+    /// leaving out those whose restrictions don't apply to its architecture.
+    fn followed_relations(&self, candidate: Option<usize>, policy: &RelationPolicy) -> Vec<(RelationKind, &Dependency)> {
+        let mut result = vec![];
+        if let Some(c) = candidate {
+            let arch = self.package_arch(self.candidates[c].package_num);
+            for kind in RelationKind::ALL {
+                if policy.follows(kind) {
+                    for dep in self.candidates[c].relations(kind) {
+                        if self.dep_applies(dep, arch) {
+                            result.push((kind, dep));
+                        }
+                    }
+                }
             }
         }
+        result
+    }
+
+    pub fn get_available_debver(&self, package_name: &str) -> Option<&DebianVersionNum> {
+        let package_num = self.package_name_to_num.get(package_name)?;
+        self.best_candidate(*package_num).map(|c| &self.candidates[c].version)
     }
 
-    pub fn get_md5sum(&self, package_name: &str) -> Option<&str> {
+    #[allow(clippy::needless_return)]
+    pub fn get_installed_debver(&self, package_name: &str) -> Option<&DebianVersionNum> {
         let package_num = self.package_name_to_num.get(package_name);
         return match package_num {
             None => None,
-            Some(x) => match self.md5sums.get(x) {
+            Some(x) => match self.installed_debvers.get(x) {
                 None => None,
                 Some(y) => Some(y)
             }
@@ -113,107 +504,340 @@ impl Packages {
     }
 
     // helper functions; these aren't structs so I can't make them implement Fmt::Display.
-    fn deps2str(&self, deps: &Vec<Dependency>) -> String {
+    #[allow(clippy::needless_return)]
+    fn deps2str(&self, deps: &[Dependency]) -> String {
         return deps.iter().map(|dep| self.dep2str(dep)).format(", ").to_string();
     }
 
-    fn dep2str(&self, dep: &Dependency) -> String {
+    #[allow(clippy::needless_return)]
+    fn dep2str(&self, dep: &[RelVersionedPackageNum]) -> String {
         return dep.iter().map(|d| {
-            let pn = self.get_package_name(d.package_num);
+            let pn = match &d.arch_qualifier {
+                None => String::from(self.get_package_name(d.package_num)),
+                Some(arch) => format!("{}:{}", self.get_package_name(d.package_num), arch)
+            };
             match &d.rel_version {
-                None => String::from(pn),
-                Some((rel, ver)) => format!("{} ({} {})", pn, rel.to_string(), ver)
+                None => format!("{}{}", pn, d.restrictions),
+                Some((rel, ver)) => format!("{} ({} {}){}", pn, rel, ver, d.restrictions)
             }
         }).format(" | ").to_string();
     }
 
+    fn rels2str(&self, rels: &[RelVersionedPackageNum]) -> String {
+        rels.iter().map(|rel| self.dep2str(std::slice::from_ref(rel))).format(", ").to_string()
+    }
+
+    fn removals2str(&self, removals: &[Removal]) -> String {
+        removals.iter().map(|r| {
+            let reason = if r.replaced { "replaced by" } else { "conflicts with" };
+            format!("{} ({} {})", self.get_package_name(r.package_num), reason, self.get_package_name(r.because_of))
+        }).format(", ").to_string()
+    }
+
+    // finds the candidate named by an optional version argument, or the best one; prints an error if there isn't one
+    fn select_candidate(&self, package_name: &str, version: Option<&str>) -> Option<usize> {
+        let package_num = *self.get_package_num(package_name);
+        let cand = match version.map(|v| v.parse::<debversion::DebianVersionNum>()) {
+            None => self.best_candidate(package_num),
+            Some(Ok(v)) => self.find_candidate(package_num, &v),
+            Some(Err(e)) => {
+                println!("bad version {}: {}", version.unwrap(), e);
+                return None;
+            }
+        };
+        if cand.is_none() {
+            if let Some(v) = version {
+                println!("no version {} of package {}", v, package_name);
+            }
+        }
+        cand
+    }
+
     // output commands
-    pub fn print_deps(&self, package_name: &str) {
+    pub fn print_deps(&self, package_name: &str, version: Option<&str>) {
         if !self.package_exists(package_name) {
             println!("no such package {}", package_name);
             return;
         }
-        let deps : &Vec<Dependency> = &*self.dependencies.get(self.get_package_num(package_name)).unwrap();
+        let deps : &[Dependency] = match self.select_candidate(package_name, version) {
+            None if version.is_some() => return,
+            None => &[],
+            Some(c) => self.candidates[c].relations(RelationKind::Depends)
+        };
         println!("{:?} depends on {:?}", package_name, self.deps2str(deps));
     }
 
-    pub fn print_transitive_dep_solution(&self, package_name: &str) {
+    pub fn print_transitive_dep_solution(&self, package_name: &str, policy: &RelationPolicy) {
         if !self.package_exists(package_name) {
             println!("no such package {}", package_name);
             return;
         }
-        let dep_solution : Vec<i32> = self.transitive_dep_solution(package_name);
-        println!("{:?} transitive dependency solution: {:?}", package_name, dep_solution.iter().map(|dep| self.get_package_name(*dep)).format(", ").to_string());
+        match self.transitive_dep_solution(package_name, policy) {
+            Ok(dep_solution) => {
+                println!("{:?} transitive dependency solution: {:?}", package_name, dep_solution.packages.iter().map(|(dep, _)| self.get_package_name(*dep)).format(", ").to_string());
+                if !dep_solution.removals.is_empty() {
+                    println!("{:?} to remove: {:?}", package_name, self.removals2str(&dep_solution.removals));
+                }
+            }
+            Err(explanation) => {
+                println!("{:?} cannot be installed:", package_name);
+                println!("{}", explanation);
+            }
+        }
     }
 
-    pub fn print_how_to_install(&self, package_name: &str) {
+    // apt's way of writing sizes: 4 significant digits at most, in B, kB, MB, ... (powers of 1000)
+    fn size2str(bytes: u64) -> String {
+        let mut size = bytes as f64;
+        for unit in ["", "k", "M", "G", "T"] {
+            if size < 100.0 && !unit.is_empty() {
+                return format!("{:.1} {}B", size, unit);
+            }
+            if size < 10000.0 {
+                return format!("{:.0} {}B", size, unit);
+            }
+            size /= 1000.0;
+        }
+        format!("{:.0} PB", size)
+    }
+
+    fn print_plan_size(&self, size: &PlanSize) {
+        println!("Need to get {} of archives.", Self::size2str(size.download));
+        let change = Self::size2str(size.disk_delta.unsigned_abs());
+        if size.disk_delta >= 0 {
+            println!("After this operation, {} of additional disk space will be used.", change);
+        } else {
+            println!("After this operation, {} disk space will be freed.", change);
+        }
+    }
+
+    /// Prints the plan plan_install makes for package_name, or why it can't stay within max_size.
+    pub fn print_how_to_install(&self, package_name: &str, policy: &RelationPolicy, strategy: InstallStrategy, max_size: Option<u64>) {
         if !self.package_exists(package_name) {
             println!("no such package {}", package_name);
             return;
         }
         println!("Package {}:", package_name);
-        let pkgs_to_install:Vec<i32> = self.compute_how_to_install(package_name);
-        println!("{:?} to install: {:?}", package_name, pkgs_to_install.iter().map(|dep| self.get_package_name(*dep)).format(", ").to_string());
+        let plan = match self.plan_install(package_name, policy, strategy, max_size) {
+            Ok(plan) => plan,
+            Err(plan) => {
+                println!("{:?} can't be installed within --max-size {}: it needs {} of archives", package_name,
+                         Self::size2str(max_size.unwrap()), Self::size2str(plan.size.download));
+                return;
+            }
+        };
+        if plan.strategy != strategy {
+            println!("(picking the smallest alternatives to stay within --max-size)");
+        }
+        println!("{:?} to install: {:?}", package_name, plan.dependencies.iter().map(|dep| self.get_package_name(self.candidates[*dep].package_num)).format(", ").to_string());
+        let new_candidates = plan.candidates.iter().copied()
+            .filter(|c| self.installed_debvers.get(&self.candidates[*c].package_num) != Some(&self.candidates[*c].version))
+            .collect_vec();
+        println!("{:?} install order: {:?}", package_name, self.install_order2str(&self.install_order(&new_candidates)));
+        if !plan.removals.is_empty() {
+            println!("{:?} to remove: {:?}", package_name, self.removals2str(&plan.removals));
+        }
+        self.print_plan_size(&plan.size);
+        for problem in self.contradictory_version_constraints(&plan.candidates) {
+            println!("{:?} can't be installed: {}", package_name, problem);
+        }
+        if !self.unsatisfiable_dependencies(*self.get_package_num(package_name)).is_empty() {
+            println!("{:?} can't be installed: some dependencies can't be satisfied (see why-not {})", package_name, package_name);
+        }
+    }
+
+    /// Prints the plan compute_upgrade_plan makes, unless its download is bigger than a max_size budget (in bytes).
+    pub fn print_upgrade_plan(&self, max_size: Option<u64>) {
+        let plan: UpgradePlan = self.compute_upgrade_plan();
+        let changed = plan.installs.iter().chain(plan.upgrades.iter().chain(plan.downgrades.iter()).map(|(_, c)| c)).copied().collect_vec();
+        let size = self.plan_size(&changed, &plan.removals);
+        if let Some(budget) = max_size.filter(|budget| size.download > *budget) {
+            println!("The upgrade can't be done within --max-size {}: it needs {} of archives.", Self::size2str(budget), Self::size2str(size.download));
+            return;
+        }
+        let by_name = |pns: &mut Vec<i32>| pns.sort_by(|a, b| self.get_package_name(*a).cmp(self.get_package_name(*b)));
+        let mut removals = plan.removals.clone();
+        let mut kept_back = plan.kept_back.clone();
+        by_name(&mut removals);
+        by_name(&mut kept_back);
+        let changes = |changes: &[(i32, usize)]| changes.iter()
+            .map(|(pn, c)| (self.get_package_name(*pn), format!("{} ({} => {})", self.get_package_name(*pn), self.installed_debvers[pn], self.candidates[*c].version)))
+            .sorted()
+            .map(|(_, line)| line)
+            .collect_vec();
+        let installs = plan.installs.iter()
+            .map(|c| format!("{} ({})", self.get_package_name(self.candidates[*c].package_num), self.candidates[*c].version))
+            .sorted()
+            .collect_vec();
+        let sections = [
+            ("The following NEW packages will be installed:", installs),
+            ("The following packages will be upgraded:", changes(&plan.upgrades)),
+            ("The following packages will be DOWNGRADED:", changes(&plan.downgrades)),
+            ("The following packages have been kept back:", kept_back.iter().map(|pn| String::from(self.get_package_name(*pn))).collect_vec()),
+            ("The following packages will be REMOVED:", removals.iter().map(|pn| String::from(self.get_package_name(*pn))).collect_vec()),
+        ];
+        for (heading, lines) in &sections {
+            if !lines.is_empty() {
+                println!("{}", heading);
+                for line in lines {
+                    println!("  {}", line);
+                }
+            }
+        }
+        println!("{} upgraded, {} newly installed, {} downgraded, {} to remove and {} not upgraded.",
+                 plan.upgrades.len(), plan.installs.len(), plan.downgrades.len(), plan.removals.len(), plan.kept_back.len());
+        self.print_plan_size(&size);
     }
 
-    pub fn print_info(&self, package_name: &str) {
+    pub fn print_info(&self, package_name: &str, version: Option<&str>) {
         if !self.package_exists(package_name) {
             println!("no such package {}", package_name);
             return;
         }
+        let package_num = *self.get_package_num(package_name);
+        let cand = self.select_candidate(package_name, version);
+        if cand.is_none() && version.is_some() {
+            return;
+        }
         println!("Package: {}", package_name);
-        let a = self.get_available_debver(package_name);
+        let a = cand.map(|c| &self.candidates[c]);
         let i = self.get_installed_debver(package_name);
         match a {
             None => (),
             Some(a) => {
-                println!("Version: {}", a.to_string());
-                println!("MD5Sum: {}", self.get_md5sum(package_name).unwrap().to_string());
-                println!("Depends: {}", self.deps2str(&*self.dependencies.get(self.get_package_num(package_name)).unwrap()));
+                println!("Version: {}", a.version);
+                if !a.architecture.is_empty() {
+                    println!("Architecture: {}", a.architecture);
+                }
+                if a.multi_arch != MultiArch::No {
+                    println!("Multi-Arch: {}", a.multi_arch.field_value());
+                }
+                if let Some(md5sum) = &a.md5sum {
+                    println!("MD5Sum: {}", md5sum);
+                }
+                println!("Depends: {}", self.deps2str(a.relations(RelationKind::Depends)));
+                for kind in [RelationKind::PreDepends, RelationKind::Recommends, RelationKind::Suggests] {
+                    let deps = a.relations(kind);
+                    if !deps.is_empty() {
+                        println!("{}: {}", kind.field_name(), self.deps2str(deps));
+                    }
+                }
+                for (field, rels) in [("Provides", &a.provides), ("Conflicts", &a.conflicts),
+                                      ("Breaks", &a.breaks), ("Replaces", &a.replaces)] {
+                    if !rels.is_empty() {
+                        println!("{}: {}", field, self.rels2str(rels));
+                    }
+                }
             }
         }
+        let others = self.get_candidates(package_num).iter().filter(|c| Some(**c) != cand).collect_vec();
+        if !others.is_empty() {
+            println!("Other-Versions: {}", others.iter().map(|c| &self.candidates[**c].version).format(", "));
+        }
+        let other_archs = self.all_arch_variants(package_num).into_iter()
+            .filter(|p| *p != package_num && (self.available.contains_key(p) || self.installed_debvers.contains_key(p)))
+            .collect_vec();
+        if !other_archs.is_empty() {
+            println!("Other-Architectures: {}", other_archs.iter().map(|p| self.get_package_name(*p)).format(", "));
+        }
+        if let Some(providers) = self.providers.get(&package_num) {
+            println!("Provided-By: {}", providers.iter().map(|c| self.get_package_name(self.candidates[*c].package_num)).unique().format(", "));
+        }
         match i {
             None => (),
-            Some(i) => { println!("Installed-Version: {}", i.to_string()) }
-        }
-        match (a, i) {
-            (Some(aa), Some(ii)) =>
-                { println!("Newer-Available: {:?}", aa > ii); }
-            _ => ()
+            Some(i) => { println!("Installed-Version: {}", i) }
         }
+        if let (Some(aa), Some(ii)) = (self.get_available_debver(package_name), i) { println!("Newer-Available: {:?}", aa > ii); }
     }
 
     // generate output for package-verifier
     pub fn output_md5s(&self, fname: &str) {
         let path = Path::new(fname);
         let mut md5s : String = "name,version,hash\n".to_owned();
-        for pn in self.get_package_names() {
-            match (self.get_available_debver(pn), self.get_md5sum(pn)) {
-                (Some(v), Some(m)) => {
-                    let row = format!("{},{},{}\n",pn,v.to_string(),m);
-                    md5s.push_str(&row)
-                }
-                (_, _) => ()
+        for cand in &self.candidates {
+            if let Some(m) = &cand.md5sum {
+                let row = format!("{},{},{}\n",self.get_package_name(cand.package_num),cand.version,m);
+                md5s.push_str(&row)
             }
         }
         std::fs::write(path, md5s).unwrap();
     }
 
     // provided parse function to let students do the async io part independently
+    // rows that can't be read, or that don't have a valid name, version and md5sum, are reported and skipped
+    // the file may be compressed, as the other index files may (see decompress.rs)
     pub fn parse_csv(&mut self, filename: &str) {
-        let mut rdr = csv::Reader::from_path(filename).unwrap();
+        let mut rdr = match decompress::open(filename) {
+            Ok(file) => csv::Reader::from_reader(file),
+            Err(e) => { println!("can't read {}: {}", filename, e); return; }
+        };
+        let source = self.add_source(filename, DEFAULT_PRIORITY);
         for line in rdr.records() {
-            let line = line.unwrap();
-            let package_name = String::from(line.get(0).unwrap());
-            let debver = String::from(line.get(1).unwrap()).parse::<debversion::DebianVersionNum>().unwrap();
-            let md5sum = String::from(line.get(2).unwrap());
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => { println!("{}: skipping row: {}", filename, e); continue; }
+            };
+            let lineno = line.position().map_or(0, |p| p.line());
+            let (package_name, debver, md5sum) = match (line.get(0), line.get(1), line.get(2)) {
+                (Some(name), Some(version), Some(md5sum)) => (String::from(name), version, String::from(md5sum)),
+                _ => { println!("{}:{}: skipping row: needs name, version and md5sum", filename, lineno); continue; }
+            };
+            let debver = match debver.parse::<debversion::DebianVersionNum>() {
+                Ok(v) => v,
+                Err(e) => { println!("{}:{}: skipping {}: {}", filename, lineno, package_name, e); continue; }
+            };
 
             let package_num = self.get_package_num_inserting(&package_name);
-            self.available_debvers.insert(package_num, debver);
-            self.md5sums.insert(package_num, md5sum);
-
+            let mut cand = Candidate::new(package_num, debver);
+            cand.md5sum = Some(md5sum);
+            cand.sources.push(source);
+            self.insert_candidate(cand);
         }
 
-        println!("Packages available: {}", self.available_debvers.keys().len());
+        println!("Packages available: {}", self.available.keys().len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Packages;
+
+    #[test]
+    fn reloading_a_candidate_replaces_its_relations() {
+        let packages = Packages::from_text("\
+Package: other
+Version: 1
+Provides: mta
+Depends: libc6
+
+Package: exim
+Version: 1
+Provides: mta
+Conflicts: postfix
+Depends: libc6
+
+Package: exim
+Version: 1
+Provides: mail-transport-agent
+Breaks: sendmail
+Depends: libdb5.3
+
+Package: other
+Version: 1
+Provides: mta
+Depends: libc6
+", "");
+        let num = |name: &str| *packages.get_package_num(name);
+        let exim = packages.get_candidates(num("exim"))[0];
+        let other = packages.get_candidates(num("other"))[0];
+        assert_eq!(packages.get_candidates(num("exim")), &[exim]);
+        assert_eq!(packages.get_candidates(num("other")), &[other]);
+        let entries = |index: &std::collections::HashMap<i32, Vec<usize>>, name: &str| index.get(&num(name)).cloned().unwrap_or_default();
+        assert_eq!(entries(&packages.providers, "mta"), vec![other]);
+        assert_eq!(entries(&packages.providers, "mail-transport-agent"), vec![exim]);
+        assert_eq!(entries(&packages.conflicts_on, "postfix"), Vec::<usize>::new());
+        assert_eq!(entries(&packages.conflicts_on, "sendmail"), vec![exim]);
+        assert_eq!(entries(&packages.depended_on, "libc6"), vec![other]);
+        assert_eq!(entries(&packages.depended_on, "libdb5.3"), vec![exim]);
     }
 }
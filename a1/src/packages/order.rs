@@ -0,0 +1,153 @@
+// Ordering the packages of an install plan so that each one comes after what it needs.
+//
+// The plan's packages and their Pre-Depends and Depends edges (to other packages of the plan
+// that satisfy them) form a directed graph. Its strongly connected components, found with petgraph's
+// tarjan_scc, are scheduled dependencies first; a component with more than one package is a
+// dependency cycle, whose packages have to be configured together.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use petgraph::algo::tarjan_scc;
+use petgraph::Graph;
+
+use crate::packages::{RelationKind, RelationPolicy};
+use crate::Packages;
+
+impl Packages {
+    /// Returns the candidates of plan in an order they can be installed in, as groups: a group of one candidate can
+    /// be unpacked and configured once the groups before it are, and a larger group is a dependency cycle. Within
+    /// a group, and among groups which are ready to install at the same time, candidates keep their order in plan.
+    pub fn install_order(&self, plan: &[usize]) -> Vec<Vec<usize>> {
+        let mut graph: Graph<usize, RelationKind> = Graph::new();
+        let nodes = plan.iter().map(|c| graph.add_node(*c)).collect::<Vec<_>>();
+        let node_of: HashMap<i32, usize> = plan.iter().enumerate().map(|(i, c)| (self.get_candidate(*c).package_num, i)).collect();
+        for (i, &c) in plan.iter().enumerate() {
+            let arch = self.package_arch(self.get_candidate(c).package_num);
+            for (kind, dep) in self.followed_relations(Some(c), &RelationPolicy::default()) {
+                for alternative in dep {
+                    for q in self.alternative_packages(alternative) {
+                        if let Some(&j) = node_of.get(&q) {
+                            if i != j && self.satisfies(alternative, Some(arch), &self.available_version(plan[j])) {
+                                graph.update_edge(nodes[i], nodes[j], kind);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        // schedule the components, dependencies first, taking the one with the earliest member whenever there is a choice
+        let sccs = tarjan_scc(&graph).into_iter().map(|mut scc| { scc.sort(); scc }).collect::<Vec<_>>();
+        let mut component = vec![0; plan.len()];
+        for (k, scc) in sccs.iter().enumerate() {
+            for n in scc {
+                component[n.index()] = k;
+            }
+        }
+        let mut waiting_on = vec![0; sccs.len()]; // dependencies on other components not yet scheduled
+        let mut dependers: Vec<Vec<usize>> = vec![vec![]; sccs.len()];
+        for edge in graph.raw_edges() {
+            let (from, to) = (component[edge.source().index()], component[edge.target().index()]);
+            if from != to && !dependers[to].contains(&from) {
+                dependers[to].push(from);
+                waiting_on[from] += 1;
+            }
+        }
+        let mut ready: BinaryHeap<Reverse<(usize, usize)>> = (0..sccs.len())
+            .filter(|k| waiting_on[*k] == 0)
+            .map(|k| Reverse((sccs[k][0].index(), k)))
+            .collect();
+        let mut result = vec![];
+        while let Some(Reverse((_, k))) = ready.pop() {
+            result.push(sccs[k].iter().map(|n| graph[*n]).collect());
+            for &d in &dependers[k] {
+                waiting_on[d] -= 1;
+                if waiting_on[d] == 0 {
+                    ready.push(Reverse((sccs[d][0].index(), d)));
+                }
+            }
+        }
+        result
+    }
+
+    /// Formats the groups of install_order as "a, b, {c, d}, e", with cycles in braces.
+    pub fn install_order2str(&self, groups: &[Vec<usize>]) -> String {
+        groups.iter().map(|group| {
+            let names = group.iter().map(|c| self.get_package_name(self.get_candidate(*c).package_num)).collect::<Vec<_>>();
+            if names.len() == 1 { names[0].to_string() } else { format!("{{{}}}", names.join(", ")) }
+        }).collect::<Vec<_>>().join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Packages;
+
+    const PACKAGES: &str = "\
+Package: a
+Version: 1
+Depends: b
+
+Package: b
+Version: 1
+Depends: c
+
+Package: c
+Version: 1
+
+Package: app
+Version: 1
+Depends: lib
+Pre-Depends: tool
+
+Package: tool
+Version: 1
+
+Package: lib
+Version: 1
+Depends: tool
+
+Package: x
+Version: 1
+Depends: y, base
+
+Package: y
+Version: 1
+Depends: x
+
+Package: base
+Version: 1
+
+Package: z
+Version: 1
+";
+
+    // the install order of the named packages' candidates, planned in the order given
+    fn order(packages: &Packages, plan: &[&str]) -> String {
+        let plan = plan.iter().map(|name| packages.best_candidate(*packages.get_package_num(name)).unwrap()).collect::<Vec<_>>();
+        packages.install_order2str(&packages.install_order(&plan))
+    }
+
+    #[test]
+    fn installs_dependencies_first() {
+        let packages = Packages::from_text(PACKAGES, "");
+        assert_eq!(order(&packages, &["a", "b", "c"]), "c, b, a");
+        assert_eq!(order(&packages, &["c", "a", "b"]), "c, b, a");
+    }
+
+    #[test]
+    fn installs_pre_dependencies_first() {
+        let packages = Packages::from_text(PACKAGES, "");
+        assert_eq!(order(&packages, &["app", "tool"]), "tool, app");
+        assert_eq!(order(&packages, &["app", "lib", "tool"]), "tool, lib, app");
+    }
+
+    #[test]
+    fn groups_cycles_and_keeps_the_plan_order() {
+        let packages = Packages::from_text(PACKAGES, "");
+        assert_eq!(order(&packages, &["z", "y", "x", "base"]), "z, base, {y, x}");
+        assert_eq!(order(&packages, &["x", "y", "base", "z"]), "base, {x, y}, z");
+        // packages ready at the same time keep their order, before and after a cycle
+        assert_eq!(order(&packages, &["x", "c", "y", "base", "z"]), "c, base, {x, y}, z");
+    }
+}
@@ -0,0 +1,350 @@
+// Loading a whole Packages index (sid's is over 60000 stanzas) quickly.
+//
+// The file is memory-mapped rather than read, and cut at blank lines into a few chunks per thread,
+// which rayon parses in parallel into ParsedStanzas whose strings point into the map. Only then are
+// the chunks merged into the index, in file order and on one thread, since that needs &mut self:
+// each name is looked up (or added) once per mention, with no copy made to parse it. Each chunk
+// counts its lines, so that messages can be numbered by their line in the whole file afterwards.
+//
+// bench-load times this against read_packages_regex, the loader rpkg used before: it matches a regex
+// against each line, copies each field into a String, and looks each name up as it is met. It is
+// kept only for comparison, and (like it always did) ignores continuation lines.
+
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
+use memmap2::Mmap;
+use rayon::prelude::*;
+use regex::Regex;
+
+use crate::packages::decompress::{self, Compression};
+use crate::packages::dep_expr;
+use crate::packages::parsers::{parse_stanza, ParsedStanza};
+use crate::packages::{Candidate, MultiArch, DEFAULT_PRIORITY};
+use crate::Packages;
+
+use rpkg::{deb822, debversion};
+
+const KEYVAL_REGEX: &str = r"^(?P<key>(\w|-)+): (?P<value>.+)";
+
+// a Packages stanza being read by read_packages_regex; the candidate's package number is only known once its
+// Architecture has been seen
+struct RegexStanza {
+    name : String,
+    cand : Candidate,
+    error : Option<(usize, String)>, // why (and at which line) the stanza can't be used, until a valid Version is seen
+}
+
+// the paragraphs of one chunk, parsed
+struct ParsedChunk<'a> {
+    stanzas : Vec<ParsedStanza<'a>>,
+    messages : Vec<(usize, String)>, // numbered by line within the chunk, from 1
+    lines : usize, // newlines in the chunk
+    truncated : bool, // the chunk isn't valid UTF-8, so it and the rest of the file aren't loaded past its last good paragraph
+}
+
+/// Cuts text into about n pieces, each ending just after a blank line (or at the end of text), so that no paragraph
+/// is split between two pieces.
+pub fn paragraph_chunks(text: &[u8], n: usize) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut start = 0;
+    for i in 1..=n {
+        if start == text.len() {
+            break;
+        }
+        let target = (text.len() * i / n).max(start);
+        let end = match text[target..].windows(2).position(|w| w == b"\n\n") {
+            Some(pos) if i < n => target + pos + 2,
+            _ => text.len()
+        };
+        chunks.push(&text[start..end]);
+        start = end;
+    }
+    chunks
+}
+
+fn parse_chunk(chunk: &[u8]) -> ParsedChunk<'_> {
+    let lines = chunk.iter().filter(|b| **b == b'\n').count();
+    let mut parsed = ParsedChunk { stanzas: vec![], messages: vec![], lines, truncated: false };
+    let text = match std::str::from_utf8(chunk) {
+        Ok(text) => text,
+        Err(e) => {
+            // as when streaming, loading stops at the paragraph with the invalid line
+            let bad_line = 1 + chunk[..e.valid_up_to()].iter().filter(|b| **b == b'\n').count();
+            parsed.messages.push((bad_line, String::from("skipping paragraph: stream did not contain valid UTF-8")));
+            parsed.truncated = true;
+            let end = chunk[..e.valid_up_to()].windows(2).rposition(|w| w == b"\n\n").map_or(0, |pos| pos + 2);
+            std::str::from_utf8(&chunk[..end]).unwrap()
+        }
+    };
+    for paragraph in deb822::text_paragraphs(text, 1) {
+        match paragraph {
+            Err(e) => parsed.messages.push((e.line, format!("skipping paragraph: {}", e.error))),
+            Ok(paragraph) => {
+                if let Some(stanza) = parse_stanza(&paragraph, &mut parsed.messages) {
+                    parsed.stanzas.push(stanza);
+                }
+            }
+        }
+    }
+    parsed
+}
+
+// fastest of runs runs of f
+fn best_time(runs: usize, mut f: impl FnMut()) -> Duration {
+    (0..runs).map(|_| {
+        let start = Instant::now();
+        f();
+        start.elapsed()
+    }).min().unwrap_or_default()
+}
+
+impl Packages {
+    /// Loads a Packages file a line at a time, matching each against a regex, as rpkg did before it had a deb822
+    /// parser; only bench_load uses it. Continuation lines are ignored, so a folded relation field only keeps its
+    /// first line.
+    pub fn read_packages_regex(&mut self, filename: &str, source: usize) -> io::Result<Vec<String>> {
+        let kv_regexp = Regex::new(KEYVAL_REGEX).unwrap();
+        let mut messages = vec![];
+        let mut current: Option<RegexStanza> = None;
+        for (lineno, ip) in decompress::open(filename)?.lines().enumerate() {
+            let (lineno, ip) = (lineno + 1, ip?);
+            let caps = match kv_regexp.captures(&ip) {
+                Some(caps) => caps,
+                None => {
+                    // a blank line ends the stanza
+                    if ip.trim().is_empty() {
+                        self.insert_regex_stanza(filename, current.take(), &mut messages);
+                    }
+                    continue;
+                }
+            };
+            let (key, value) = (caps.name("key").unwrap().as_str(), caps.name("value").unwrap().as_str());
+            if key == "Package" {
+                self.insert_regex_stanza(filename, current.take(), &mut messages);
+                let mut cand = Candidate::new(0, debversion::DebianVersionNum::default());
+                cand.sources.push(source);
+                current = Some(RegexStanza {
+                    name: String::from(value.trim()), cand, error: Some((lineno, String::from("no Version field")))
+                });
+                continue;
+            }
+            let relations = match key {
+                "Pre-Depends" | "Depends" | "Recommends" | "Suggests" |
+                "Provides" | "Conflicts" | "Breaks" | "Replaces" => match dep_expr::parse_field(value) {
+                    Ok(relations) => self.intern_relations(relations),
+                    Err(e) => {
+                        let name = current.as_ref().map_or("", |stanza| stanza.name.as_str());
+                        messages.push(format!("{}:{}: ignoring {} field of {}: {}", filename, lineno, key, name, e));
+                        vec![]
+                    }
+                },
+                _ => vec![]
+            };
+            let (cand, error) = match current.as_mut() {
+                None => continue,
+                Some(RegexStanza { cand, error, .. }) => (cand, error)
+            };
+            match key {
+                "Version" => match value.trim().parse::<debversion::DebianVersionNum>() {
+                    Ok(version) => { cand.version = version; *error = None; }
+                    Err(e) => *error = Some((lineno, e.to_string()))
+                },
+                "Architecture" => cand.architecture = String::from(value.trim()),
+                "Multi-Arch" => cand.multi_arch = MultiArch::from_field(value),
+                "MD5sum" => cand.md5sum = Some(String::from(value)),
+                "Size" => cand.size = value.trim().parse::<u64>().ok(),
+                "Installed-Size" => cand.installed_size = value.trim().parse::<u64>().ok(),
+                "Pre-Depends" => cand.pre_dependencies = relations,
+                "Depends" => cand.dependencies = relations,
+                "Recommends" => cand.recommends = relations,
+                "Suggests" => cand.suggests = relations,
+                "Provides" => cand.provides = relations.into_iter().flatten().collect_vec(),
+                "Conflicts" => cand.conflicts = relations.into_iter().flatten().collect_vec(),
+                "Breaks" => cand.breaks = relations.into_iter().flatten().collect_vec(),
+                "Replaces" => cand.replaces = relations.into_iter().flatten().collect_vec(),
+                _ => ()
+            }
+        }
+        self.insert_regex_stanza(filename, current.take(), &mut messages);
+        Ok(messages)
+    }
+
+    fn insert_regex_stanza(&mut self, filename: &str, stanza: Option<RegexStanza>, messages: &mut Vec<String>) {
+        if let Some(RegexStanza { name, mut cand, error }) = stanza {
+            if let Some((lineno, e)) = error {
+                messages.push(format!("{}:{}: skipping {}: {}", filename, lineno, name, e));
+                return;
+            }
+            cand.package_num = self.get_package_num_for_arch_inserting(&name, &cand.architecture);
+            self.insert_candidate(cand);
+        }
+    }
+
+    /// Loads a Packages file as read_packages_streaming does, into the same index and with the same messages, but
+    /// memory-mapped and parsed in parallel. A compressed file can't be mapped, so it is streamed instead.
+    pub fn read_packages_parallel(&mut self, filename: &str, source: usize) -> io::Result<Vec<String>> {
+        self.read_packages_in_chunks(filename, source, rayon::current_num_threads() * 4)
+    }
+
+    // read_packages_parallel, cutting the file into about num_chunks chunks
+    fn read_packages_in_chunks(&mut self, filename: &str, source: usize, num_chunks: usize) -> io::Result<Vec<String>> {
+        if decompress::compression_of(filename)? != Compression::None {
+            return self.read_packages_streaming(filename, source);
+        }
+        let file = File::open(filename)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(vec![]);
+        }
+        // safe as long as nothing truncates the file while it is being loaded
+        let map = unsafe { Mmap::map(&file)? };
+        let chunks = paragraph_chunks(&map, num_chunks);
+        let parsed: Vec<ParsedChunk> = chunks.par_iter().map(|chunk| parse_chunk(chunk)).collect();
+        let mut messages = vec![];
+        let mut first_line = 1;
+        self.candidates.reserve(parsed.iter().map(|chunk| chunk.stanzas.len()).sum());
+        for chunk in parsed {
+            messages.extend(chunk.messages.iter().map(|(line, message)| format!("{}:{}: {}", filename, first_line + line - 1, message)));
+            for stanza in chunk.stanzas {
+                self.insert_parsed_stanza(stanza, source);
+            }
+            if chunk.truncated {
+                break;
+            }
+            first_line += chunk.lines;
+        }
+        Ok(messages)
+    }
+
+    /// Times loading filename into an empty index with read_packages_regex and with read_packages_parallel, taking the
+    /// best of runs tries each, and checks that both load the same candidates.
+    pub fn bench_load(filename: &str, runs: usize) {
+        let mut regex = Packages::new();
+        let mut parallel = Packages::new();
+        let (mut regex_result, mut parallel_result) = (Ok(vec![]), Ok(vec![]));
+        let regex_time = best_time(runs, || {
+            regex = Packages::new();
+            let source = regex.add_source(filename, DEFAULT_PRIORITY);
+            regex_result = regex.read_packages_regex(filename, source);
+        });
+        if let Err(e) = regex_result {
+            println!("can't read {}: {}", filename, e);
+            return;
+        }
+        let parallel_time = best_time(runs, || {
+            parallel = Packages::new();
+            let source = parallel.add_source(filename, DEFAULT_PRIORITY);
+            parallel_result = parallel.read_packages_parallel(filename, source);
+        });
+        let messages = parallel_result.unwrap_or_default();
+        println!("{}: {} candidates, {} problems", filename, parallel.candidates.len(), messages.len());
+        println!("regex loader: {:.1} ms", regex_time.as_secs_f64() * 1000.0);
+        println!("parallel loader: {:.1} ms ({} threads)", parallel_time.as_secs_f64() * 1000.0, rayon::current_num_threads());
+        println!("speedup: {:.1}x (best of {})", regex_time.as_secs_f64() / parallel_time.as_secs_f64(), runs);
+        let same = regex.candidates.len() == parallel.candidates.len()
+            && regex.candidates.iter().zip(&parallel.candidates).all(|(a, b)|
+                regex.get_package_name(a.package_num) == parallel.get_package_name(b.package_num) && a.version == b.version
+                && regex.deps2str(&a.dependencies) == parallel.deps2str(&b.dependencies));
+        if !same {
+            println!("warning: the loaders disagree about the contents of {}", filename);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use itertools::Itertools;
+
+    use super::paragraph_chunks;
+    use crate::packages::DEFAULT_PRIORITY;
+    use crate::Packages;
+
+    // a Packages file of n stanzas with folded fields, runs of blank lines and a few stanzas that can't be loaded,
+    // ending without a newline
+    fn packages_text(n: usize) -> String {
+        let mut text = String::new();
+        for i in 0..n {
+            text.push_str(&match i % 7 {
+                0 => format!("Package: lib{0}\nVersion: 1.{0}-1\nArchitecture: amd64\nMulti-Arch: same\nDepends: libc6 (>= 2.36),\n libgcc-s1\nDescription: library {0}\n more about it\n .\n and more\n", i),
+                1 => format!("Package: lib{0}\nVersion: 1.{0}-1\nArchitecture: i386\nMulti-Arch: same\nDepends: libc6:i386\nSize: {0}\nMD5sum: 0123456789abcdef\n", i - 1),
+                2 => format!("Package: tool{0}\nVersion: 2:{0}.0\nArchitecture: all\nProvides: tool (= {0})\nConflicts: tool-legacy\nBreaks: lib{1} (<< 1.{1}-1)\nRecommends: lib{1} | tool0\n", i, i - 2),
+                3 => format!("Package: broken{0}\nVersion: not a version\n", i),
+                4 => format!("Package: odd{0}\nVersion: 1\nDepends: libc6 (>= \nInstalled-Size: {0}\n\n", i),
+                5 => String::from("Version: 3\nArchitecture: amd64\n"),
+                _ => format!("Package: app{0}\nVersion: {0}\nArchitecture: amd64\nPre-Depends: dpkg (>= 1.15)\nDepends: tool{1},\n  lib{2} [amd64] <!nocheck>\n", i, i - 4, i - 6),
+            });
+            text.push('\n');
+        }
+        text.pop();
+        text
+    }
+
+    // what an index holds, by package name rather than by number, since numbers are handed out by a counter that
+    // all indexes share
+    fn contents(packages: &Packages) -> Vec<String> {
+        let mut lines = packages.package_name_to_num.keys().sorted().cloned().collect_vec();
+        for cand in &packages.candidates {
+            lines.push(format!("{} {} {} {:?} {:?} {:?} {:?} {:?}", packages.get_package_name(cand.package_num), cand.version,
+                cand.architecture, cand.multi_arch, cand.md5sum, cand.size, cand.installed_size, cand.sources));
+            for deps in [&cand.pre_dependencies, &cand.dependencies, &cand.recommends, &cand.suggests] {
+                lines.push(packages.deps2str(deps));
+            }
+            for rels in [&cand.provides, &cand.conflicts, &cand.breaks, &cand.replaces] {
+                lines.push(packages.rels2str(rels));
+            }
+        }
+        for (field, map) in [("available", &packages.available), ("providers", &packages.providers),
+                ("conflicts_on", &packages.conflicts_on), ("depended_on", &packages.depended_on)] {
+            lines.extend(map.iter().map(|(num, cands)| format!("{} {}: {:?}", field, packages.get_package_name(*num), cands)).sorted());
+        }
+        lines.extend(packages.arch_variants.iter().map(|(num, variants)| format!("arch_variants {}: {}",
+            packages.get_package_name(*num), variants.iter().map(|v| packages.get_package_name(*v)).format(", "))).sorted());
+        lines
+    }
+
+    #[test]
+    fn paragraph_chunks_cut_only_at_blank_lines() {
+        let text = packages_text(30);
+        for n in 1..=100 {
+            let chunks = paragraph_chunks(text.as_bytes(), n);
+            assert!(chunks.len() <= n);
+            assert_eq!(chunks.concat(), text.as_bytes());
+            for chunk in &chunks[..chunks.len() - 1] {
+                assert!(chunk.ends_with(b"\n\n"), "{} chunks: {:?}", n, std::str::from_utf8(chunk));
+            }
+        }
+    }
+
+    #[test]
+    fn loads_the_same_index_as_streaming() {
+        let dir = std::env::temp_dir().join(format!("rpkg-parallel-load-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let filename = String::from(dir.join("Packages").to_str().unwrap());
+        fs::write(&filename, packages_text(60)).unwrap();
+
+        let load = |num_chunks: Option<usize>| {
+            let mut packages = Packages::new();
+            let source = packages.add_source(&filename, DEFAULT_PRIORITY);
+            let messages = match num_chunks {
+                None => packages.read_packages_streaming(&filename, source),
+                Some(n) => packages.read_packages_in_chunks(&filename, source, n),
+            };
+            (packages, messages.unwrap())
+        };
+        let (streaming, streaming_messages) = load(None);
+        assert_eq!(streaming.candidates.len(), (0..60).filter(|i| i % 7 != 3 && i % 7 != 5).count());
+        assert_eq!(streaming_messages.len(), 60 / 7 * 3 + 1);
+        // with many chunks, most stanzas straddle the offset some chunk was aimed to end at
+        for num_chunks in [1, 2, 3, 7, 16, 59, 200] {
+            let (parallel, messages) = load(Some(num_chunks));
+            assert_eq!(messages, streaming_messages, "{} chunks", num_chunks);
+            assert_eq!(contents(&parallel), contents(&streaming), "{} chunks", num_chunks);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
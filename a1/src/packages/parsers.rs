@@ -1,45 +1,144 @@
-use std::fs::File;
 use std::io::{self, BufRead};
-use std::path::Path;
 
-use regex::Regex;
+use itertools::Itertools;
 
-use crate::packages::RelVersionedPackageNum;
+use crate::packages::{Candidate, Dependency, MultiArch, RelVersionedPackageNum, DEFAULT_PRIORITY};
+use crate::packages::decompress;
+use crate::packages::dep_expr::{self, RelationExpr};
+use crate::packages::pinning::Pin;
 use crate::Packages;
 
+use rpkg::deb822::{self, BorrowedParagraph, Paragraph, Paragraphs};
 use rpkg::debversion;
 
-const KEYVAL_REGEX: &str = r"^(?P<key>(\w|-)+): (?P<value>.+)";
-const PKGNAME_AND_VERSION_REGEX: &str =
-    r"(?P<pkg>(\w|\.|\+|-)+)( \((?P<op>(<|=|>)(<|=|>)?) (?P<ver>.*)\))?";
+// the fields of a dpkg status stanza that rpkg keeps
+struct InstalledStanza {
+    name : String,
+    arch : String,
+    version : Option<debversion::DebianVersionNum>,
+    present : bool, // false if Status says the package is not (or no longer) installed
+    essential : bool,
+    installed_size : Option<u64>,
+    multi_arch : MultiArch,
+    relations : Vec<Vec<Vec<RelationExpr>>>, // one per DEPENDENCY_FIELDS entry
+}
+
+// Whether a dpkg Status field ("install ok installed") describes a package whose files are on the system. Removed
+// packages whose configuration files remain (config-files) and not-installed ones are not; half-installed or
+// unconfigured packages are.
+fn status_is_present(status: &str) -> bool {
+    match status.split_whitespace().nth(2) {
+        None => true,
+        Some(state) => state != "not-installed" && state != "config-files"
+    }
+}
+
+// the Package field of a paragraph, or the line and reason it hasn't a usable one
+fn stanza_name<'a>(paragraph: &BorrowedParagraph<'a>) -> Result<&'a str, (usize, String)> {
+    match paragraph.field("Package") {
+        None => Err((paragraph.line, String::from("skipping paragraph: no Package field"))),
+        Some(field) if field.value.is_empty() || field.value.contains(char::is_whitespace) =>
+            Err((field.line, format!("skipping paragraph: bad package name {:?}", field.value))),
+        Some(field) => Ok(field.value)
+    }
+}
+
+// the Package field of a paragraph, or None (with a message) if it hasn't a usable one
+fn paragraph_name<'a>(filename: &str, paragraph: &'a Paragraph) -> Option<&'a str> {
+    match stanza_name(&paragraph.borrow()) {
+        Ok(name) => Some(name),
+        Err((lineno, e)) => {
+            println!("{}:{}: {}", filename, lineno, e);
+            None
+        }
+    }
+}
+
+/// The relation fields of a Packages stanza, in the order ParsedStanza::relations keeps them.
+pub const RELATION_FIELDS: [&str; 8] = ["Pre-Depends", "Depends", "Recommends", "Suggests", "Provides", "Conflicts", "Breaks", "Replaces"];
+
+// the relation fields of a status stanza that rpkg keeps, in the order InstalledStanza::relations keeps them
+const DEPENDENCY_FIELDS: [&str; 4] = ["Pre-Depends", "Depends", "Recommends", "Suggests"];
+
+/// A Packages stanza with its fields parsed but its package names not yet looked up, so that it can be built without
+/// access to the index (and so in parallel with others). Strings are borrowed from the paragraph.
+pub struct ParsedStanza<'a> {
+    pub name : &'a str,
+    pub architecture : &'a str,
+    pub version : debversion::DebianVersionNum,
+    pub multi_arch : MultiArch,
+    pub md5sum : Option<&'a str>,
+    pub size : Option<u64>,
+    pub installed_size : Option<u64>,
+    pub relations : Vec<Vec<Vec<RelationExpr>>>, // one per RELATION_FIELDS entry
+}
+
+/// Parses the fields of a Packages stanza, adding a (line, message) pair to messages for each problem: None is
+/// returned for a stanza without a usable Package field or a valid Version, and a relation field that can't be parsed
+/// is left empty.
+pub fn parse_stanza<'a>(paragraph: &BorrowedParagraph<'a>, messages: &mut Vec<(usize, String)>) -> Option<ParsedStanza<'a>> {
+    let name = match stanza_name(paragraph) {
+        Ok(name) => name,
+        Err(message) => { messages.push(message); return None; }
+    };
+    let version = match paragraph.field("Version").map(|v| (v.line, v.value.parse::<debversion::DebianVersionNum>())) {
+        None => {
+            messages.push((paragraph.line, format!("skipping {}: no Version field", name)));
+            return None;
+        }
+        Some((lineno, Err(e))) => {
+            messages.push((lineno, format!("skipping {}: {}", name, e)));
+            return None;
+        }
+        Some((_, Ok(version))) => version
+    };
+    let relations = RELATION_FIELDS.iter().map(|key| relation_field(paragraph, key, name, messages)).collect_vec();
+    Some(ParsedStanza {
+        name,
+        architecture: paragraph.get("Architecture").unwrap_or(""),
+        version,
+        multi_arch: MultiArch::from_field(paragraph.get("Multi-Arch").unwrap_or("")),
+        md5sum: paragraph.get("MD5sum"),
+        size: paragraph.get("Size").and_then(|s| s.parse::<u64>().ok()),
+        installed_size: paragraph.get("Installed-Size").and_then(|s| s.parse::<u64>().ok()),
+        relations,
+    })
+}
+
+// the relation field key of the stanza of package name, parsed; one that can't be parsed is reported in messages and
+// left empty
+fn relation_field<'a>(paragraph: &BorrowedParagraph<'a>, key: &str, name: &str, messages: &mut Vec<(usize, String)>) -> Vec<Vec<RelationExpr>> {
+    match paragraph.field(key) {
+        None => vec![],
+        Some(field) => match dep_expr::parse_field(field.value) {
+            Ok(relations) => relations,
+            Err(_) => {
+                // report the error against the field as it would read on one line
+                let e = dep_expr::parse_field(&field.folded()).err().unwrap_or_default();
+                messages.push((field.line, format!("ignoring {} field of {}: {}", key, name, e)));
+                vec![]
+            }
+        }
+    }
+}
 
 impl Packages {
-    /// Loads packages and version numbers from a file, calling get_package_num_inserting on the package name
-    /// and inserting the appropriate value into the installed_debvers map with the parsed version number.
+    /// Loads packages and version numbers from a file, calling get_package_num_for_arch_inserting on the package name
+    /// and architecture, and inserting the appropriate value into the installed_debvers map with the parsed version number.
+    /// Stanzas whose Status field says the package isn't installed (e.g. "deinstall ok config-files") are skipped;
+    /// Essential: yes packages are remembered, since they are never autoremoved. The Pre-Depends, Depends, Recommends
+    /// and Suggests fields are kept too, for installed packages which no Packages file lists. Stanzas that are
+    /// malformed, or that have no Package field or an invalid Version, are reported with their file and line and skipped.
     pub fn parse_installed(&mut self, filename: &str) {
-        let kv_regexp = Regex::new(KEYVAL_REGEX).unwrap();
-        if let Ok(lines) = read_lines(filename) {
-            let mut current_package_num: i32 = 0;
-            for line in lines {
-                if let Ok(ip) = line {
-                    match kv_regexp.captures(&ip) {
-                        None => (),
-                        Some(caps) => {
-                            let (key, value) = (
-                                caps.name("key").unwrap().as_str(),
-                                caps.name("value").unwrap().as_str(),
-                            );
-                            if key == "Package" {
-                                current_package_num = self.get_package_num_inserting(&value);
-                            }
-                            // println!("package #:{}", current_package_num);
-                            if key == "Version" {
-                                let debver = value
-                                    .trim()
-                                    .parse::<debversion::DebianVersionNum>()
-                                    .unwrap();
-                                self.installed_debvers.insert(current_package_num, debver);
-                            }
+        match read_paragraphs(filename) {
+            Err(e) => println!("can't read {}: {}", filename, e),
+            Ok(paragraphs) => {
+                for paragraph in paragraphs {
+                    match paragraph {
+                        Err(e) => println!("{}:{}: skipping paragraph: {}", filename, e.line, e.error),
+                        Ok(paragraph) => {
+                            let stanza = installed_stanza(filename, &paragraph);
+                            self.insert_installed(stanza);
                         }
                     }
                 }
@@ -51,89 +150,241 @@ impl Packages {
         );
     }
 
-    /// Loads packages, version numbers, dependencies, and md5sums from a file, calling get_package_num_inserting on the package name
-    /// and inserting the appropriate values into the dependencies, md5sum, and available_debvers maps.
-    pub fn parse_packages(&mut self, filename: &str) {
-        let kv_regexp = Regex::new(KEYVAL_REGEX).unwrap();
-        let pkgver_regexp = Regex::new(PKGNAME_AND_VERSION_REGEX).unwrap();
-
-        if let Ok(lines) = read_lines(filename) {
-            let mut current_package_num = 0;
-            for line in lines {
-                if let Ok(ip) = line {
-                    match kv_regexp.captures(&ip) {
-                        None => (),
-                        Some(caps) => {
-                            let (key, value) = (
-                                caps.name("key").unwrap().as_str(),
-                                caps.name("value").unwrap().as_str(),
-                            );
-                            if key == "Package" {
-                                current_package_num = self.get_package_num_inserting(&value);
-                            }
-                            if key == "Version" {
-                                let debver = value
-                                    .trim()
-                                    .parse::<debversion::DebianVersionNum>()
-                                    .unwrap();
-                                self.available_debvers.insert(current_package_num, debver);
-                            }
-                            if key == "MD5sum" {
-                                self.md5sums
-                                    .insert(current_package_num, String::from(value));
-                            }
-                            if key == "Depends" {
-                                let mut dependencies = vec![];
-                                for dependency in value.split(",") {
-                                    let mut alternatives = vec![];
-                                    for alternative in dependency.split("|") {
-                                        match pkgver_regexp.captures(alternative) {
-                                            None => {}
-                                            Some(cap) => {
-                                                let package = cap.name("pkg").unwrap().as_str();
-                                                let package_num = self.get_package_num_inserting(package);
-                                                let mut rel_version = Option::None;
-
-                                                match cap.name("op") {
-                                                    None => {}
-                                                    Some(option) => {
-                                                        let op: debversion::VersionRelation = option
-                                                            .as_str()
-                                                            .parse::<debversion::VersionRelation>()
-                                                            .unwrap();
-                                                        let ver: String = cap
-                                                            .name("ver")
-                                                            .unwrap()
-                                                            .as_str()
-                                                            .to_string();
-                                                        rel_version = Option::Some((op, ver));
-                                                    }
-                                                }
-                                                alternatives.push(RelVersionedPackageNum {package_num, rel_version});
-                                            }
-                                        }
-                                    }
-                                    dependencies.push(alternatives);
-                                }
-                                self.dependencies.insert(current_package_num, dependencies);
-                            }
-                        }
-                    }
+    /// Loads packages, version numbers, dependencies, and md5sums from a file, calling get_package_num_for_arch_inserting on the
+    /// package name and architecture and adding one candidate per stanza to the index, so that several versions (or architectures) of a package can coexist.
+    /// Each candidate records its own Pre-Depends, Depends, Recommends and Suggests fields, and its Provides, Conflicts,
+    /// Breaks and Replaces relations, and notes that it was listed by source. Relation fields may be folded over
+    /// several lines.
+    /// Problems are reported with the file and line they were found at: a malformed stanza, or one without a Package
+    /// field or a valid Version, is skipped, and a relation field that can't be parsed is ignored.
+    /// The file is memory-mapped and parsed in parallel (see parallel_load.rs); a compressed one (gzip, xz or zstd,
+    /// told by its first bytes) is decompressed as it is read instead, and parsed a paragraph at a time.
+    pub fn parse_packages(&mut self, filename: &str, source: usize) {
+        match self.read_packages_parallel(filename, source) {
+            Err(e) => println!("can't read {}: {}", filename, e),
+            Ok(messages) => {
+                for message in messages {
+                    println!("{}", message);
                 }
             }
         }
         println!(
             "Packages available: {}",
-            self.available_debvers.keys().len()
+            self.available.keys().len()
         );
     }
+
+    /// Loads a Packages file as parse_packages does, but a paragraph at a time on one thread, and returns the problems
+    /// found instead of printing them. A read error ends loading, keeping what was read.
+    pub fn read_packages_streaming(&mut self, filename: &str, source: usize) -> io::Result<Vec<String>> {
+        let mut messages = vec![];
+        for paragraph in read_paragraphs(filename)? {
+            match paragraph {
+                Err(e) => messages.push(format!("{}:{}: skipping paragraph: {}", filename, e.line, e.error)),
+                Ok(paragraph) => {
+                    let mut problems = vec![];
+                    if let Some(stanza) = parse_stanza(&paragraph.borrow(), &mut problems) {
+                        self.insert_parsed_stanza(stanza, source);
+                    }
+                    messages.extend(problems.into_iter().map(|(lineno, problem)| format!("{}:{}: {}", filename, lineno, problem)));
+                }
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Adds a parsed stanza listed by source to the index, looking up (or adding) the names it mentions.
+    pub fn insert_parsed_stanza(&mut self, stanza: ParsedStanza, source: usize) {
+        let mut cand = Candidate::new(self.get_package_num_for_arch_inserting(stanza.name, stanza.architecture), stanza.version);
+        cand.sources.push(source);
+        cand.architecture = String::from(stanza.architecture);
+        cand.multi_arch = stanza.multi_arch;
+        cand.md5sum = stanza.md5sum.map(String::from);
+        cand.size = stanza.size;
+        cand.installed_size = stanza.installed_size;
+        let mut relations = stanza.relations.into_iter().map(|field| self.intern_relations(field));
+        cand.pre_dependencies = relations.next().unwrap();
+        cand.dependencies = relations.next().unwrap();
+        cand.recommends = relations.next().unwrap();
+        cand.suggests = relations.next().unwrap();
+        cand.provides = relations.next().unwrap().into_iter().flatten().collect_vec();
+        cand.conflicts = relations.next().unwrap().into_iter().flatten().collect_vec();
+        cand.breaks = relations.next().unwrap().into_iter().flatten().collect_vec();
+        cand.replaces = relations.next().unwrap().into_iter().flatten().collect_vec();
+        self.insert_candidate(cand);
+    }
+
+    fn insert_installed(&mut self, stanza: Option<InstalledStanza>) {
+        if let Some(InstalledStanza { name, arch, version: Some(version), present: true, essential, installed_size, multi_arch, relations }) = stanza {
+            let package_num = self.get_package_num_for_arch_inserting(&name, &arch);
+            let mut cand = Candidate::new(package_num, version.clone());
+            cand.architecture = arch;
+            cand.multi_arch = multi_arch;
+            cand.installed_size = installed_size;
+            let mut relations = relations.into_iter().map(|field| self.intern_relations(field));
+            cand.pre_dependencies = relations.next().unwrap();
+            cand.dependencies = relations.next().unwrap();
+            cand.recommends = relations.next().unwrap();
+            cand.suggests = relations.next().unwrap();
+            self.insert_installed_relations(cand);
+            self.installed_debvers.insert(package_num, version);
+            if let Some(kib) = installed_size {
+                self.installed_sizes.insert(package_num, kib);
+            }
+            if essential {
+                self.essential.insert(package_num);
+            }
+        }
+    }
+
+    /// Loads apt's extended_states file, which marks packages that were installed only to satisfy dependencies
+    /// (Auto-Installed: 1). Its stanzas have Package, Architecture and Auto-Installed fields.
+    pub fn parse_extended_states(&mut self, filename: &str) {
+        let paragraphs = match read_paragraphs(filename) {
+            Err(e) => { println!("can't read {}: {}", filename, e); return; }
+            Ok(paragraphs) => paragraphs
+        };
+        for paragraph in paragraphs {
+            let paragraph = match paragraph {
+                Err(e) => { println!("{}:{}: skipping paragraph: {}", filename, e.line, e.error); continue; }
+                Ok(paragraph) => paragraph
+            };
+            if let Some(name) = paragraph_name(filename, &paragraph) {
+                let package_num = self.get_package_num_for_arch_inserting(name, paragraph.get("Architecture").unwrap_or(""));
+                if paragraph.get("Auto-Installed") == Some("1") {
+                    self.auto_installed.insert(package_num);
+                } else {
+                    self.auto_installed.remove(&package_num);
+                }
+            }
+        }
+        println!("Packages marked auto-installed: {}", self.auto_installed.len());
+    }
+
+    /// Loads each Packages file listed in a sources file. Each line reads <pkgfile-name> [<suite> [<priority>]];
+    /// the suite defaults to the file name and the priority to 500. Blank lines and lines starting with # are ignored.
+    pub fn parse_sources(&mut self, filename: &str) {
+        match read_lines(filename) {
+            Err(e) => println!("can't read {}: {}", filename, e),
+            Ok(lines) => {
+                for (lineno, ip) in lines.map_while(Result::ok).enumerate() {
+                    let fields = ip.split_whitespace().collect_vec();
+                    if fields.is_empty() || fields[0].starts_with('#') {
+                        continue;
+                    }
+                    let priority = match fields.get(2).map(|p| p.parse::<i32>()) {
+                        None => DEFAULT_PRIORITY,
+                        Some(Ok(p)) if fields.len() == 3 => p,
+                        _ => {
+                            println!("{}:{}: expected <pkgfile-name> [<suite> [<priority>]]", filename, lineno + 1);
+                            continue;
+                        }
+                    };
+                    let source = self.add_source(fields.get(1).unwrap_or(&fields[0]), priority);
+                    self.parse_packages(fields[0], source);
+                }
+            }
+        }
+    }
+
+    /// Loads pins from an apt_preferences(5)-style file: records of Package, Pin and Pin-Priority fields separated by
+    /// blank lines. Pins select versions by "version <glob>", "origin <origin>" or "release a=<suite>, o=<origin>".
+    /// Records that can't be understood are reported and skipped.
+    pub fn parse_preferences(&mut self, filename: &str) {
+        let paragraphs = match read_paragraphs(filename) {
+            Err(e) => { println!("can't read {}: {}", filename, e); return; }
+            Ok(paragraphs) => paragraphs
+        };
+        let mut num_pins = 0;
+        for paragraph in paragraphs {
+            let pin = match &paragraph {
+                Err(e) => Err(e.error.to_string()),
+                Ok(paragraph) => match (paragraph.get("Package"), paragraph.get("Pin"), paragraph.get("Pin-Priority")) {
+                    (Some(package), Some(pin), Some(priority)) => Pin::parse(package, pin, priority),
+                    _ => Err(String::from("needs Package, Pin and Pin-Priority"))
+                }
+            };
+            let start = match &paragraph { Err(e) => e.line, Ok(paragraph) => paragraph.line };
+            match pin {
+                Ok(pin) => { self.add_pin(pin); num_pins += 1; }
+                Err(e) => println!("{}:{}: ignoring record: {}", filename, start, e)
+            }
+        }
+        println!("Pins loaded: {}", num_pins);
+    }
+
+    /// Looks up (or adds) the package names of a relation field parsed by dep_expr::parse_field; fields which don't
+    /// allow alternatives (Provides, Conflicts, ...) simply yield singleton lists.
+    pub fn intern_relations(&mut self, field: Vec<Vec<RelationExpr>>) -> Vec<Dependency> {
+        field.into_iter().map(|dependency| dependency.into_iter().map(|rel| RelVersionedPackageNum {
+            package_num: self.get_package_num_inserting(&rel.name),
+            arch_qualifier: rel.arch_qualifier,
+            rel_version: rel.rel_version,
+            restrictions: rel.restrictions,
+        }).collect_vec()).collect_vec()
+    }
+}
+
+// the typed stanza for a paragraph of dpkg's status file, or None (with a message) if it can't be used
+fn installed_stanza(filename: &str, paragraph: &Paragraph) -> Option<InstalledStanza> {
+    let name = paragraph_name(filename, paragraph)?;
+    let version = match paragraph.field("Version").map(|v| (v.line, v.value.parse::<debversion::DebianVersionNum>())) {
+        None => None,
+        Some((_, Ok(version))) => Some(version),
+        Some((lineno, Err(e))) => {
+            println!("{}:{}: skipping {}: {}", filename, lineno, name, e);
+            return None;
+        }
+    };
+    let mut problems = vec![];
+    let relations = DEPENDENCY_FIELDS.iter().map(|key| relation_field(&paragraph.borrow(), key, name, &mut problems)).collect_vec();
+    for (lineno, problem) in problems {
+        println!("{}:{}: {}", filename, lineno, problem);
+    }
+    Some(InstalledStanza {
+        name: String::from(name),
+        arch: String::from(paragraph.get("Architecture").unwrap_or("")),
+        version,
+        present: paragraph.get("Status").is_none_or(status_is_present),
+        essential: paragraph.get("Essential") == Some("yes"),
+        installed_size: paragraph.get("Installed-Size").and_then(|s| s.parse::<u64>().ok()),
+        multi_arch: MultiArch::from_field(paragraph.get("Multi-Arch").unwrap_or("")),
+        relations,
+    })
+}
+
+// the paragraphs of filename, which may be compressed
+fn read_paragraphs(filename: &str) -> io::Result<Paragraphs<Box<dyn BufRead>>> {
+    return Ok(deb822::paragraphs(decompress::open(filename)?));
 }
 
 // standard template code downloaded from the Internet somewhere
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+fn read_lines(filename: &str) -> io::Result<io::Lines<Box<dyn BufRead>>> {
+    Ok(decompress::open(filename)?.lines())
+}
+
+#[cfg(test)]
+impl Packages {
+    // an index of the stanzas of packages (a Packages file), all listed by one source, with those of status installed
+    pub fn from_text(packages: &str, status: &str) -> Packages {
+        let mut index = Packages::new();
+        index.load_text(packages, status);
+        index
+    }
+
+    // loads the stanzas of packages, all listed by one new source, and installs those of status
+    pub fn load_text(&mut self, packages: &str, status: &str) {
+        let source = self.add_source("test", DEFAULT_PRIORITY);
+        for paragraph in deb822::paragraphs(packages.as_bytes()) {
+            let paragraph = paragraph.unwrap();
+            let mut problems = vec![];
+            let stanza = parse_stanza(&paragraph.borrow(), &mut problems).unwrap();
+            assert!(problems.is_empty(), "{:?}", problems);
+            self.insert_parsed_stanza(stanza, source);
+        }
+        for paragraph in deb822::paragraphs(status.as_bytes()) {
+            let stanza = installed_stanza("status", &paragraph.unwrap());
+            self.insert_installed(stanza);
+        }
+    }
 }
@@ -0,0 +1,293 @@
+// Sources (the Packages files candidates come from) and apt_preferences(5)-style pinning.
+//
+// Every candidate has a pin priority: that of the first matching pin (pins naming the package
+// come before pins for Package: *), or else the highest priority of the sources it was loaded
+// from. The installed version of a package has priority at least 100, as in apt.
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::packages::Candidate;
+use crate::Packages;
+
+/// The priority of a source loaded without an explicit one, as for an ordinary apt source.
+pub const DEFAULT_PRIORITY: i32 = 500;
+// the priority apt gives the dpkg status file, i.e. the installed version
+const INSTALLED_PRIORITY: i32 = 100;
+// from this on, apt will downgrade an installed package to reach the pinned version
+const DOWNGRADE_PRIORITY: i32 = 1000;
+
+/// A Packages file (or CSV file) that candidates were loaded from.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Source {
+    pub suite : String,
+    pub origin : String,
+    pub priority : i32,
+}
+
+enum PinTarget {
+    // (key, value) conditions from "Pin: release a=stable, o=Debian", all of which must hold
+    Release(Vec<(String, String)>),
+    Origin(String),
+    Version(String),
+}
+
+/// One record of a preferences file.
+pub struct Pin {
+    packages : Vec<String>,
+    target : PinTarget,
+    priority : i32,
+}
+
+impl Pin {
+    /// Builds a pin from the Package, Pin and Pin-Priority fields of a preferences record, or describes
+    /// why it can't.
+    pub fn parse(package: &str, pin: &str, priority: &str) -> Result<Pin, String> {
+        let priority = priority.trim().parse::<i32>().map_err(|_| format!("bad Pin-Priority {:?}", priority))?;
+        let packages = package.split_whitespace().map(String::from).collect_vec();
+        let (kind, arg) = pin.trim().split_once(' ').unwrap_or((pin.trim(), ""));
+        let arg = arg.trim();
+        let target = match kind {
+            "version" => PinTarget::Version(String::from(arg)),
+            "origin" => PinTarget::Origin(String::from(arg.trim_matches('"'))),
+            "release" => {
+                let mut conditions = vec![];
+                for cond in arg.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+                    let (key, value) = cond.split_once('=').unwrap_or(("a", cond));
+                    if !["a", "n", "o"].contains(&key) {
+                        return Err(format!("unsupported release condition {:?}", cond));
+                    }
+                    conditions.push((String::from(key), String::from(value)));
+                }
+                PinTarget::Release(conditions)
+            }
+            _ => return Err(format!("unsupported Pin {:?}", pin))
+        };
+        Ok(Pin { packages, target, priority })
+    }
+
+    fn is_generic(&self) -> bool {
+        self.packages.iter().all(|p| p == "*")
+    }
+
+    fn matches(&self, package_name: &str, cand: &Candidate, sources: &[Source]) -> bool {
+        if !self.packages.iter().any(|p| glob_match(p, package_name)) {
+            return false;
+        }
+        match &self.target {
+            PinTarget::Version(pattern) => glob_match(pattern, &cand.version.to_string()),
+            PinTarget::Origin(origin) => cand.sources.iter().any(|s| sources[*s].origin == *origin),
+            PinTarget::Release(conditions) => cand.sources.iter().any(|s| {
+                let source = &sources[*s];
+                conditions.iter().all(|(key, value)| match key.as_str() {
+                    "o" => source.origin == *value,
+                    _ => source.suite == *value
+                })
+            })
+        }
+    }
+}
+
+// shell-style matching, where * matches any string and ? any one character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star, matched)) = backtrack {
+            pi = star + 1;
+            ti = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+impl Packages {
+    /// Registers a source and returns its number; adding a suite that already exists just updates its priority.
+    pub fn add_source(&mut self, suite: &str, priority: i32) -> usize {
+        if let Some(idx) = self.sources.iter().position(|s| s.suite == suite) {
+            self.sources[idx].priority = priority;
+            return idx;
+        }
+        self.sources.push(Source { suite: String::from(suite), origin: String::new(), priority });
+        self.sources.len() - 1
+    }
+
+    // a source as policy shows it: its suite, and its origin if it has one
+    fn source2str(&self, source: usize) -> String {
+        let source = &self.sources[source];
+        if source.origin.is_empty() {
+            return source.suite.clone();
+        }
+        format!("{} (o={})", source.suite, source.origin)
+    }
+
+    /// Sets the Origin of a source, as given by the Release file of the suite it was loaded from.
+    pub fn set_source_origin(&mut self, source: usize, origin: &str) {
+        self.sources[source].origin = String::from(origin);
+    }
+
+    pub fn add_pin(&mut self, pin: Pin) {
+        self.pins.push(pin);
+    }
+
+    /// Returns the pin priority of a candidate, as described at the top of this file.
+    pub fn candidate_priority(&self, candidate: usize) -> i32 {
+        let cand = &self.candidates[candidate];
+        let name = self.get_package_name(cand.package_num);
+        let pinned = self.pins.iter().filter(|p| !p.is_generic()).find(|p| p.matches(name, cand, &self.sources))
+            .or_else(|| self.pins.iter().filter(|p| p.is_generic()).find(|p| p.matches(name, cand, &self.sources)));
+        if let Some(pin) = pinned {
+            return pin.priority;
+        }
+        let mut priority = cand.sources.iter().map(|s| self.sources[*s].priority).max().unwrap_or(DEFAULT_PRIORITY);
+        if self.installed_debvers.get(&cand.package_num) == Some(&cand.version) {
+            priority = priority.max(INSTALLED_PRIORITY);
+        }
+        priority
+    }
+
+    /// Returns the installable candidates of package_num (those with a non-negative priority),
+    /// highest priority first and then highest version first.
+    pub fn candidates_by_preference(&self, package_num: i32) -> Vec<usize> {
+        self.get_candidates(package_num).iter().copied()
+            .map(|c| (self.candidate_priority(c), c))
+            .filter(|(priority, _)| *priority >= 0)
+            .sorted_by(|(p1, c1), (p2, c2)| p2.cmp(p1).then(self.candidates[*c2].version.cmp(&self.candidates[*c1].version)))
+            .map(|(_, c)| c)
+            .collect_vec()
+    }
+
+    /// Returns the candidate apt's policy would pick for package_num, if any: the preferred one, except that an
+    /// installed package is never downgraded unless the lower version is pinned at 1000 or more, and stays at its
+    /// installed version if that has a higher priority than every other candidate. In that case the result is the candidate for
+    /// the installed version, or None if that version is no longer available.
+    pub fn policy_candidate(&self, package_num: i32) -> Option<usize> {
+        let installed = self.installed_debvers.get(&package_num);
+        let installed_candidate = installed.and_then(|iv| self.find_candidate(package_num, iv));
+        let installed_priority = installed_candidate.map_or(INSTALLED_PRIORITY, |c| self.candidate_priority(c));
+        for c in self.candidates_by_preference(package_num) {
+            if let Some(iv) = installed {
+                let priority = self.candidate_priority(c);
+                if self.candidates[c].version < *iv && priority < DOWNGRADE_PRIORITY {
+                    continue;
+                }
+                if priority < installed_priority {
+                    return installed_candidate;
+                }
+            }
+            return Some(c);
+        }
+        installed_candidate
+    }
+
+    /// Prints the version table for a package, in the style of apt-cache policy.
+    pub fn print_policy(&self, package_name: &str) {
+        if !self.package_exists(package_name) {
+            println!("no such package {}", package_name);
+            return;
+        }
+        let package_num = *self.get_package_num(package_name);
+        let installed = self.installed_debvers.get(&package_num);
+        let candidate = self.policy_candidate(package_num);
+        let none = String::from("(none)");
+        println!("{}:", package_name);
+        println!("  Installed: {}", installed.map_or(none.clone(), |v| v.to_string()));
+        // staying at an installed version that is no longer available still counts as a candidate
+        let candidate_version = candidate.map(|c| &self.candidates[c].version).or(installed);
+        println!("  Candidate: {}", candidate_version.map_or(none, |v| v.to_string()));
+        println!("  Version table:");
+        let mut installed_listed = false;
+        for &c in self.get_candidates(package_num) {
+            let cand = &self.candidates[c];
+            let is_installed = installed == Some(&cand.version);
+            installed_listed |= is_installed;
+            if let Some(iv) = installed {
+                if !installed_listed && cand.version < *iv {
+                    println!(" *** {} {} installed", iv, INSTALLED_PRIORITY);
+                    installed_listed = true;
+                }
+            }
+            println!(" {} {} {} {}", if is_installed { "***" } else { "   " }, cand.version, self.candidate_priority(c),
+                     cand.sources.iter().map(|s| self.source2str(*s)).format(", "));
+        }
+        if let (Some(iv), false) = (installed, installed_listed) {
+            println!(" *** {} {} installed", iv, INSTALLED_PRIORITY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_match, Pin};
+    use crate::Packages;
+
+    #[test]
+    fn glob_matches_like_the_shell() {
+        assert!(glob_match("libc6", "libc6"));
+        assert!(!glob_match("libc6", "libc6-dev"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("lib*", "libc6"));
+        assert!(glob_match("*-dev", "libc6-dev"));
+        assert!(!glob_match("*-dev", "libc6-dev-i386"));
+        assert!(glob_match("2.3*", "2.36-9"));
+        assert!(glob_match("lib*t*", "libjpeg-turbo-progs"));
+        assert!(!glob_match("lib*c*", "libjpeg-turbo-progs"));
+        assert!(glob_match("lib?6", "libc6"));
+        assert!(!glob_match("lib?6", "lib6"));
+        assert!(glob_match("*a*a*", "banana"));
+        assert!(!glob_match("*a*a*a*a", "banana"));
+        assert!(glob_match("**", "x"));
+        assert!(!glob_match("", "x"));
+    }
+
+    // foo 2.0 is installed and still available; 1.0 is pinned at priority
+    fn downgradable(priority: i32, installed_available: bool) -> Packages {
+        let mut packages_text = String::from("Package: foo\nVersion: 1.0\n");
+        if installed_available {
+            packages_text.push_str("\nPackage: foo\nVersion: 2.0\n");
+        }
+        let mut packages = Packages::from_text(&packages_text, "Package: foo\nStatus: install ok installed\nVersion: 2.0\n");
+        packages.add_pin(Pin::parse("foo", "version 1.0", &priority.to_string()).unwrap());
+        packages
+    }
+
+    fn policy_version(packages: &Packages) -> Option<String> {
+        let foo = *packages.get_package_num("foo");
+        packages.policy_candidate(foo).map(|c| packages.get_candidate(c).version.to_string())
+    }
+
+    #[test]
+    fn downgrades_only_when_pinned_at_1000_or_more() {
+        for (priority, expected) in [(100, "2.0"), (500, "2.0"), (990, "2.0"), (1000, "1.0"), (1001, "1.0")] {
+            assert_eq!(policy_version(&downgradable(priority, true)).as_deref(), Some(expected), "priority {}", priority);
+        }
+    }
+
+    #[test]
+    fn keeps_an_installed_version_that_is_no_longer_available() {
+        for (priority, expected) in [(100, None), (500, None), (990, None), (1000, Some("1.0")), (1001, Some("1.0"))] {
+            assert_eq!(policy_version(&downgradable(priority, false)).as_deref(), expected, "priority {}", priority);
+        }
+    }
+
+    #[test]
+    fn upgrades_to_the_preferred_candidate() {
+        let packages_text = "Package: foo\nVersion: 2.0\n\nPackage: foo\nVersion: 3.0\n\nPackage: foo\nVersion: 4.0\n";
+        let mut packages = Packages::from_text(packages_text, "Package: foo\nStatus: install ok installed\nVersion: 2.0\n");
+        assert_eq!(policy_version(&packages).as_deref(), Some("4.0"));
+        packages.add_pin(Pin::parse("foo", "version 3.*", "990").unwrap());
+        assert_eq!(policy_version(&packages).as_deref(), Some("3.0"));
+        packages.add_pin(Pin::parse("*", "version *", "-1").unwrap());
+        assert_eq!(policy_version(&packages).as_deref(), Some("3.0"));
+    }
+}
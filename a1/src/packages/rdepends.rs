@@ -0,0 +1,228 @@
+use std::collections::{HashSet, VecDeque};
+
+use itertools::Itertools;
+
+use crate::packages::{Dependency, RelationKind};
+use crate::Packages;
+
+/// An installed package which has to be removed along with another one, because a dependency of kind `kind`
+/// was satisfied by because_of and nothing else that stays installed satisfies it.
+pub struct CascadedRemoval {
+    pub package_num: i32,
+    pub because_of: i32,
+    pub kind: RelationKind,
+}
+
+impl Packages {
+    // the names through which a dependency can refer to package_num: its own and the virtual packages it provides
+    fn names_of(&self, package_num: i32) -> Vec<i32> {
+        let mut names = vec![self.base_package_num(package_num)];
+        for c in self.get_candidates(package_num) {
+            for provided in &self.candidates[*c].provides {
+                if !names.contains(&provided.package_num) {
+                    names.push(provided.package_num);
+                }
+            }
+        }
+        names
+    }
+
+    /// Returns the installed packages whose installed version names package_num (or something it provides) in one
+    /// of its Pre-Depends, Depends, Recommends or Suggests fields, with the kind of field and the dependency, sorted by name.
+    /// The fields of an installed package that no Packages file lists are those of its status stanza.
+    pub fn reverse_dependencies(&self, package_num: i32) -> Vec<(i32, RelationKind, &Dependency)> {
+        let mut result: Vec<(i32, RelationKind, &Dependency)> = vec![];
+        for name in self.names_of(package_num) {
+            for c in self.depended_on.get(&name).into_iter().flatten() {
+                let q = self.candidates[*c].package_num;
+                let installed_here = self.installed_version(q).is_some_and(|iv| iv.candidate == Some(*c));
+                if q == package_num || !installed_here {
+                    continue;
+                }
+                for kind in RelationKind::ALL {
+                    for dep in self.candidates[*c].relations(kind) {
+                        let names_it = dep.iter().any(|alternative| alternative.package_num == name);
+                        if names_it && !result.iter().any(|(p, k, d)| *p == q && *k == kind && std::ptr::eq(*d, dep)) {
+                            result.push((q, kind, dep));
+                        }
+                    }
+                }
+            }
+        }
+        result.sort_by(|a, b| self.get_package_name(a.0).cmp(self.get_package_name(b.0)));
+        result
+    }
+
+    /// Returns the installed packages which depend on package_num through a chain of Pre-Depends and Depends,
+    /// nearest first.
+    pub fn transitive_reverse_dependencies(&self, package_num: i32) -> Vec<i32> {
+        let mut result = vec![];
+        let mut seen: HashSet<i32> = HashSet::from([package_num]);
+        let mut worklist: VecDeque<i32> = VecDeque::from([package_num]);
+        while let Some(p) = worklist.pop_front() {
+            for (q, kind, _) in self.reverse_dependencies(p) {
+                let hard = kind == RelationKind::PreDepends || kind == RelationKind::Depends;
+                if hard && seen.insert(q) {
+                    result.push(q);
+                    worklist.push_back(q);
+                }
+            }
+        }
+        result
+    }
+
+    /// Computes what else has to be removed along with the installed package package_num: every installed package
+    /// with a Pre-Depends or Depends which holds now but wouldn't once the removed packages are gone, transitively.
+    /// Alternatives count, so a dependency A | B only breaks when both A and B go. Dependencies which are already
+    /// broken don't cascade.
+    pub fn compute_remove_plan(&self, package_num: i32) -> Vec<CascadedRemoval> {
+        let mut result = vec![];
+        let nothing_removed = HashSet::new();
+        let mut removed: HashSet<i32> = HashSet::from([package_num]);
+        let mut worklist: VecDeque<i32> = VecDeque::from([package_num]);
+        while let Some(r) = worklist.pop_front() {
+            for (q, kind, dep) in self.reverse_dependencies(r) {
+                if removed.contains(&q) || !(kind == RelationKind::PreDepends || kind == RelationKind::Depends) {
+                    continue;
+                }
+                let arch = self.package_arch(q);
+                if !self.dep_applies(dep, arch) {
+                    continue;
+                }
+                let holds_now = self.installed_satisfier(dep, arch, &nothing_removed).is_some();
+                if holds_now && self.installed_satisfier(dep, arch, &removed).is_none() {
+                    removed.insert(q);
+                    worklist.push_back(q);
+                    result.push(CascadedRemoval { package_num: q, because_of: r, kind });
+                }
+            }
+        }
+        result
+    }
+
+    pub fn print_rdepends(&self, package_name: &str) {
+        if !self.package_exists(package_name) {
+            println!("no such package {}", package_name);
+            return;
+        }
+        let package_num = *self.get_package_num(package_name);
+        let direct = self.reverse_dependencies(package_num);
+        println!("{:?} is needed by: {:?}", package_name, direct.iter()
+            .map(|(q, kind, dep)| format!("{} ({} {})", self.get_package_name(*q), kind.verb(), self.dep2str(dep)))
+            .format(", ").to_string());
+        let transitive = self.transitive_reverse_dependencies(package_num);
+        println!("{:?} transitive reverse dependencies: {:?}", package_name,
+                 transitive.iter().map(|q| self.get_package_name(*q)).format(", ").to_string());
+    }
+
+    pub fn print_remove_plan(&self, package_name: &str) {
+        if !self.package_exists(package_name) {
+            println!("no such package {}", package_name);
+            return;
+        }
+        let package_num = *self.get_package_num(package_name);
+        if !self.installed_debvers.contains_key(&package_num) {
+            println!("{} is not installed", package_name);
+            return;
+        }
+        let cascade = self.compute_remove_plan(package_num);
+        let removals = std::iter::once(String::from(package_name)).chain(cascade.iter().map(|r| format!("{} ({} {})",
+            self.get_package_name(r.package_num), r.kind.verb(), self.get_package_name(r.because_of))));
+        println!("{:?} to remove: {:?}", package_name, removals.format(", ").to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Packages;
+
+    const PACKAGES: &str = "\
+Package: libc
+Version: 2.0
+
+Package: libz
+Version: 1.0
+Depends: libc
+
+Package: app
+Version: 1.0
+Pre-Depends: libz
+Recommends: extras
+
+Package: extras
+Version: 1.0
+
+Package: mailer
+Version: 1.0
+Depends: exim | postfix
+
+Package: exim
+Version: 4.0
+Provides: mail-transport-agent
+
+Package: postfix
+Version: 3.0
+Provides: mail-transport-agent
+
+Package: cron
+Version: 3.0
+Depends: mail-transport-agent
+
+Package: picky
+Version: 1.0
+Depends: libc (>= 3.0)
+";
+
+    // the status stanzas of packages installed at the versions Packages lists, and of old-tool, which no Packages
+    // file lists any more
+    fn status(packages: &[&str]) -> String {
+        let mut text = String::from("Package: old-tool\nStatus: install ok installed\nVersion: 0.1\nDepends: libz\n");
+        for package in packages {
+            let version = PACKAGES.split("\n\n").find(|stanza| stanza.starts_with(&format!("Package: {}\n", package)))
+                .and_then(|stanza| stanza.lines().find_map(|line| line.strip_prefix("Version: "))).unwrap();
+            text.push_str(&format!("\nPackage: {}\nStatus: install ok installed\nVersion: {}\n", package, version));
+        }
+        text
+    }
+
+    fn remove_plan(packages: &Packages, package_name: &str) -> Vec<String> {
+        packages.compute_remove_plan(*packages.get_package_num(package_name)).iter().map(|r| format!("{} ({} {})",
+            packages.get_package_name(r.package_num), r.kind.verb(), packages.get_package_name(r.because_of))).collect()
+    }
+
+    #[test]
+    fn lists_reverse_dependencies_of_every_kind() {
+        let packages = Packages::from_text(PACKAGES, &status(&["libc", "libz", "app", "extras", "exim", "cron", "mailer"]));
+        let rdepends = |name: &str| packages.reverse_dependencies(*packages.get_package_num(name)).iter()
+            .map(|(q, kind, dep)| format!("{} {} {}", packages.get_package_name(*q), kind.verb(), packages.dep2str(dep)))
+            .collect::<Vec<_>>();
+        assert_eq!(rdepends("libz"), vec!["app pre-depends on libz", "old-tool depends on libz"]);
+        assert_eq!(rdepends("extras"), vec!["app recommends extras"]);
+        // through a virtual package, and through an alternative
+        assert_eq!(rdepends("exim"), vec!["cron depends on mail-transport-agent", "mailer depends on exim | postfix"]);
+        let transitive = packages.transitive_reverse_dependencies(*packages.get_package_num("libc"));
+        assert_eq!(transitive.iter().map(|q| packages.get_package_name(*q)).collect::<Vec<_>>(), vec!["libz", "app", "old-tool"]);
+    }
+
+    #[test]
+    fn removes_transitive_reverse_dependencies() {
+        let packages = Packages::from_text(PACKAGES, &status(&["libc", "libz", "app", "extras"]));
+        assert_eq!(remove_plan(&packages, "libc"), vec!["libz (depends on libc)", "app (pre-depends on libz)", "old-tool (depends on libz)"]);
+        // a Recommends doesn't cascade
+        assert_eq!(remove_plan(&packages, "extras"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn keeps_packages_another_installed_alternative_satisfies() {
+        let packages = Packages::from_text(PACKAGES, &status(&["mailer", "exim", "postfix", "cron"]));
+        assert_eq!(remove_plan(&packages, "exim"), Vec::<String>::new());
+        let packages = Packages::from_text(PACKAGES, &status(&["mailer", "exim", "cron"]));
+        assert_eq!(remove_plan(&packages, "exim"), vec!["cron (depends on exim)", "mailer (depends on exim)"]);
+    }
+
+    #[test]
+    fn already_broken_dependencies_dont_cascade() {
+        let packages = Packages::from_text(PACKAGES, &status(&["libc", "picky"]));
+        assert_eq!(remove_plan(&packages, "libc"), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,260 @@
+use crate::packages::{Candidate, MultiArch, RelVersionedPackageNum, VersionedPackage};
+use crate::Packages;
+use rpkg::debversion;
+
+impl Packages {
+    fn stanza(&self, vp: &VersionedPackage) -> Option<&Candidate> {
+        vp.candidate.map(|c| &self.candidates[c])
+    }
+
+    fn multi_arch(&self, vp: &VersionedPackage) -> MultiArch {
+        self.stanza(vp).map_or(MultiArch::No, |c| c.multi_arch)
+    }
+
+    // Whether a package of arch target_arch with the given Multi-Arch field may satisfy alternative, when the relation
+    // is declared by a package of arch from_arch (None for Conflicts, Breaks and Replaces, which apply to every
+    // architecture). An unqualified dependency needs the depender's architecture unless the target is Multi-Arch:
+    // foreign; pkg:any needs a target that is Multi-Arch: allowed (or foreign).
+    fn arch_matches(&self, alternative: &RelVersionedPackageNum, from_arch: Option<&str>, target_arch: &str, target_ma: MultiArch) -> bool {
+        match alternative.arch_qualifier.as_deref() {
+            Some("any") => from_arch.is_none() || target_ma == MultiArch::Allowed || target_ma == MultiArch::Foreign,
+            Some("native") => target_arch == self.native_arch,
+            Some(arch) => target_arch == arch,
+            None => match from_arch {
+                None => true,
+                Some(arch) => target_arch == arch || target_ma == MultiArch::Foreign
+            }
+        }
+    }
+
+    /// Returns true if some alternative of dep applies on arch: its architecture restriction list (if any) admits arch,
+    /// and its build-profile formula (if any) holds with no build profiles active. A dependency none of whose
+    /// alternatives applies is ignored, as when building a source package.
+    pub fn dep_applies(&self, dep: &[RelVersionedPackageNum], arch: &str) -> bool {
+        dep.iter().any(|alternative| alternative.restrictions.applies(arch, &[]))
+    }
+
+    /// Returns true if vp is the package named by alternative, and of an architecture that alternative accepts
+    /// (see satisfies), whatever its version.
+    pub fn arch_satisfies(&self, alternative: &RelVersionedPackageNum, from_arch: Option<&str>, vp: &VersionedPackage) -> bool {
+        self.base_package_num(vp.package_num) == alternative.package_num
+            && self.arch_matches(alternative, from_arch, self.package_arch(vp.package_num), self.multi_arch(vp))
+    }
+
+    /// Returns true if vp satisfies alternative, either because it is the named package and the version relation
+    /// holds, or because it Provides the named package. An unversioned alternative is satisfied by any provider;
+    /// a versioned one only by a versioned Provides (= v) which meets the relation.
+    /// from_arch is the architecture of the package declaring the relation, which decides (with vp's Multi-Arch field)
+    /// which architectures of the named package qualify; None accepts any architecture. An alternative whose restrictions
+    /// don't apply on from_arch (or on the native architecture, if None) is satisfied by nothing.
+    pub fn satisfies(&self, alternative: &RelVersionedPackageNum, from_arch: Option<&str>, vp: &VersionedPackage) -> bool {
+        if !alternative.restrictions.applies(from_arch.unwrap_or(&self.native_arch), &[]) {
+            return false;
+        }
+        if self.arch_satisfies(alternative, from_arch, vp) {
+            return match &alternative.rel_version {
+                None => true,
+                // relation versions are validated when parsed
+                Some((op, required_version)) => {
+                    let v = required_version
+                        .parse::<debversion::DebianVersionNum>()
+                        .unwrap();
+                    debversion::cmp_debversion_with_op(op, vp.version, &v)
+                }
+            };
+        }
+        if !self.arch_matches(alternative, from_arch, self.package_arch(vp.package_num), self.multi_arch(vp)) {
+            return false;
+        }
+        let provides = self.stanza(vp).map_or(&[][..], |c| &c.provides);
+        for provided in provides {
+            if provided.package_num != alternative.package_num {
+                continue;
+            }
+            match (&alternative.rel_version, &provided.rel_version) {
+                (None, _) => return true,
+                (Some(_), None) => (),
+                (Some((op, required_version)), Some((_, provided_version))) => {
+                    let rv = required_version.parse::<debversion::DebianVersionNum>().unwrap();
+                    let pv = provided_version.parse::<debversion::DebianVersionNum>().unwrap();
+                    if debversion::cmp_debversion_with_op(op, &pv, &rv) {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Returns the packages which might satisfy alternative: the named package (if it is a real one) for every
+    /// architecture, and its providers.
+    pub fn alternative_packages(&self, alternative: &RelVersionedPackageNum) -> Vec<i32> {
+        let mut result = vec![];
+        for p in self.all_arch_variants(alternative.package_num) {
+            if self.available.contains_key(&p) || self.installed_debvers.contains_key(&p) {
+                result.push(p);
+            }
+        }
+        if let Some(providers) = self.providers.get(&alternative.package_num) {
+            for c in providers {
+                let p = self.candidates[*c].package_num;
+                if !result.contains(&p) {
+                    result.push(p);
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns true if p and q are the same package for two architectures and can't be installed together,
+    /// which is the case unless both are Multi-Arch: same and at the same version.
+    pub fn arch_conflict(&self, p: &VersionedPackage, q: &VersionedPackage) -> bool {
+        if p.package_num == q.package_num || self.base_package_num(p.package_num) != self.base_package_num(q.package_num) {
+            return false;
+        }
+        let coinstallable = self.multi_arch(p) == MultiArch::Same && self.multi_arch(q) == MultiArch::Same && p.version == q.version;
+        !coinstallable
+    }
+
+    /// Returns true if p declares Conflicts or Breaks against q, or is q for another architecture and can't be
+    /// co-installed with it.
+    /// A package never conflicts with itself, even through a virtual package it also provides, nor (as with dpkg)
+    /// with itself for another architecture when both are Multi-Arch: same and can be co-installed.
+    pub fn declares_conflict(&self, p: &VersionedPackage, q: &VersionedPackage) -> bool {
+        if self.base_package_num(p.package_num) == self.base_package_num(q.package_num) {
+            return self.arch_conflict(p, q);
+        }
+        if let Some(stanza) = self.stanza(p) {
+            for rel in stanza.conflicts.iter().chain(stanza.breaks.iter()) {
+                if self.satisfies(rel, None, q) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Returns true if p declares that it Replaces q.
+    pub fn declares_replaces(&self, p: &VersionedPackage, q: &VersionedPackage) -> bool {
+        match self.stanza(p) {
+            None => false,
+            Some(stanza) => stanza.replaces.iter().any(|rel| self.satisfies(rel, None, q))
+        }
+    }
+
+    /// Returns the installed packages which would have to be removed if vp were installed.
+    pub fn conflicting_installed(&self, vp: &VersionedPackage) -> Vec<i32> {
+        let mut result = vec![];
+        // conflicts declared by vp itself
+        if let Some(stanza) = self.stanza(vp) {
+            for rel in stanza.conflicts.iter().chain(stanza.breaks.iter()) {
+                for q in self.alternative_packages(rel) {
+                    if let Some(qv) = self.installed_version(q) {
+                        if self.base_package_num(q) != self.base_package_num(vp.package_num) && self.satisfies(rel, None, &qv) && !result.contains(&q) {
+                            result.push(q);
+                        }
+                    }
+                }
+            }
+        }
+        // vp for other architectures
+        for q in self.all_arch_variants(vp.package_num) {
+            if let Some(qv) = self.installed_version(q) {
+                if self.arch_conflict(vp, &qv) && !result.contains(&q) {
+                    result.push(q);
+                }
+            }
+        }
+        // conflicts declared against vp (or something it provides) by installed packages
+        let mut names = vec![self.base_package_num(vp.package_num)];
+        names.extend(self.stanza(vp).into_iter().flat_map(|s| s.provides.iter().map(|p| p.package_num)));
+        for name in names {
+            for c in self.conflicts_on.get(&name).into_iter().flatten() {
+                let q = self.candidates[*c].package_num;
+                if let Some(qv) = self.installed_version(q) {
+                    if qv.candidate == Some(*c) && !result.contains(&q) && self.declares_conflict(&qv, vp) {
+                        result.push(q);
+                    }
+                }
+            }
+        }
+        result.sort();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packages::RelationPolicy;
+    use crate::Packages;
+
+    // both architectures of a Multi-Arch: same library which, like libjpeg62-turbo, takes over a virtual package
+    const PACKAGES: &str = "\
+Package: libjpeg62-turbo
+Architecture: amd64
+Version: 1:2.1.5-2
+Multi-Arch: same
+Provides: libjpeg62
+Conflicts: libjpeg62
+Replaces: libjpeg62
+
+Package: libjpeg62-turbo
+Architecture: i386
+Version: 1:2.1.5-2
+Multi-Arch: same
+Provides: libjpeg62
+Conflicts: libjpeg62
+Replaces: libjpeg62
+
+Package: libtiff5
+Architecture: i386
+Version: 4.2.0-1
+Depends: libjpeg62-turbo (>= 1.3.1)
+
+Package: libjpeg62
+Architecture: amd64
+Version: 6b2-3
+";
+
+    const STATUS: &str = "\
+Package: libjpeg62-turbo
+Status: install ok installed
+Architecture: amd64
+Version: 1:2.1.5-2
+Multi-Arch: same
+";
+
+    fn packages() -> Packages {
+        // the architecture is set before anything is loaded, as set-arch has to be
+        let mut packages = Packages::new();
+        packages.set_native_arch("amd64");
+        packages.load_text(PACKAGES, STATUS);
+        packages
+    }
+
+    #[test]
+    fn multi_arch_same_variants_dont_conflict_through_a_virtual_package() {
+        let packages = packages();
+        let amd64 = *packages.get_package_num("libjpeg62-turbo");
+        let i386 = *packages.get_package_num("libjpeg62-turbo:i386");
+        let amd64_installed = packages.installed_version(amd64).unwrap();
+        let i386_available = packages.available_version(packages.best_candidate(i386).unwrap());
+        assert!(!packages.declares_conflict(&i386_available, &amd64_installed));
+        assert!(!packages.declares_conflict(&amd64_installed, &i386_available));
+        assert_eq!(packages.conflicting_installed(&i386_available), Vec::<i32>::new());
+        // the real package still conflicts with both
+        let libjpeg62 = packages.available_version(packages.best_candidate(*packages.get_package_num("libjpeg62")).unwrap());
+        assert!(packages.declares_conflict(&i386_available, &libjpeg62));
+        assert_eq!(packages.conflicting_installed(&libjpeg62), vec![amd64]);
+    }
+
+    #[test]
+    fn multi_arch_same_variants_are_installed_together() {
+        let packages = packages();
+        let solution = packages.transitive_dep_solution("libtiff5:i386", &RelationPolicy::default()).unwrap();
+        let i386 = *packages.get_package_num("libjpeg62-turbo:i386");
+        assert!(solution.packages.iter().any(|(pn, _)| *pn == i386));
+        assert!(solution.removals.is_empty());
+        assert!(packages.compute_upgrade_plan().removals.is_empty());
+    }
+}
@@ -0,0 +1,343 @@
+// A small DPLL satisfiability solver, tailored to the clauses produced by the package resolver.
+//
+// Variables are numbered from 1; a literal is +v (v is true) or -v (v is false).
+// The branching heuristic is deliberately simple and deterministic: it picks the first clause
+// (in insertion order) which is not yet satisfied and which cannot be satisfied by leaving its
+// negative literals unassigned, and then tries that clause's positive literals in order.
+// Unassigned variables end up false, so callers get small models (nothing is installed unless
+// something needs it), and the order of literals within a clause expresses a preference.
+
+pub type Lit = i32;
+
+fn var_of(lit: Lit) -> usize {
+    lit.unsigned_abs() as usize
+}
+
+pub struct Solver {
+    clauses: Vec<Vec<Lit>>,
+    // occurs[v] lists the clauses mentioning variable v (with either polarity)
+    occurs: Vec<Vec<usize>>,
+    assignment: Vec<Option<bool>>,
+    // the clause that forced each variable, or None for decisions (and unassigned variables)
+    reasons: Vec<Option<usize>>,
+    trail: Vec<Lit>,
+    qhead: usize,
+    conflict_core: Vec<usize>,
+}
+
+impl Solver {
+    pub fn new() -> Solver {
+        Solver {
+            clauses: vec![],
+            occurs: vec![vec![]],
+            assignment: vec![None],
+            reasons: vec![None],
+            trail: vec![],
+            qhead: 0,
+            conflict_core: vec![],
+        }
+    }
+
+    /// Allocates a fresh variable and returns its (positive) literal.
+    pub fn new_var(&mut self) -> Lit {
+        self.occurs.push(vec![]);
+        self.assignment.push(None);
+        self.reasons.push(None);
+        (self.assignment.len() - 1) as Lit
+    }
+
+    /// Adds clause lits (a disjunction) and returns its index, which the caller can use to map
+    /// conflict explanations back to wherever the clause came from.
+    pub fn add_clause(&mut self, lits: Vec<Lit>) -> usize {
+        let idx = self.clauses.len();
+        for lit in &lits {
+            let v = var_of(*lit);
+            if !self.occurs[v].contains(&idx) {
+                self.occurs[v].push(idx);
+            }
+        }
+        self.clauses.push(lits);
+        idx
+    }
+
+    pub fn clause(&self, idx: usize) -> &[Lit] {
+        &self.clauses[idx]
+    }
+
+    pub fn value(&self, lit: Lit) -> Option<bool> {
+        self.assignment[var_of(lit)].map(|b| if lit > 0 { b } else { !b })
+    }
+
+    /// After a successful solve(), returns whether the variable of (positive) literal lit is true.
+    pub fn model_value(&self, lit: Lit) -> bool {
+        self.value(lit) == Some(true)
+    }
+
+    /// After an unsuccessful solve(), returns the clauses involved in the last conflict, in the
+    /// order in which they became relevant; the final entry is the violated clause.
+    pub fn conflict_core(&self) -> &[usize] {
+        &self.conflict_core
+    }
+
+    fn assign(&mut self, lit: Lit, reason: Option<usize>) {
+        let v = var_of(lit);
+        self.assignment[v] = Some(lit > 0);
+        self.reasons[v] = reason;
+        self.trail.push(lit);
+    }
+
+    fn undo_to(&mut self, trail_len: usize) {
+        while self.trail.len() > trail_len {
+            let v = var_of(self.trail.pop().unwrap());
+            self.assignment[v] = None;
+            self.reasons[v] = None;
+        }
+        self.qhead = self.qhead.min(trail_len);
+    }
+
+    // Unit propagation; returns the index of a violated clause, if any.
+    fn propagate(&mut self) -> Option<usize> {
+        while self.qhead < self.trail.len() {
+            let lit = self.trail[self.qhead];
+            self.qhead += 1;
+            for i in 0..self.occurs[var_of(lit)].len() {
+                let ci = self.occurs[var_of(lit)][i];
+                let mut unassigned = None;
+                let mut num_unassigned = 0;
+                let mut satisfied = false;
+                for &l in &self.clauses[ci] {
+                    match self.value(l) {
+                        Some(true) => { satisfied = true; break; }
+                        Some(false) => (),
+                        None => { num_unassigned += 1; unassigned = Some(l); }
+                    }
+                }
+                if satisfied {
+                    continue;
+                }
+                match num_unassigned {
+                    0 => return Some(ci),
+                    1 => self.assign(unassigned.unwrap(), Some(ci)),
+                    _ => ()
+                }
+            }
+        }
+        None
+    }
+
+    fn record_conflict(&mut self, conflict: usize) {
+        let position: std::collections::HashMap<usize, usize> = self.trail.iter().enumerate()
+            .map(|(pos, lit)| (var_of(*lit), pos)).collect();
+        let mut seen = vec![false; self.clauses.len()];
+        let mut core: Vec<(usize, usize)> = vec![];
+        let mut stack = vec![conflict];
+        seen[conflict] = true;
+        while let Some(ci) = stack.pop() {
+            for &l in &self.clauses[ci] {
+                let v = var_of(l);
+                if let Some(r) = self.reasons[v] {
+                    if !seen[r] {
+                        seen[r] = true;
+                        core.push((position[&v], r));
+                        stack.push(r);
+                    }
+                }
+            }
+        }
+        core.sort();
+        self.conflict_core = core.into_iter().map(|(_, ci)| ci).collect();
+        self.conflict_core.push(conflict);
+    }
+
+    // The branching heuristic described at the top of this file.
+    fn pick_branch(&self) -> Option<Lit> {
+        'clauses: for clause in &self.clauses {
+            let mut candidate = None;
+            for &l in clause {
+                match self.value(l) {
+                    Some(true) => continue 'clauses,
+                    Some(false) => (),
+                    None => {
+                        if l < 0 {
+                            continue 'clauses;
+                        }
+                        if candidate.is_none() {
+                            candidate = Some(l);
+                        }
+                    }
+                }
+            }
+            if candidate.is_some() {
+                return candidate;
+            }
+        }
+        None
+    }
+
+    /// Searches for a satisfying assignment; unassigned variables are set to false at the end.
+    pub fn solve(&mut self) -> bool {
+        self.solve_with(&[])
+    }
+
+    /// Searches for a satisfying assignment in which every literal of assumptions is true, as if each were a unit
+    /// clause. The clauses are kept, so the solver can be asked again under other assumptions; after an unsuccessful
+    /// solve, the conflict core may involve the clauses that made an assumption fail, but not the assumptions.
+    pub fn solve_with(&mut self, assumptions: &[Lit]) -> bool {
+        self.undo_to(0);
+        self.conflict_core.clear();
+        for ci in 0..self.clauses.len() {
+            match self.clauses[ci].len() {
+                0 => { self.record_conflict(ci); return false; }
+                1 => {
+                    let l = self.clauses[ci][0];
+                    match self.value(l) {
+                        Some(false) => { self.record_conflict(ci); return false; }
+                        Some(true) => (),
+                        None => self.assign(l, Some(ci))
+                    }
+                }
+                _ => ()
+            }
+        }
+        if let Some(conflict) = self.propagate() {
+            self.record_conflict(conflict);
+            return false;
+        }
+
+        // each entry is (trail length before the decision, decision literal, already flipped); assumptions count as
+        // decisions which can't be flipped
+        let mut decisions: Vec<(usize, Lit, bool)> = vec![];
+        for &lit in assumptions {
+            match self.value(lit) {
+                Some(true) => continue,
+                Some(false) => {
+                    match self.reasons[var_of(lit)] {
+                        Some(reason) => self.record_conflict(reason),
+                        None => self.conflict_core.clear() // contradicted by an earlier assumption
+                    }
+                    return false;
+                }
+                None => ()
+            }
+            decisions.push((self.trail.len(), lit, true));
+            self.assign(lit, None);
+            if let Some(conflict) = self.propagate() {
+                self.record_conflict(conflict);
+                return false;
+            }
+        }
+        loop {
+            let lit = match self.pick_branch() {
+                None => break,
+                Some(lit) => lit
+            };
+            decisions.push((self.trail.len(), lit, false));
+            self.assign(lit, None);
+            while let Some(conflict) = self.propagate() {
+                self.record_conflict(conflict);
+                loop {
+                    let (len, lit, flipped) = match decisions.pop() {
+                        None => return false,
+                        Some(d) => d
+                    };
+                    self.undo_to(len);
+                    if !flipped {
+                        decisions.push((len, -lit, true));
+                        self.assign(-lit, None);
+                        break;
+                    }
+                }
+            }
+        }
+
+        for v in 1..self.assignment.len() {
+            if self.assignment[v].is_none() {
+                self.assign(-(v as Lit), None);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lit, Solver};
+
+    fn solver_for(num_vars: usize, clauses: Vec<Vec<Lit>>) -> Solver {
+        let mut solver = Solver::new();
+        for _ in 0..num_vars {
+            solver.new_var();
+        }
+        for clause in clauses {
+            solver.add_clause(clause);
+        }
+        solver
+    }
+
+    fn model(solver: &Solver, num_vars: usize) -> Vec<Lit> {
+        (1..=num_vars as Lit).filter(|v| solver.model_value(*v)).collect()
+    }
+
+    #[test]
+    fn finds_the_smallest_preferred_model() {
+        // 1 needs 2 or 3 and 4; 5 is never needed
+        let mut solver = solver_for(5, vec![vec![1], vec![-1, 2, 3], vec![-1, 4]]);
+        assert!(solver.solve());
+        assert_eq!(model(&solver, 5), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn backtracks_to_later_literals() {
+        // 2 is preferred over 3 but needs 4, which is ruled out
+        let mut solver = solver_for(4, vec![vec![2, 3], vec![-2, 4], vec![-4, -1], vec![1]]);
+        assert!(solver.solve());
+        assert_eq!(model(&solver, 4), vec![1, 3]);
+    }
+
+    #[test]
+    fn explains_unsatisfiable_clauses() {
+        let mut solver = solver_for(3, vec![vec![1], vec![-1, 2], vec![-2, 3], vec![-3, -1]]);
+        assert!(!solver.solve());
+        // 1 forces 2 (clause 1) and rules out 3 (clause 3), so clause 2 is the one violated
+        assert_eq!(solver.conflict_core(), &[0, 1, 3, 2]);
+        let mut solver = solver_for(1, vec![vec![1], vec![]]);
+        assert!(!solver.solve());
+        assert_eq!(solver.conflict_core(), &[1]);
+    }
+
+    #[test]
+    fn explains_conflicts_found_while_searching() {
+        // every choice for 1 (2 or 3) conflicts with 4
+        let mut solver = solver_for(4, vec![vec![1], vec![-1, 2, 3], vec![-2, -4], vec![-3, -4], vec![4]]);
+        assert!(!solver.solve());
+        let core = solver.conflict_core();
+        assert_eq!(core.last(), Some(&1));
+        assert!(core.contains(&3) && core.contains(&4));
+    }
+
+    #[test]
+    fn solves_under_assumptions_without_losing_clauses() {
+        // 1 and 2 each need 3, which excludes 4
+        let mut solver = solver_for(4, vec![vec![-1, 3], vec![-2, 3], vec![-3, -4]]);
+        assert!(solver.solve());
+        assert_eq!(model(&solver, 4), Vec::<Lit>::new());
+        assert!(solver.solve_with(&[1, 2]));
+        assert_eq!(model(&solver, 4), vec![1, 2, 3]);
+        assert!(!solver.solve_with(&[1, 4]));
+        assert_eq!(solver.conflict_core().last(), Some(&2));
+        assert!(solver.solve_with(&[4]));
+        assert_eq!(model(&solver, 4), vec![4]);
+        assert!(!solver.solve_with(&[1, -1]));
+        assert!(solver.solve_with(&[2, 2]));
+        assert_eq!(model(&solver, 4), vec![2, 3]);
+    }
+
+    #[test]
+    fn fails_under_assumptions_that_search_refutes() {
+        // 1 needs 2 or 3, both of which need 4, which 5 excludes
+        let mut solver = solver_for(5, vec![vec![-1, 2, 3], vec![-2, 4], vec![-3, 4], vec![-4, -5]]);
+        assert!(!solver.solve_with(&[5, 1]));
+        assert!(solver.solve_with(&[1]));
+        assert_eq!(model(&solver, 5), vec![1, 2, 4]);
+    }
+}
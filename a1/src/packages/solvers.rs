@@ -1,119 +1,1101 @@
-use crate::packages::Dependency;
+use crate::packages::{Dependency, InstallStrategy, RelationKind, RelationPolicy, VersionedPackage};
+use crate::packages::sat::{Lit, Solver};
 use crate::Packages;
-use rpkg::debversion::DebianVersionNum;
-use std::collections::VecDeque;
+use itertools::Itertools;
+use rpkg::debversion::{DebianVersionNum, VersionSet};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
 
-impl Packages {
-    /// Computes a solution for the transitive dependencies of package_name; when there is a choice A | B | C,
-    /// chooses the first option A. Returns a Vec<i32> of package numbers.
-    ///
-    /// Note: does not consider which packages are installed.
-    pub fn transitive_dep_solution(&self, package_name: &str) -> Vec<i32> {
-        if !self.package_exists(package_name) {
-            return vec![];
+/// Result of transitive_dep_solution: the packages that must be present, and the installed packages which
+/// would have to be removed because they conflict with (or are broken by) one of them.
+pub struct DepSolution {
+    pub packages: Vec<(i32, Option<usize>)>, // (package, candidate to install or None to keep the installed version)
+    pub removals: Vec<Removal>,
+}
+
+/// Result of compute_upgrade_plan. Upgrades and downgrades are (package, candidate) pairs.
+pub struct UpgradePlan {
+    pub upgrades: Vec<(i32, usize)>,
+    pub downgrades: Vec<(i32, usize)>,
+    pub installs: Vec<usize>,
+    pub removals: Vec<i32>,
+    pub kept_back: Vec<i32>, // installed packages with a newer candidate that can't be installed
+}
+
+/// The download and the change in disk usage (both in bytes) a plan brings. Packages without a Size or
+/// Installed-Size field count as 0.
+pub struct PlanSize {
+    pub download: u64,
+    pub disk_delta: i64,
+}
+
+/// A plan for installing a package: the candidates to install besides the package's own, all of them, the
+/// installed packages they force out, what they cost, and the strategy which picked them.
+pub struct InstallPlan {
+    pub dependencies: Vec<usize>,
+    pub candidates: Vec<usize>,
+    pub removals: Vec<Removal>,
+    pub size: PlanSize,
+    pub strategy: InstallStrategy,
+}
+
+pub struct Removal {
+    pub package_num: i32,
+    pub because_of: i32,
+    pub replaced: bool, // because_of declares Replaces on the removed package
+}
+
+// A candidate satisfying a dependency, with what select_dependency ranks it by.
+struct Eligible {
+    candidate: usize,
+    alternative_idx: usize,
+    conflicts: bool, // with an installed package
+    wrong_version_installed: bool,
+    priority: i32,
+    new_packages: usize, // only computed for min-new and min-size
+    download_size: u64,
+}
+
+// A (package, version) that the resolver may pick: the installed version of a package, or one of its candidates.
+struct Choice<'a> {
+    package: VersionedPackage<'a>,
+    installed: bool,
+}
+
+// Why a clause was added; used to explain unsatisfiable requests.
+enum ClauseOrigin {
+    Request(i32),
+    OneVersion(i32),
+    Depends(usize, RelationKind, usize), // (choice index, relation field, index into that field)
+    Conflicts(usize, usize), // (choice index, choice index)
+}
+
+// Encodes the dependency closure of a package as a SAT problem: one variable per choice,
+// a clause requiring some choice of the requested package, pairwise clauses allowing at most
+// one choice per package, and for every choice c and dependency A | B | C of its version,
+// a clause (not c) or (choices of A, B, C or their providers that satisfy the version relation).
+// Choices which declare Conflicts or Breaks against each other get a clause (not c) or (not d).
+struct Resolver<'a> {
+    packages: &'a Packages,
+    policy: RelationPolicy,
+    solver: Solver,
+    choices: Vec<Choice<'a>>,
+    choices_of: HashMap<i32, Vec<usize>>,
+    conflicts_with_installed: HashMap<usize, bool>,
+    origins: Vec<ClauseOrigin>,
+    selectors: HashMap<i32, Lit>, // requested package -> the variable its request is conditional on, if it is
+    prefer_upgrades: bool, // try the policy candidate of an installed package before its installed version
+}
+
+impl<'a> Resolver<'a> {
+    fn new(packages: &'a Packages, policy: &RelationPolicy) -> Resolver<'a> {
+        Resolver {
+            packages,
+            policy: *policy,
+            solver: Solver::new(),
+            choices: vec![],
+            choices_of: HashMap::new(),
+            conflicts_with_installed: HashMap::new(),
+            origins: vec![],
+            selectors: HashMap::new(),
+            prefer_upgrades: false,
         }
+    }
 
-        let deps: &Vec<Dependency> = &*self
-            .dependencies
-            .get(self.get_package_num(package_name))
-            .unwrap();
-        let mut dependency_set = vec![];
-        let mut worklist = Vec::new();
+    // the variable of a choice, which come after the selectors
+    fn lit(&self, choice: usize) -> Lit {
+        (self.selectors.len() + choice + 1) as Lit
+    }
 
-        for dependency in deps {
-            match dependency.first() {
-                None => {
-                    continue;
+    fn add_clause(&mut self, lits: Vec<Lit>, origin: ClauseOrigin) {
+        self.solver.add_clause(lits);
+        self.origins.push(origin);
+    }
+
+    // Creates the choices for package_num (installed version first, then the installable candidates in order of preference,
+    // but with the policy candidate ahead of the installed version if prefer_upgrades is set) the first time it is seen;
+    // returns true if that happened, i.e. the package still needs its dependencies encoded.
+    fn add_choices(&mut self, package_num: i32) -> bool {
+        if self.choices_of.contains_key(&package_num) {
+            return false;
+        }
+        let packages = self.packages;
+        let mut choices = vec![];
+        let installed = packages.installed_version(package_num);
+        if let Some(iv) = installed {
+            choices.push(Choice { package: iv, installed: true });
+        }
+        for c in packages.candidates_by_preference(package_num) {
+            let av = packages.available_version(c);
+            if installed.is_none_or(|iv| iv.version != av.version) {
+                choices.push(Choice { package: av, installed: false });
+            }
+        }
+        if let (true, Some(iv), Some(upgrade)) = (self.prefer_upgrades, installed, packages.policy_candidate(package_num)) {
+            if packages.get_candidate(upgrade).version != *iv.version {
+                let pos = choices.iter().position(|choice| choice.package.candidate == Some(upgrade) && !choice.installed).unwrap();
+                let choice = choices.remove(pos);
+                choices.insert(0, choice);
+            }
+        }
+        let mut indices = vec![];
+        for choice in choices {
+            let lit = self.solver.new_var();
+            assert_eq!(lit, self.lit(self.choices.len()));
+            indices.push(self.choices.len());
+            self.choices.push(choice);
+        }
+        for (i, a) in indices.iter().enumerate() {
+            for b in &indices[i + 1..] {
+                let lits = vec![-self.lit(*a), -self.lit(*b)];
+                self.add_clause(lits, ClauseOrigin::OneVersion(package_num));
+            }
+        }
+        self.choices_of.insert(package_num, indices);
+        true
+    }
+
+    fn conflicts_with_installed(&mut self, choice: usize) -> bool {
+        if let Some(result) = self.conflicts_with_installed.get(&choice) {
+            return *result;
+        }
+        let result = !self.packages.conflicting_installed(&self.choices[choice].package).is_empty();
+        self.conflicts_with_installed.insert(choice, result);
+        result
+    }
+
+    // Choices satisfying dependency dep, directly or through Provides. Installed ones come first, then the ones which
+    // don't conflict with anything installed, and otherwise they are in the order of the alternatives (and, within
+    // a package, from highest version down).
+    // Packages seen for the first time are queued on worklist so that their dependencies get encoded too.
+    fn satisfying_choices(&mut self, dep: &Dependency, from_arch: &str, worklist: &mut VecDeque<i32>) -> Vec<usize> {
+        let mut result = vec![];
+        for alternative in dep {
+            for package_num in self.packages.alternative_packages(alternative) {
+                if self.add_choices(package_num) {
+                    worklist.push_back(package_num);
                 }
-                Some(first_option) => {
-                    worklist.push(first_option);
+                for &c in &self.choices_of[&package_num] {
+                    if self.packages.satisfies(alternative, Some(from_arch), &self.choices[c].package) && !result.contains(&c) {
+                        result.push(c);
+                    }
                 }
             }
         }
+        let keys: HashMap<usize, (bool, bool)> = result.iter()
+            .map(|c| (*c, (!self.choices[*c].installed, self.conflicts_with_installed(*c))))
+            .collect();
+        result.sort_by_key(|c| keys[c]);
+        result
+    }
 
-        while let Some(first_option) = worklist.pop() {
-            dependency_set.push(first_option.package_num);
-            if let Some(dependencies) = self.dependencies.get(&first_option.package_num) {
-                for dependency in dependencies {
-                    match dependency.first() {
-                        None => {
+    // Adds a clause for every pair of choices where one declares Conflicts or Breaks against the other, or which are
+    // the same package for two architectures and can't be co-installed. A package's own Conflicts and Breaks never apply
+    // to itself for another architecture: whether the two can be co-installed is only decided by their Multi-Arch fields.
+    fn encode_conflicts(&mut self) {
+        let packages = self.packages;
+        let mut seen: HashSet<(usize, usize)> = HashSet::new();
+        for c in 0..self.choices.len() {
+            let p = packages.base_package_num(self.choices[c].package.package_num);
+            let stanza = match self.choices[c].package.candidate {
+                None => continue,
+                Some(stanza) => packages.get_candidate(stanza)
+            };
+            for rel in stanza.conflicts.iter().chain(stanza.breaks.iter()) {
+                for q in packages.alternative_packages(rel) {
+                    if packages.base_package_num(q) == p {
+                        continue;
+                    }
+                    for &d in self.choices_of.get(&q).into_iter().flatten() {
+                        if packages.satisfies(rel, None, &self.choices[d].package) && seen.insert((c.min(d), c.max(d))) {
+                            self.solver.add_clause(vec![-self.lit(c), -self.lit(d)]);
+                            self.origins.push(ClauseOrigin::Conflicts(c, d));
+                        }
+                    }
+                }
+            }
+        }
+        for c in 0..self.choices.len() {
+            for q in packages.all_arch_variants(self.choices[c].package.package_num) {
+                for &d in self.choices_of.get(&q).into_iter().flatten() {
+                    if packages.arch_conflict(&self.choices[c].package, &self.choices[d].package) && seen.insert((c.min(d), c.max(d))) {
+                        self.solver.add_clause(vec![-self.lit(c), -self.lit(d)]);
+                        self.origins.push(ClauseOrigin::Conflicts(c, d));
+                    }
+                }
+            }
+        }
+    }
+
+    // Requires some choice of each of package_nums (unless its selector is false), and encodes everything they might need.
+    fn encode(&mut self, package_nums: &[i32]) {
+        let mut worklist: VecDeque<i32> = VecDeque::new();
+        for &package_num in package_nums {
+            if self.add_choices(package_num) {
+                worklist.push_back(package_num);
+            }
+            let mut root_lits = self.selectors.get(&package_num).map(|s| -s).into_iter().collect_vec();
+            root_lits.extend(self.choices_of[&package_num].iter().map(|c| self.lit(*c)));
+            self.add_clause(root_lits, ClauseOrigin::Request(package_num));
+        }
+        let packages = self.packages;
+        while let Some(pn) = worklist.pop_front() {
+            let arch = packages.package_arch(pn);
+            for c in self.choices_of[&pn].clone() {
+                let stanza = match self.choices[c].package.candidate {
+                    None => continue,
+                    Some(stanza) => packages.get_candidate(stanza)
+                };
+                for kind in RelationKind::ALL {
+                    if !self.policy.follows(kind) {
+                        continue;
+                    }
+                    for (dep_idx, dep) in stanza.relations(kind).iter().enumerate() {
+                        if !packages.dep_applies(dep, arch) {
+                            continue;
+                        }
+                        let mut lits = vec![-self.lit(c)];
+                        let satisfying = self.satisfying_choices(dep, arch, &mut worklist);
+                        lits.extend(satisfying.into_iter().map(|d| self.lit(d)));
+                        // like apt, a recommendation or suggestion that can't be satisfied at all is ignored
+                        let soft = kind == RelationKind::Recommends || kind == RelationKind::Suggests;
+                        if soft && lits.len() == 1 {
                             continue;
                         }
-                        Some(first_option) => {
-                            if !dependency_set.contains(&first_option.package_num) {
-                                worklist.push(first_option);
-                            }
+                        self.add_clause(lits, ClauseOrigin::Depends(c, kind, dep_idx));
+                    }
+                }
+            }
+        }
+        self.encode_conflicts();
+    }
+
+    // Encodes package_nums as encode does, but with each request holding only while a selector variable of its own
+    // is true, so that requests can be given up by solving under fewer assumptions, without encoding anything again.
+    fn encode_selectable(&mut self, package_nums: &[i32]) {
+        assert!(self.choices.is_empty(), "selectors must be allocated before any choice");
+        for &package_num in package_nums {
+            let selector = self.solver.new_var();
+            self.selectors.insert(package_num, selector);
+        }
+        self.encode(package_nums);
+    }
+
+    // the selectors of package_nums, all true
+    fn assumptions(&self, package_nums: &[i32]) -> Vec<Lit> {
+        package_nums.iter().map(|pn| self.selectors[pn]).collect()
+    }
+
+    fn choice2str(&self, c: usize) -> String {
+        let choice = &self.choices[c];
+        let state = if choice.installed { "installed" } else { "available" };
+        format!("{} ({} {})", self.packages.get_package_name(choice.package.package_num), state, choice.package.version)
+    }
+
+    fn explain(&self, clause: usize) -> String {
+        match self.origins[clause] {
+            ClauseOrigin::Request(pn) => {
+                if self.solver.clause(clause).is_empty() {
+                    format!("{} was requested, but no version of it is known", self.packages.get_package_name(pn))
+                } else {
+                    format!("{} was requested", self.packages.get_package_name(pn))
+                }
+            }
+            ClauseOrigin::OneVersion(pn) =>
+                format!("only one version of {} can be installed", self.packages.get_package_name(pn)),
+            ClauseOrigin::Depends(c, kind, dep_idx) => {
+                let stanza = self.packages.get_candidate(self.choices[c].package.candidate.unwrap());
+                let dep = &stanza.relations(kind)[dep_idx];
+                let mut line = format!("{} {} {}", self.choice2str(c), kind.verb(), self.packages.dep2str(dep));
+                if self.solver.clause(clause).len() == 1 {
+                    line.push_str(", which no known version satisfies");
+                }
+                line
+            }
+            ClauseOrigin::Conflicts(c, d) =>
+                format!("{} conflicts with {}", self.choice2str(c), self.choice2str(d)),
+        }
+    }
+
+    // After an unsuccessful solve, finds a requested package to give up on: the one nearest to the violated clause
+    // among those the conflict involves.
+    fn culprit(&self, requested: &[i32]) -> Option<i32> {
+        for ci in self.solver.conflict_core().iter().rev() {
+            let involved = match self.origins[*ci] {
+                ClauseOrigin::Request(pn) | ClauseOrigin::OneVersion(pn) => vec![pn],
+                ClauseOrigin::Depends(c, _, _) => vec![self.choices[c].package.package_num],
+                ClauseOrigin::Conflicts(c, d) => vec![self.choices[d].package.package_num, self.choices[c].package.package_num],
+            };
+            if let Some(pn) = involved.into_iter().find(|pn| requested.contains(pn)) {
+                return Some(pn);
+            }
+        }
+        requested.last().copied()
+    }
+
+    fn explain_conflict(&self) -> String {
+        let core = self.solver.conflict_core();
+        let mut lines = vec![];
+        for (i, ci) in core.iter().enumerate() {
+            let prefix = if i + 1 == core.len() { "but" } else { "-" };
+            lines.push(format!("{} {}", prefix, self.explain(*ci)));
+        }
+        lines.join("\n")
+    }
+}
+
+impl Packages {
+    /// Computes a consistent set of packages which must be present for package_name to be installed, taking
+    /// version relations, alternatives A | B | C and the installed packages into account. Every available version
+    /// of a package is considered, newest first. Already-installed alternatives are preferred; otherwise
+    /// alternatives are tried in the order they are listed.
+    /// Pre-Depends and Depends are always followed, Recommends and Suggests as policy says (but, as with apt, one which
+    /// can't be satisfied by any known package is ignored). Provides are followed, and Conflicts/Breaks between the
+    /// chosen packages are ruled out; conflicts with other installed packages are reported as removals.
+    /// Returns the packages (excluding package_name itself) and removals, or an explanation of why no solution exists.
+    pub fn transitive_dep_solution(&self, package_name: &str, policy: &RelationPolicy) -> Result<DepSolution, String> {
+        if !self.package_exists(package_name) {
+            return Ok(DepSolution { packages: vec![], removals: vec![] });
+        }
+        let package_num = *self.get_package_num(package_name);
+        let mut resolver = Resolver::new(self, policy);
+        resolver.encode(&[package_num]);
+        if !resolver.solver.solve() {
+            return Err(resolver.explain_conflict());
+        }
+        let chosen = (0..resolver.choices.len())
+            .filter(|c| resolver.solver.model_value(resolver.lit(*c)))
+            .map(|c| &resolver.choices[c])
+            .collect::<Vec<_>>();
+        let mut removals: Vec<Removal> = vec![];
+        for choice in &chosen {
+            for q in self.conflicting_installed(&choice.package) {
+                if !chosen.iter().any(|other| other.package.package_num == q) && !removals.iter().any(|r| r.package_num == q) {
+                    let replaced = self.declares_replaces(&choice.package, &self.installed_version(q).unwrap());
+                    removals.push(Removal { package_num: q, because_of: choice.package.package_num, replaced });
+                }
+            }
+        }
+        let packages = chosen.iter()
+            .filter(|choice| choice.package.package_num != package_num)
+            .map(|choice| (choice.package.package_num, if choice.installed { None } else { choice.package.candidate }))
+            .collect();
+        Ok(DepSolution { packages, removals })
+    }
+
+    /// Plans a whole-system upgrade, like apt full-upgrade: every installed package is moved to the candidate apt's
+    /// policy picks for it where possible and kept at its installed version otherwise, new packages are installed as
+    /// dependencies require, and installed packages are removed only when no consistent set keeps them. Removals
+    /// are chosen greedily: while there is no solution, the requested package closest to the conflict is given up.
+    /// Everything is encoded once; giving up a package just solves again without assuming it is kept.
+    pub fn compute_upgrade_plan(&self) -> UpgradePlan {
+        let mut requested = self.installed_debvers.keys().copied().collect::<Vec<_>>();
+        requested.sort_by(|a, b| self.get_package_name(*a).cmp(self.get_package_name(*b)));
+        let mut resolver = Resolver::new(self, &RelationPolicy::default());
+        resolver.prefer_upgrades = true;
+        resolver.encode_selectable(&requested);
+        loop {
+            let assumptions = resolver.assumptions(&requested);
+            if !resolver.solver.solve_with(&assumptions) {
+                let culprit = resolver.culprit(&requested).unwrap();
+                requested.retain(|pn| *pn != culprit);
+                continue;
+            }
+            let mut plan = UpgradePlan { upgrades: vec![], downgrades: vec![], installs: vec![], removals: vec![], kept_back: vec![] };
+            let chosen = (0..resolver.choices.len())
+                .filter(|c| resolver.solver.model_value(resolver.lit(*c)))
+                .map(|c| &resolver.choices[c])
+                .collect::<Vec<_>>();
+            for choice in &chosen {
+                let pn = choice.package.package_num;
+                match (self.installed_debvers.get(&pn), choice.installed, choice.package.candidate) {
+                    (None, _, Some(c)) => plan.installs.push(c),
+                    (Some(iv), false, Some(c)) => {
+                        if choice.package.version > iv {
+                            plan.upgrades.push((pn, c));
+                        } else {
+                            plan.downgrades.push((pn, c));
+                        }
+                    }
+                    (Some(iv), true, _) => {
+                        let newer = self.policy_candidate(pn).is_some_and(|c| self.get_candidate(c).version > *iv);
+                        if newer {
+                            plan.kept_back.push(pn);
                         }
                     }
+                    _ => ()
                 }
             }
+            plan.removals = self.installed_debvers.keys().copied()
+                .filter(|pn| !chosen.iter().any(|choice| choice.package.package_num == *pn))
+                .collect();
+            return plan;
         }
-        return dependency_set;
     }
 
     /// Computes a set of packages that need to be installed to satisfy package_name's deps given the current installed packages.
-    /// When a dependency A | B | C is unsatisfied, there are two possible cases:
-    ///   (1) there are no versions of A, B, or C installed; pick the alternative with the highest version number (yes, compare apples and oranges).
-    ///   (2) at least one of A, B, or C is installed (say A, B), but with the wrong version; of the installed packages (A, B), pick the one with the highest version number.
-    pub fn compute_how_to_install(&self, package_name: &str) -> Vec<i32> {
+    /// When a dependency A | B | C is unsatisfied, strategy decides which candidate satisfying it to pick:
+    ///   prefer-installed (the default): if at least one of A, B, or C is installed (say A, B), but with the wrong version, pick among
+    ///     the installed packages (A, B); then pick the one with the highest version number (yes, compare apples and oranges).
+    ///   newest: pick the one with the highest version number, installed or not.
+    ///   min-new, min-size: pick the one which, with what it pulls in, adds the fewest new packages or the smallest download.
+    /// Only candidates whose version satisfies the dependency are considered; pin priority comes before version,
+    /// and candidates pinned below 0 are never picked.
+    /// Which relation kinds (Pre-Depends, Depends, Recommends, Suggests) count as dependencies is up to policy.
+    /// Returns the candidates to install, excluding package_name's own.
+    #[allow(clippy::needless_return)]
+    pub fn compute_how_to_install(&self, package_name: &str, policy: &RelationPolicy, strategy: InstallStrategy) -> Vec<usize> {
         if !self.package_exists(package_name) {
             return vec![];
         }
-        let mut dependencies_to_add: Vec<i32> = vec![];
+        let root = match self.best_candidate(*self.get_package_num(package_name)) {
+            None => return vec![],
+            Some(root) => root
+        };
+        let mut dependencies_to_add = self.install_closure(root, policy, strategy, &HashSet::new());
+        dependencies_to_add.retain(|c| *c != root);
+        return dependencies_to_add;
+    }
 
-        let mut worklist: VecDeque<i32> = VecDeque::new();
+    // The candidates installing root brings in, root included, leaving out packages in planned.
+    fn install_closure(&self, root: usize, policy: &RelationPolicy, strategy: InstallStrategy, planned: &HashSet<i32>) -> Vec<usize> {
+        let mut dependencies_to_add: Vec<usize> = vec![];
+        let mut seen_packages: HashSet<i32> = planned.clone();
 
-        worklist.push_back(*self.get_package_num(package_name));
+        let mut worklist: VecDeque<usize> = VecDeque::new();
 
-        while let Some(current_package_num) = worklist.pop_front() {
-            if dependencies_to_add.contains(&current_package_num) {
+        worklist.push_back(root);
+
+        while let Some(current_candidate) = worklist.pop_front() {
+            let package_num = self.get_candidate(current_candidate).package_num;
+            if !seen_packages.insert(package_num) {
                 continue;
             }
+            let arch = self.package_arch(package_num);
 
-            dependencies_to_add.push(current_package_num);
+            dependencies_to_add.push(current_candidate);
 
-            if let Some(dependencies) = self.dependencies.get(&current_package_num) {
-                for dependency in dependencies {
-                    if self.dep_is_satisfied(dependency).is_none() {
-                        let selected_package = self.select_dependency(dependency);
-                        if let Some(package_num) = selected_package {
-                            worklist.push_back(package_num);
-                        }
+            for (_, dependency) in self.followed_relations(Some(current_candidate), policy) {
+                if self.dep_is_satisfied(dependency, arch).is_none() {
+                    let selected_package = self.select_dependency(dependency, arch, policy, strategy, &seen_packages);
+                    if let Some(candidate) = selected_package {
+                        worklist.push_back(candidate);
                     }
                 }
             }
+        }
+        dependencies_to_add
+    }
+
+    // Virtual alternatives are expanded to the packages providing them. Alternatives which would conflict with
+    // an installed package are only picked if every alternative does. The costs of min-new and min-size are
+    // estimated with the closure prefer-installed would pick, given the packages already planned.
+    fn select_dependency(&self, dep: &Dependency, from_arch: &str, policy: &RelationPolicy, strategy: InstallStrategy,
+                         planned: &HashSet<i32>) -> Option<usize> {
+        let wrong_version_packages: Vec<&str> = self.dep_satisfied_by_wrong_version(dep, from_arch);
 
-            if current_package_num == *self.get_package_num(package_name) {
-                dependencies_to_add.pop();
+        let mut eligible: Vec<Eligible> = vec![];
+        for (alternative_idx, alternative) in dep.iter().enumerate() {
+            for package_num in self.alternative_packages(alternative) {
+                let wrong_version_installed = wrong_version_packages.contains(&self.get_package_name(package_num));
+
+                for c in self.candidates_by_preference(package_num) {
+                    let av = self.available_version(c);
+                    if !self.satisfies(alternative, Some(from_arch), &av) || eligible.iter().any(|e| e.candidate == c) {
+                        continue;
+                    }
+                    let (new_packages, download_size) = match strategy {
+                        InstallStrategy::MinNew | InstallStrategy::MinSize => {
+                            let closure = self.install_closure(c, policy, InstallStrategy::PreferInstalled, planned);
+                            (closure.len(), closure.iter().map(|d| self.get_candidate(*d).size.unwrap_or(0)).sum())
+                        }
+                        _ => (0, 0)
+                    };
+                    eligible.push(Eligible {
+                        candidate: c,
+                        alternative_idx,
+                        conflicts: !self.conflicting_installed(&av).is_empty(),
+                        wrong_version_installed,
+                        priority: self.candidate_priority(c),
+                        new_packages,
+                        download_size,
+                    });
+                }
             }
         }
-        return dependencies_to_add;
+        // lower is better throughout
+        let compare = |a: &Eligible, b: &Eligible| a.conflicts.cmp(&b.conflicts)
+            .then_with(|| match strategy {
+                InstallStrategy::PreferInstalled => b.wrong_version_installed.cmp(&a.wrong_version_installed),
+                InstallStrategy::Newest => Ordering::Equal,
+                InstallStrategy::MinNew => a.new_packages.cmp(&b.new_packages),
+                InstallStrategy::MinSize => a.download_size.cmp(&b.download_size).then(a.new_packages.cmp(&b.new_packages))
+            })
+            .then_with(|| b.priority.cmp(&a.priority))
+            .then_with(|| self.get_candidate(b.candidate).version.cmp(&self.get_candidate(a.candidate).version))
+            .then_with(|| a.alternative_idx.cmp(&b.alternative_idx))
+            .then_with(|| self.get_package_name(self.get_candidate(a.candidate).package_num)
+                .cmp(self.get_package_name(self.get_candidate(b.candidate).package_num)));
+        eligible.iter().min_by(|a, b| compare(a, b)).map(|e| e.candidate)
     }
 
-    fn select_dependency(&self, dep: &Dependency) -> Option<i32> {
-        let mut best_package_num: Option<i32> = None;
-        let mut highest_version: Option<&DebianVersionNum> = None;
+    // the versioned, alternative-free Pre-Depends and Depends of candidate c, as (constrained package, kind, dependency)
+    fn version_constraints(&self, c: usize) -> Vec<(i32, RelationKind, &Dependency)> {
+        let mut result = vec![];
+        for (kind, dep) in self.followed_relations(Some(c), &RelationPolicy::default()) {
+            if let [alternative] = dep.as_slice() {
+                if alternative.rel_version.is_some() {
+                    result.push((alternative.package_num, kind, dep));
+                }
+            }
+        }
+        result
+    }
 
-        let wrong_version_packages: Vec<String> = self.dep_satisfied_by_wrong_version(dep)
-            .into_iter()
-            .map(|num| num.to_string())
-            .collect();
+    /// Checks that the versioned Pre-Depends and Depends which the candidates in planned, and the installed packages
+    /// they leave alone, place on each package can all be met by a single version. Dependencies with alternatives are
+    /// left out, since another alternative may satisfy them. Each constraint allows a single range of versions, so
+    /// when they can't all hold, two of them already contradict each other; returns a description of such a pair
+    /// for every package that has one, sorted by name.
+    pub fn contradictory_version_constraints(&self, planned: &[usize]) -> Vec<String> {
+        let planned_packages: HashSet<i32> = planned.iter().map(|c| self.get_candidate(*c).package_num).collect();
+        // constrained package -> (constraining package, kind, dependency)
+        let mut constraints: HashMap<i32, Vec<(i32, RelationKind, &Dependency)>> = HashMap::new();
+        for &c in planned {
+            for (target, kind, dep) in self.version_constraints(c) {
+                constraints.entry(target).or_default().push((self.get_candidate(c).package_num, kind, dep));
+            }
+        }
+        for (target, list) in constraints.iter_mut() {
+            for c in self.depended_on.get(target).into_iter().flatten() {
+                let q = self.get_candidate(*c).package_num;
+                let installed_here = self.installed_version(q).is_some_and(|iv| iv.candidate == Some(*c));
+                if planned_packages.contains(&q) || !installed_here {
+                    continue;
+                }
+                for (t, kind, dep) in self.version_constraints(*c) {
+                    if t == *target {
+                        list.push((q, kind, dep));
+                    }
+                }
+            }
+        }
+        let mut result = vec![];
+        let describe = |(q, kind, dep): &(i32, RelationKind, &Dependency)| {
+            format!("{} {} {}", self.get_package_name(*q), kind.verb(), self.dep2str(dep))
+        };
+        for (_, list) in constraints.iter().sorted_by(|a, b| self.get_package_name(*a.0).cmp(self.get_package_name(*b.0))) {
+            let sets = list.iter().map(|(_, _, dep)| {
+                let (op, version) = dep[0].rel_version.as_ref().unwrap();
+                // relation versions are validated when parsed
+                VersionSet::from_relation(op, &version.parse::<DebianVersionNum>().unwrap())
+            }).collect_vec();
+            let contradiction = (0..sets.len()).tuple_combinations().find(|(i, j)| sets[*i].intersect(&sets[*j]).is_empty());
+            if let Some((i, j)) = contradiction {
+                result.push(format!("{}, but {}", describe(&list[i]), describe(&list[j])));
+            }
+        }
+        result
+    }
 
-        for alternative in dep {
-            let package_num = alternative.package_num;
+    // the Installed-Size of an installed package in bytes: from the status file, or else from its Packages entry
+    fn installed_bytes(&self, package_num: i32) -> u64 {
+        let kib = match self.installed_sizes.get(&package_num) {
+            Some(kib) => Some(*kib),
+            None => self.installed_debvers.get(&package_num)
+                .and_then(|iv| self.find_candidate(package_num, iv))
+                .and_then(|c| self.get_candidate(c).installed_size)
+        };
+        kib.unwrap_or(0) * 1024
+    }
 
-            let is_wrong_version_installed = wrong_version_packages.contains(&package_num.to_string());
+    /// Returns what installing candidates (replacing the installed versions of their packages) and removing removals
+    /// would download and do to disk usage. Candidates for versions that are already installed cost nothing.
+    pub fn plan_size(&self, candidates: &[usize], removals: &[i32]) -> PlanSize {
+        let mut size = PlanSize { download: 0, disk_delta: 0 };
+        for &c in candidates {
+            let cand = self.get_candidate(c);
+            if self.installed_debvers.get(&cand.package_num) == Some(&cand.version) {
+                continue;
+            }
+            size.download += cand.size.unwrap_or(0);
+            size.disk_delta += (cand.installed_size.unwrap_or(0) * 1024) as i64 - self.installed_bytes(cand.package_num) as i64;
+        }
+        for &pn in removals {
+            size.disk_delta -= self.installed_bytes(pn) as i64;
+        }
+        size
+    }
 
-            if is_wrong_version_installed || self.available_debvers.contains_key(&package_num) {
-                if let Some(version) = self.available_debvers.get(&package_num) {
-                    if highest_version.is_none() || version > highest_version.unwrap() {
-                        best_package_num = Some(package_num);
-                        highest_version = Some(version);
-                    }
+    // the plan compute_how_to_install makes for package_name with strategy
+    fn install_plan(&self, package_name: &str, policy: &RelationPolicy, strategy: InstallStrategy) -> InstallPlan {
+        let dependencies = self.compute_how_to_install(package_name, policy, strategy);
+        let mut candidates = dependencies.clone();
+        candidates.extend(self.best_candidate(*self.get_package_num(package_name)));
+        let removals = self.removals_for(&candidates);
+        let size = self.plan_size(&candidates, &removals.iter().map(|r| r.package_num).collect_vec());
+        InstallPlan { dependencies, candidates, removals, size, strategy }
+    }
+
+    /// Plans installing package_name, which must exist. With a max_size budget (in bytes), a plan whose download is
+    /// bigger is rejected; if the min-size strategy gives one that fits, that is returned instead. Otherwise the plan
+    /// strategy makes is returned as an error.
+    pub fn plan_install(&self, package_name: &str, policy: &RelationPolicy, strategy: InstallStrategy, max_size: Option<u64>) -> Result<InstallPlan, InstallPlan> {
+        let plan = self.install_plan(package_name, policy, strategy);
+        let budget = match max_size {
+            Some(budget) if plan.size.download > budget => budget,
+            _ => return Ok(plan)
+        };
+        if strategy != InstallStrategy::MinSize {
+            let smaller = self.install_plan(package_name, policy, InstallStrategy::MinSize);
+            if smaller.size.download <= budget {
+                return Ok(smaller);
+            }
+        }
+        Err(plan)
+    }
+
+    /// Returns the installed packages which conflict with the given candidates, as removals.
+    pub fn removals_for(&self, candidates: &[usize]) -> Vec<Removal> {
+        let mut removals: Vec<Removal> = vec![];
+        for c in candidates {
+            let av = self.available_version(*c);
+            for q in self.conflicting_installed(&av) {
+                let replaced_by_plan = candidates.iter().any(|other| self.get_candidate(*other).package_num == q);
+                if !replaced_by_plan && !removals.iter().any(|r| r.package_num == q) {
+                    let replaced = self.declares_replaces(&av, &self.installed_version(q).unwrap());
+                    removals.push(Removal { package_num: q, because_of: av.package_num, replaced });
                 }
             }
         }
-        return best_package_num;
+        removals
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packages::pinning::Pin;
+    use crate::packages::{InstallStrategy, RelationPolicy};
+    use crate::Packages;
+
+    fn names(packages: &Packages, solution: &[(i32, Option<usize>)]) -> Vec<String> {
+        solution.iter().map(|(pn, c)| match c {
+            None => format!("{} (installed)", packages.get_package_name(*pn)),
+            Some(c) => format!("{} {}", packages.get_package_name(*pn), packages.get_candidate(*c).version)
+        }).collect()
+    }
+
+    fn solve(packages: &Packages, package_name: &str) -> Result<Vec<String>, String> {
+        packages.transitive_dep_solution(package_name, &RelationPolicy::default())
+            .map(|solution| names(packages, &solution.packages))
+    }
+
+    #[test]
+    fn solves_dependency_chains() {
+        let packages = Packages::from_text("\
+Package: a
+Version: 1
+Depends: b, c
+
+Package: b
+Version: 1
+Depends: c
+
+Package: c
+Version: 1
+", "");
+        assert_eq!(solve(&packages, "a").unwrap(), vec!["b 1", "c 1"]);
+        assert_eq!(solve(&packages, "c").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn explains_unsatisfiable_dependencies() {
+        let packages = Packages::from_text("\
+Package: a
+Version: 1
+Depends: b (>= 2)
+
+Package: b
+Version: 1
+", "");
+        assert_eq!(solve(&packages, "a").unwrap_err(), "\
+- a was requested
+but a (available 1) depends on b (>= 2), which no known version satisfies");
+    }
+
+    #[test]
+    fn explains_conflicts() {
+        let packages = Packages::from_text("\
+Package: a
+Version: 1
+Depends: b, c
+
+Package: b
+Version: 1
+Conflicts: c
+
+Package: c
+Version: 1
+", "");
+        assert_eq!(solve(&packages, "a").unwrap_err(), "\
+- a was requested
+- a (available 1) depends on b
+- a (available 1) depends on c
+but b (available 1) conflicts with c (available 1)");
+    }
+
+    #[test]
+    fn prefers_installed_alternatives_then_the_first() {
+        let text = "\
+Package: a
+Version: 1
+Depends: b | c
+
+Package: b
+Version: 1
+
+Package: c
+Version: 1
+";
+        assert_eq!(solve(&Packages::from_text(text, ""), "a").unwrap(), vec!["b 1"]);
+        let status = "Package: c\nStatus: install ok installed\nVersion: 1\n";
+        assert_eq!(solve(&Packages::from_text(text, status), "a").unwrap(), vec!["c (installed)"]);
+    }
+
+    #[test]
+    fn follows_version_relations_and_versioned_provides() {
+        let packages = Packages::from_text("\
+Package: a
+Version: 1
+Depends: b (<< 2), mta (>= 3)
+
+Package: b
+Version: 2
+
+Package: b
+Version: 1.5
+
+Package: b
+Version: 1
+
+Package: exim
+Version: 1
+Provides: mta (= 2)
+
+Package: postfix
+Version: 1
+Provides: mta (= 3)
+", "");
+        assert_eq!(solve(&packages, "a").unwrap(), vec!["b 1.5", "postfix 1"]);
+    }
+
+    #[test]
+    fn upgrades_what_it_can_and_removes_what_it_must() {
+        // a can be upgraded if c is installed; d can't be, and e can't stay; g and h conflict
+        let packages = Packages::from_text("\
+Package: a
+Version: 2
+Depends: c
 
+Package: c
+Version: 1
+
+Package: d
+Version: 2
+Depends: missing
+
+Package: d
+Version: 1
+
+Package: e
+Version: 1
+Depends: missing
+
+Package: g
+Version: 1
+Conflicts: h
+
+Package: h
+Version: 1
+", "\
+Package: a
+Status: install ok installed
+Version: 1
+
+Package: d
+Status: install ok installed
+Version: 1
+
+Package: e
+Status: install ok installed
+Version: 1
+
+Package: g
+Status: install ok installed
+Version: 1
+
+Package: h
+Status: install ok installed
+Version: 1
+");
+        let plan = packages.compute_upgrade_plan();
+        let upgrades = plan.upgrades.iter().map(|(pn, c)| (packages.get_package_name(*pn), packages.get_candidate(*c).version.to_string())).collect::<Vec<_>>();
+        assert_eq!(upgrades, vec![("a", String::from("2"))]);
+        assert_eq!(plan.installs.iter().map(|c| packages.get_package_name(packages.get_candidate(*c).package_num)).collect::<Vec<_>>(), vec!["c"]);
+        let mut removals = plan.removals.iter().map(|pn| packages.get_package_name(*pn)).collect::<Vec<_>>();
+        removals.sort();
+        assert_eq!(removals, vec!["e", "h"]);
+        assert_eq!(plan.kept_back.iter().map(|pn| packages.get_package_name(*pn)).collect::<Vec<_>>(), vec!["d"]);
+    }
+
+    fn how_to_install(packages: &Packages, package_name: &str, strategy: InstallStrategy) -> Vec<String> {
+        packages.compute_how_to_install(package_name, &RelationPolicy::default(), strategy).iter()
+            .map(|c| format!("{} {}", packages.get_package_name(packages.get_candidate(*c).package_num), packages.get_candidate(*c).version))
+            .collect()
+    }
+
+    #[test]
+    fn each_strategy_picks_its_own_alternative() {
+        // alpha is installed at a version too old, beta is the newest, gamma pulls in nothing else and delta's
+        // closure is the smallest download
+        let packages = Packages::from_text("\
+Package: app
+Version: 1
+Depends: alpha (>= 2) | beta | gamma | delta
+
+Package: alpha
+Version: 2
+Size: 1000
+Depends: a1, a2
+
+Package: a1
+Version: 1
+Size: 1000
+
+Package: a2
+Version: 1
+Size: 1000
+
+Package: beta
+Version: 9
+Size: 100
+Depends: b1
+
+Package: b1
+Version: 1
+Size: 10000
+
+Package: gamma
+Version: 1
+Size: 5000
+
+Package: delta
+Version: 1
+Size: 100
+Depends: d1
+
+Package: d1
+Version: 1
+Size: 100
+", "Package: alpha\nStatus: install ok installed\nVersion: 1\n");
+        assert_eq!(how_to_install(&packages, "app", InstallStrategy::PreferInstalled), vec!["alpha 2", "a1 1", "a2 1"]);
+        assert_eq!(how_to_install(&packages, "app", InstallStrategy::Newest), vec!["beta 9", "b1 1"]);
+        assert_eq!(how_to_install(&packages, "app", InstallStrategy::MinNew), vec!["gamma 1"]);
+        assert_eq!(how_to_install(&packages, "app", InstallStrategy::MinSize), vec!["delta 1", "d1 1"]);
+    }
+
+    #[test]
+    fn breaks_ties_by_priority_version_alternative_order_and_name() {
+        let mut packages = Packages::from_text("\
+Package: by-order
+Version: 1
+Depends: x | y
+
+Package: by-version
+Version: 1
+Depends: y | z
+
+Package: by-name
+Version: 1
+Depends: mta
+
+Package: by-priority
+Version: 1
+Depends: z | y
+
+Package: x
+Version: 1
+
+Package: y
+Version: 1
+
+Package: z
+Version: 2
+
+Package: postfix
+Version: 1
+Provides: mta
+
+Package: exim
+Version: 1
+Provides: mta
+", "");
+        for strategy in [InstallStrategy::PreferInstalled, InstallStrategy::Newest, InstallStrategy::MinNew, InstallStrategy::MinSize] {
+            assert_eq!(how_to_install(&packages, "by-order", strategy), vec!["x 1"]);
+            assert_eq!(how_to_install(&packages, "by-version", strategy), vec!["z 2"]);
+            assert_eq!(how_to_install(&packages, "by-name", strategy), vec!["exim 1"]);
+            assert_eq!(how_to_install(&packages, "by-priority", strategy), vec!["z 2"]);
+        }
+        packages.add_pin(Pin::parse("y", "version 1", "600").unwrap());
+        for strategy in [InstallStrategy::PreferInstalled, InstallStrategy::Newest, InstallStrategy::MinNew, InstallStrategy::MinSize] {
+            assert_eq!(how_to_install(&packages, "by-priority", strategy), vec!["y 1"]);
+        }
+    }
+
+    // app needs big or small, small needs lib; old is upgraded, same is installed at its available version and gone
+    // is installed but no longer available
+    fn sized_packages() -> Packages {
+        Packages::from_text("\
+Package: app
+Version: 1
+Size: 1000
+Installed-Size: 10
+Depends: big | small
+
+Package: big
+Version: 1
+Size: 50000
+Installed-Size: 100
+
+Package: small
+Version: 1
+Size: 2000
+Installed-Size: 20
+Depends: lib
+
+Package: lib
+Version: 1
+Size: 500
+Installed-Size: 5
+
+Package: old
+Version: 2
+Size: 300
+Installed-Size: 40
+
+Package: same
+Version: 1
+Size: 600
+Installed-Size: 6
+", "\
+Package: old
+Status: install ok installed
+Version: 1
+Installed-Size: 30
+
+Package: same
+Status: install ok installed
+Version: 1
+Installed-Size: 6
+
+Package: gone
+Status: install ok installed
+Version: 1
+Installed-Size: 7
+")
+    }
+
+    #[test]
+    fn sizes_plans_leaving_out_installed_versions() {
+        let packages = sized_packages();
+        let candidate = |name: &str| packages.best_candidate(*packages.get_package_num(name)).unwrap();
+        let size = packages.plan_size(&[candidate("app"), candidate("old"), candidate("same")], &[*packages.get_package_num("gone")]);
+        assert_eq!(size.download, 1000 + 300);
+        assert_eq!(size.disk_delta, (10 + (40 - 30) - 7) * 1024);
+    }
+
+    #[test]
+    fn falls_back_to_the_smallest_plan_within_max_size() {
+        let packages = sized_packages();
+        let plan = |max_size| match packages.plan_install("app", &RelationPolicy::default(), InstallStrategy::PreferInstalled, max_size) {
+            Ok(plan) => Ok((plan.dependencies.iter().map(|c| packages.get_package_name(packages.get_candidate(*c).package_num)).collect::<Vec<_>>(),
+                            plan.size.download, plan.strategy == InstallStrategy::MinSize)),
+            Err(plan) => Err(plan.size.download)
+        };
+        assert_eq!(plan(None), Ok((vec!["big"], 51000, false)));
+        assert_eq!(plan(Some(51000)), Ok((vec!["big"], 51000, false)));
+        assert_eq!(plan(Some(10000)), Ok((vec!["small", "lib"], 3500, true)));
+        assert_eq!(plan(Some(3000)), Err(51000));
+    }
+
+    #[test]
+    fn follows_recommends_and_suggests_as_the_policy_says() {
+        let packages = Packages::from_text("\
+Package: app
+Version: 1
+Pre-Depends: pre
+Depends: lib
+Recommends: rec
+Suggests: sug
+
+Package: pre
+Version: 1
+
+Package: lib
+Version: 1
+
+Package: rec
+Version: 1
+Depends: recdep
+
+Package: recdep
+Version: 1
+
+Package: sug
+Version: 1
+", "");
+        let policy = |recommends, suggests| RelationPolicy { recommends, suggests };
+        let solve_with = |policy: &RelationPolicy| {
+            let mut solution = names(&packages, &packages.transitive_dep_solution("app", policy).unwrap().packages);
+            solution.sort();
+            solution
+        };
+        let install_with = |policy: &RelationPolicy| {
+            let mut plan = packages.compute_how_to_install("app", policy, InstallStrategy::default()).iter()
+                .map(|c| packages.get_package_name(packages.get_candidate(*c).package_num))
+                .collect::<Vec<_>>();
+            plan.sort();
+            plan
+        };
+        assert_eq!(solve_with(&policy(false, false)), vec!["lib 1", "pre 1"]);
+        assert_eq!(solve_with(&policy(true, false)), vec!["lib 1", "pre 1", "rec 1", "recdep 1"]);
+        assert_eq!(solve_with(&policy(false, true)), vec!["lib 1", "pre 1", "sug 1"]);
+        assert_eq!(solve_with(&policy(true, true)), vec!["lib 1", "pre 1", "rec 1", "recdep 1", "sug 1"]);
+        assert_eq!(install_with(&policy(false, false)), vec!["lib", "pre"]);
+        assert_eq!(install_with(&policy(true, false)), vec!["lib", "pre", "rec", "recdep"]);
+        assert_eq!(install_with(&policy(false, true)), vec!["lib", "pre", "sug"]);
+        assert_eq!(install_with(&policy(true, true)), vec!["lib", "pre", "rec", "recdep", "sug"]);
+    }
+
+    #[test]
+    fn ignores_recommends_and_suggests_nothing_satisfies() {
+        let packages = Packages::from_text("\
+Package: app
+Version: 1
+Recommends: missing, rec
+Suggests: nothere (>= 2)
+
+Package: rec
+Version: 1
+
+Package: nothere
+Version: 1
+", "");
+        let solution = |recommends, suggests| names(&packages,
+            &packages.transitive_dep_solution("app", &RelationPolicy { recommends, suggests }).unwrap().packages);
+        assert_eq!(solution(false, false), Vec::<String>::new());
+        assert_eq!(solution(true, false), vec!["rec 1"]);
+        assert_eq!(solution(false, true), Vec::<String>::new());
+        assert_eq!(solution(true, true), vec!["rec 1"]);
+    }
 }
@@ -0,0 +1,303 @@
+// Aggregate figures about the dependency graph of everything loaded (see graph.rs), following
+// Pre-Depends and Depends.
+//
+// The strongly connected components of the graph (from petgraph's kosaraju_scc, which isn't
+// recursive and so copes with the whole archive) are its dependency cycles, and they come out
+// dependencies first. Collapsing each into one node leaves a DAG, over which the longest chain
+// starting at each component is computed in that order, and the packages depending on each
+// component, transitively, are counted by a search along the reversed edges.
+
+use std::collections::VecDeque;
+
+use itertools::Itertools;
+use petgraph::algo::kosaraju_scc;
+use petgraph::graph::NodeIndex;
+
+use crate::packages::graph::DependencyGraph;
+use crate::packages::{Dependency, RelationKind, RelationPolicy};
+use crate::Packages;
+
+// how many entries the stats subcommands print unless told otherwise
+pub const STATS_LIMIT: usize = 10;
+
+// the dependency graph collapsed into its strongly connected components
+struct Components {
+    members : Vec<Vec<i32>>, // component -> its packages, sorted by name; dependencies come before their dependers
+    component : Vec<usize>, // node -> its component
+    dependencies : Vec<Vec<usize>>, // component -> the other components it has edges to
+}
+
+impl Packages {
+    fn components(&self, graph: &DependencyGraph) -> Components {
+        let sccs = kosaraju_scc(&graph.graph);
+        let mut component = vec![0; graph.graph.node_count()];
+        for (k, scc) in sccs.iter().enumerate() {
+            for n in scc {
+                component[n.index()] = k;
+            }
+        }
+        let mut dependencies: Vec<Vec<usize>> = vec![vec![]; sccs.len()];
+        for edge in graph.graph.raw_edges() {
+            let (from, to) = (component[edge.source().index()], component[edge.target().index()]);
+            if from != to && !dependencies[from].contains(&to) {
+                dependencies[from].push(to);
+            }
+        }
+        let members = sccs.iter().map(|scc| scc.iter()
+            .map(|n| graph.graph[*n])
+            .sorted_by(|a, b| self.get_package_name(*a).cmp(self.get_package_name(*b)))
+            .collect_vec()).collect_vec();
+        Components { members, component, dependencies }
+    }
+
+    fn component2str(&self, members: &[i32]) -> String {
+        let names = members.iter().map(|p| self.get_package_name(*p)).collect_vec();
+        if names.len() == 1 { names[0].to_string() } else { format!("{{{}}}", names.join(", ")) }
+    }
+
+    /// Returns the dependency cycles: the strongly connected components of more than one package, largest first,
+    /// each sorted by name.
+    pub fn dependency_cycles(&self, graph: &DependencyGraph) -> Vec<Vec<i32>> {
+        self.components(graph).members.into_iter()
+            .filter(|members| members.len() > 1)
+            .sorted_by(|a, b| b.len().cmp(&a.len()).then_with(|| self.get_package_name(a[0]).cmp(self.get_package_name(b[0]))))
+            .collect_vec()
+    }
+
+    /// Returns every package with the number of other packages which depend on it, directly or transitively, most
+    /// depended upon first (and then by name).
+    pub fn reverse_closure_sizes(&self, graph: &DependencyGraph) -> Vec<(i32, usize)> {
+        let components = self.components(graph);
+        let mut dependers: Vec<Vec<usize>> = vec![vec![]; components.members.len()];
+        for (k, dependencies) in components.dependencies.iter().enumerate() {
+            for d in dependencies {
+                dependers[*d].push(k);
+            }
+        }
+        // visited[k] == c + 1 once component k was counted for component c, so the vector needn't be cleared
+        let mut visited = vec![0; components.members.len()];
+        let mut result = vec![];
+        for (c, members) in components.members.iter().enumerate() {
+            let mut count = members.len() - 1;
+            visited[c] = c + 1;
+            let mut worklist = VecDeque::from([c]);
+            while let Some(k) = worklist.pop_front() {
+                for &d in &dependers[k] {
+                    if visited[d] != c + 1 {
+                        visited[d] = c + 1;
+                        count += components.members[d].len();
+                        worklist.push_back(d);
+                    }
+                }
+            }
+            result.extend(members.iter().map(|p| (*p, count)));
+        }
+        result.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| self.get_package_name(a.0).cmp(self.get_package_name(b.0))));
+        result
+    }
+
+    /// Returns the limit longest chains of dependencies, longest first (and then by the name of the package they
+    /// start at), one per starting package. A chain lists the packages it goes through; a dependency cycle on the way
+    /// counts as one step, so its packages are listed together.
+    pub fn longest_chains(&self, graph: &DependencyGraph, limit: usize) -> Vec<Vec<Vec<i32>>> {
+        let components = self.components(graph);
+        // the number of components on the longest chain from each component, and the next one on it
+        let mut depth = vec![0; components.members.len()];
+        let mut next: Vec<Option<usize>> = vec![None; components.members.len()];
+        for k in 0..components.members.len() {
+            for &d in &components.dependencies[k] {
+                let longer = depth[d] > depth[k] || (depth[d] == depth[k] && next[k].is_some_and(|n|
+                    self.get_package_name(components.members[d][0]) < self.get_package_name(components.members[n][0])));
+                if longer {
+                    depth[k] = depth[d];
+                    next[k] = Some(d);
+                }
+            }
+            depth[k] += 1;
+        }
+        let starts = graph.graph.node_indices()
+            .map(|n: NodeIndex| (graph.graph[n], components.component[n.index()]))
+            .sorted_by(|a, b| depth[b.1].cmp(&depth[a.1]).then_with(|| self.get_package_name(a.0).cmp(self.get_package_name(b.0))))
+            .take(limit);
+        starts.map(|(p, k)| {
+            let mut chain = vec![];
+            // the starting package first, then the rest of its cycle, if it is in one
+            chain.push(std::iter::once(p).chain(components.members[k].iter().copied().filter(|q| *q != p)).collect_vec());
+            let mut k = next[k];
+            while let Some(d) = k {
+                chain.push(components.members[d].clone());
+                k = next[d];
+            }
+            chain
+        }).collect_vec()
+    }
+
+    /// Returns the Pre-Depends and Depends of the best candidate of each available package that no available
+    /// candidate satisfies, sorted by package name. Installed packages don't count: these are broken in the archive.
+    pub fn archive_unsatisfiable(&self) -> Vec<(i32, RelationKind, &Dependency)> {
+        let mut result = vec![];
+        for &p in self.available.keys().sorted_by(|a, b| self.get_package_name(**a).cmp(self.get_package_name(**b))) {
+            let arch = self.package_arch(p);
+            for (kind, dep) in self.followed_relations(self.best_candidate(p), &RelationPolicy::default()) {
+                let satisfiable = dep.iter().any(|alternative| self.alternative_packages(alternative).into_iter()
+                    .any(|q| self.get_candidates(q).iter().any(|c| self.satisfies(alternative, Some(arch), &self.available_version(*c)))));
+                if !satisfiable {
+                    result.push((p, kind, dep));
+                }
+            }
+        }
+        result
+    }
+
+    /// Prints a summary of the dependency graph, or with a subcommand (cycles, most-depended, depth or unsatisfiable),
+    /// the first limit entries of one of the lists above.
+    pub fn print_stats(&self, subcommand: Option<&str>, limit: usize) {
+        let graph = self.dependencies(&RelationPolicy::default());
+        match subcommand {
+            None => {
+                let cycles = self.dependency_cycles(&graph);
+                println!("Packages: {}", graph.graph.node_count());
+                println!("Dependency edges: {}", graph.graph.edge_count());
+                println!("Dependency cycles: {} (largest: {} packages)", cycles.len(), cycles.first().map_or(0, |c| c.len()));
+                if let Some((p, count)) = self.reverse_closure_sizes(&graph).first() {
+                    println!("Most depended upon: {} ({} packages)", self.get_package_name(*p), count);
+                }
+                if let Some(chain) = self.longest_chains(&graph, 1).first() {
+                    println!("Longest dependency chain: {} steps, from {}", chain.len(), self.get_package_name(chain[0][0]));
+                }
+                println!("Packages with unsatisfiable dependencies: {}", self.archive_unsatisfiable().iter().map(|(p, _, _)| p).unique().count());
+            }
+            Some("cycles") => {
+                for cycle in self.dependency_cycles(&graph).iter().take(limit) {
+                    println!("  {} packages: {}", cycle.len(), cycle.iter().map(|p| self.get_package_name(*p)).format(", "));
+                }
+            }
+            Some("most-depended") => {
+                for (p, count) in self.reverse_closure_sizes(&graph).iter().take(limit) {
+                    println!("  {} ({} packages)", self.get_package_name(*p), count);
+                }
+            }
+            Some("depth") => {
+                for chain in self.longest_chains(&graph, limit) {
+                    println!("  {} ({}): {}", self.get_package_name(chain[0][0]), chain.len(),
+                             chain.iter().map(|members| self.component2str(members)).format(" -> "));
+                }
+            }
+            Some("unsatisfiable") => {
+                for (p, kind, dep) in self.archive_unsatisfiable().iter().take(limit) {
+                    println!("  {} {} {}", self.get_package_name(*p), kind.verb(), self.dep2str(dep));
+                }
+            }
+            Some(other) => println!("stats: unknown subcommand {} (cycles, most-depended, depth or unsatisfiable)", other)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packages::RelationPolicy;
+    use crate::Packages;
+
+    // top -> mid -> {x, y, z} -> base, a separate cycle {p, q}, and dependencies nothing satisfies
+    const PACKAGES: &str = "\
+Package: top
+Version: 1
+Depends: mid
+
+Package: mid
+Version: 1
+Depends: x
+
+Package: x
+Version: 1
+Depends: y, base
+
+Package: y
+Version: 1
+Depends: z
+
+Package: z
+Version: 1
+Depends: x
+
+Package: base
+Version: 1
+
+Package: p
+Version: 1
+Depends: q
+
+Package: q
+Version: 1
+Pre-Depends: p
+
+Package: broken
+Version: 1
+Depends: missing | gone (>= 2)
+
+Package: too-new
+Version: 1
+Depends: base (>= 5)
+
+Package: gone
+Version: 1
+";
+
+    // an installed package no Packages file lists, whose dependency isn't available either
+    const STATUS: &str = "\
+Package: local
+Status: install ok installed
+Version: 1
+Depends: missing
+";
+
+    fn names(packages: &Packages, members: &[i32]) -> Vec<String> {
+        members.iter().map(|p| String::from(packages.get_package_name(*p))).collect()
+    }
+
+    #[test]
+    fn finds_cycles_largest_first() {
+        let packages = Packages::from_text(PACKAGES, STATUS);
+        let graph = packages.dependencies(&RelationPolicy::default());
+        let cycles = packages.dependency_cycles(&graph).iter().map(|cycle| names(&packages, cycle)).collect::<Vec<_>>();
+        assert_eq!(cycles, vec![vec!["x", "y", "z"], vec!["p", "q"]]);
+    }
+
+    #[test]
+    fn counts_transitive_reverse_dependencies() {
+        let packages = Packages::from_text(PACKAGES, STATUS);
+        let graph = packages.dependencies(&RelationPolicy::default());
+        let sizes = packages.reverse_closure_sizes(&graph).into_iter()
+            .map(|(p, count)| (packages.get_package_name(p), count))
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![("base", 5), ("x", 4), ("y", 4), ("z", 4), ("mid", 1), ("p", 1), ("q", 1),
+                               ("broken", 0), ("gone", 0), ("local", 0), ("too-new", 0), ("top", 0)]);
+    }
+
+    #[test]
+    fn finds_the_longest_chains_through_cycles() {
+        let packages = Packages::from_text(PACKAGES, STATUS);
+        let graph = packages.dependencies(&RelationPolicy::default());
+        let chains = packages.longest_chains(&graph, 5).iter()
+            .map(|chain| chain.iter().map(|members| names(&packages, members)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(chains, vec![
+            vec![vec!["top"], vec!["mid"], vec!["x", "y", "z"], vec!["base"]],
+            vec![vec!["mid"], vec!["x", "y", "z"], vec!["base"]],
+            // each package of a cycle starts a chain of its own, with the rest of the cycle after it
+            vec![vec!["x", "y", "z"], vec!["base"]],
+            vec![vec!["y", "x", "z"], vec!["base"]],
+            vec![vec!["z", "x", "y"], vec!["base"]],
+        ]);
+        assert_eq!(packages.longest_chains(&graph, 1).len(), 1);
+    }
+
+    #[test]
+    fn lists_dependencies_the_archive_cant_satisfy() {
+        let packages = Packages::from_text(PACKAGES, STATUS);
+        let unsatisfiable = packages.archive_unsatisfiable().into_iter()
+            .map(|(p, kind, dep)| format!("{} {} {}", packages.get_package_name(p), kind.verb(), packages.dep2str(dep)))
+            .collect::<Vec<_>>();
+        assert_eq!(unsatisfiable, vec!["broken depends on missing | gone (>= 2)", "too-new depends on base (>= 5)"]);
+    }
+}
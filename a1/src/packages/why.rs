@@ -0,0 +1,475 @@
+// Explaining why a package can't be installed, and why one is installed.
+//
+// A candidate can be installed if each of its Pre-Depends and Depends is satisfied by an installed
+// package, or by some candidate which can itself be installed. why_not follows the dependencies that
+// fail this test down to their causes (no such package, no version satisfying the relation, or a
+// candidate which fails in turn) and returns them as a tree rooted at the requested package. Whether
+// the package can be installed at all is up to the resolver of transitive-dep-solution, though, so
+// when the dependencies aren't what stops it (a Conflicts or Breaks is), its explanation is given.
+//
+// why_paths goes the other way: a breadth-first search from the manually installed packages (or from
+// a how-to-install target) along dependency edges, remembering every predecessor at the shortest
+// distance, finds the shortest chains of dependencies that lead to a package.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use itertools::Itertools;
+
+use crate::packages::{Dependency, InstallStrategy, RelationKind, RelationPolicy};
+use crate::Packages;
+
+/// A statement in an explanation, with the statements that lead to it.
+pub struct Derivation {
+    pub line : String,
+    pub causes : Vec<Derivation>,
+}
+
+impl Derivation {
+    fn leaf(line: String) -> Derivation {
+        Derivation { line, causes: vec![] }
+    }
+}
+
+/// One edge of a dependency chain: package_num has dependency dep in its kind field.
+#[derive(Clone, Copy)]
+pub struct Step<'a> {
+    pub package_num : i32,
+    pub kind : RelationKind,
+    pub dep : &'a Dependency,
+}
+
+// how many of the shortest chains why prints
+const WHY_LIMIT: usize = 10;
+
+struct WhyNot<'a> {
+    packages : &'a Packages,
+    policy : RelationPolicy,
+    installable : HashMap<usize, bool>, // candidates already explained
+    on_path : HashSet<usize>, // candidates being explained; one reached again through a cycle counts as installable
+}
+
+impl<'a> WhyNot<'a> {
+    fn cand2str(&self, c: usize) -> String {
+        let cand = self.packages.get_candidate(c);
+        format!("{} {}", self.packages.get_package_name(cand.package_num), cand.version)
+    }
+
+    // None if candidate c can be installed, or else why not
+    fn candidate(&mut self, c: usize) -> Option<Derivation> {
+        if self.on_path.contains(&c) {
+            return None;
+        }
+        if let Some(installable) = self.installable.get(&c) {
+            return match installable {
+                true => None,
+                false => Some(Derivation::leaf(format!("{} can't be installed (see above)", self.cand2str(c))))
+            };
+        }
+        self.on_path.insert(c);
+        let packages = self.packages;
+        let mut causes = vec![];
+        for (kind, dep) in packages.followed_relations(Some(c), &self.policy) {
+            if let Some(cause) = self.dependency(c, kind, dep) {
+                causes.push(cause);
+            }
+        }
+        self.on_path.remove(&c);
+        self.installable.insert(c, causes.is_empty());
+        if causes.is_empty() {
+            return None;
+        }
+        Some(Derivation { line: format!("{} can't be installed, because:", self.cand2str(c)), causes })
+    }
+
+    // None if dependency dep of candidate c can be satisfied, or else why not
+    fn dependency(&mut self, c: usize, kind: RelationKind, dep: &Dependency) -> Option<Derivation> {
+        let packages = self.packages;
+        let arch = packages.package_arch(packages.get_candidate(c).package_num);
+        if packages.dep_is_satisfied(dep, arch).is_some() {
+            return None;
+        }
+        let mut causes = vec![];
+        for alternative in dep {
+            let alternative_packages = packages.alternative_packages(alternative);
+            if alternative_packages.is_empty() {
+                causes.push(Derivation::leaf(format!("no package named {} is known", packages.dep2str(std::slice::from_ref(alternative)))));
+                continue;
+            }
+            for q in alternative_packages {
+                let mut rejected = vec![];
+                if let Some(iv) = packages.installed_version(q) {
+                    rejected.push(format!("{} (installed)", iv.version));
+                }
+                let mut satisfying = 0;
+                for cand in packages.candidates_by_preference(q) {
+                    if !packages.satisfies(alternative, Some(arch), &packages.available_version(cand)) {
+                        rejected.push(packages.get_candidate(cand).version.to_string());
+                        continue;
+                    }
+                    satisfying += 1;
+                    match self.candidate(cand) {
+                        None => return None,
+                        Some(cause) => causes.push(cause)
+                    }
+                }
+                if satisfying == 0 {
+                    causes.push(Derivation::leaf(format!("{} isn't satisfied by {} {}", packages.dep2str(std::slice::from_ref(alternative)),
+                        packages.get_package_name(q), rejected.iter().format(" or "))));
+                }
+            }
+        }
+        Some(Derivation { line: format!("{} {} {}", self.cand2str(c), kind.verb(), packages.dep2str(dep)), causes })
+    }
+}
+
+impl Packages {
+    /// Returns the reasons why no candidate of package_num can be installed, following Pre-Depends and Depends, or
+    /// nothing if one can. If there is just one candidate, its reasons are returned directly.
+    pub fn unsatisfiable_dependencies(&self, package_num: i32) -> Vec<Derivation> {
+        let candidates = self.candidates_by_preference(package_num);
+        if candidates.is_empty() {
+            return vec![Derivation::leaf(format!("no installable version of {} is known", self.get_package_name(package_num)))];
+        }
+        let mut why_not = WhyNot { packages: self, policy: RelationPolicy::default(), installable: HashMap::new(), on_path: HashSet::new() };
+        let mut causes = vec![];
+        for &c in &candidates {
+            match why_not.candidate(c) {
+                None => return vec![],
+                Some(cause) => causes.push(cause)
+            }
+        }
+        if causes.len() == 1 {
+            return causes.pop().unwrap().causes;
+        }
+        causes
+    }
+
+    /// Explains why package_num can't be installed, or returns None if it can, as transitive_dep_solution decides:
+    /// either no candidate's dependencies can be satisfied (see unsatisfiable_dependencies), or the versioned
+    /// dependencies of the packages how-to-install would pick contradict each other or those of installed packages,
+    /// or else whatever the resolver found, such as packages that would have to be chosen but conflict.
+    pub fn why_not(&self, package_num: i32) -> Option<Derivation> {
+        let package_name = self.get_package_name(package_num);
+        let explanation = match self.transitive_dep_solution(package_name, &RelationPolicy::default()) {
+            Ok(_) => return None,
+            Err(explanation) => explanation
+        };
+        let line = format!("{} can't be installed, because:", package_name);
+        let mut causes = self.unsatisfiable_dependencies(package_num);
+        if causes.is_empty() {
+            let mut plan = self.compute_how_to_install(package_name, &RelationPolicy::default(), InstallStrategy::default());
+            plan.extend(self.best_candidate(package_num));
+            causes = self.contradictory_version_constraints(&plan).into_iter().map(Derivation::leaf).collect_vec();
+        }
+        if causes.is_empty() {
+            causes = explanation.lines()
+                .map(|l| Derivation::leaf(String::from(l.strip_prefix("- ").unwrap_or(l))))
+                .collect_vec();
+        }
+        Some(Derivation { line, causes })
+    }
+
+    // prints the causes of a derivation as a tree below its line, each level indented under prefix
+    fn print_derivation(&self, derivation: &Derivation, prefix: &str) {
+        for (i, cause) in derivation.causes.iter().enumerate() {
+            let last = i + 1 == derivation.causes.len();
+            println!("{}{}{}", prefix, if last { "└─ " } else { "├─ " }, cause.line);
+            self.print_derivation(cause, &format!("{}{}", prefix, if last { "   " } else { "│  " }));
+        }
+    }
+
+    // the packages that dependencies of p's version in the graph (the plan's candidate, or else the installed version)
+    // lead to: installed packages satisfying them, or only packages in plan if there is one
+    fn dependency_edges(&self, p: i32, plan: Option<&HashMap<i32, usize>>, policy: &RelationPolicy) -> Vec<(i32, Step<'_>)> {
+        let version = match plan.and_then(|plan| plan.get(&p)) {
+            Some(c) => Some(self.available_version(*c)),
+            None => self.installed_version(p)
+        };
+        let mut result = vec![];
+        let version = match version {
+            None => return result,
+            Some(version) => version
+        };
+        let arch = self.package_arch(p);
+        for (kind, dep) in self.followed_relations(version.candidate, policy) {
+            for alternative in dep {
+                for q in self.alternative_packages(alternative) {
+                    let qv = match plan {
+                        Some(plan) => plan.get(&q).map(|c| self.available_version(*c)),
+                        None => self.installed_version(q)
+                    };
+                    if qv.is_some_and(|qv| self.satisfies(alternative, Some(arch), &qv)) && !result.iter().any(|(r, _)| *r == q) {
+                        result.push((q, Step { package_num: p, kind, dep }));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the shortest chains of dependencies leading from one of roots to goal, at most limit of them. Each
+    /// chain lists its steps in order, starting at a root. With a plan (package to candidate), only packages in the
+    /// plan are followed, through the dependencies of their planned candidates; otherwise installed packages are,
+    /// through those of their installed versions.
+    pub fn why_paths(&self, roots: &[i32], goal: i32, plan: Option<&HashMap<i32, usize>>, policy: &RelationPolicy, limit: usize) -> Vec<Vec<Step<'_>>> {
+        let mut distance: HashMap<i32, usize> = roots.iter().map(|r| (*r, 0)).collect();
+        // package -> the steps reaching it from packages one closer to a root
+        let mut predecessors: HashMap<i32, Vec<Step>> = HashMap::new();
+        let mut worklist: VecDeque<i32> = roots.iter().copied().collect();
+        while let Some(p) = worklist.pop_front() {
+            if p == goal {
+                break;
+            }
+            for (q, step) in self.dependency_edges(p, plan, policy) {
+                match distance.get(&q) {
+                    None => {
+                        distance.insert(q, distance[&p] + 1);
+                        worklist.push_back(q);
+                    }
+                    Some(d) if *d == distance[&p] + 1 => (),
+                    Some(_) => continue
+                }
+                predecessors.entry(q).or_default().push(step);
+            }
+        }
+        // walk the predecessors back from goal; partial chains are reversed
+        let mut result = vec![];
+        let mut partial: Vec<(i32, Vec<Step>)> = vec![(goal, vec![])];
+        while let Some((p, steps)) = partial.pop() {
+            if result.len() == limit {
+                break;
+            }
+            if distance.get(&p) == Some(&0) {
+                result.push(steps.into_iter().rev().collect_vec());
+                continue;
+            }
+            for step in predecessors.get(&p).into_iter().flatten().rev() {
+                let mut longer = steps.clone();
+                longer.push(*step);
+                partial.push((step.package_num, longer));
+            }
+        }
+        result
+    }
+
+    fn print_why_paths(&self, chains: &[Vec<Step>]) {
+        for chain in chains {
+            println!("  {}", chain.iter()
+                .map(|step| format!("{} {} {}", self.get_package_name(step.package_num), step.kind.verb(), self.dep2str(step.dep)))
+                .format(", "));
+        }
+    }
+
+    /// Prints the shortest chains of dependencies that pull package_name in: from the packages that were installed
+    /// manually (not marked Auto-Installed) to an installed package, or from target to a package how-to-install
+    /// would install for it.
+    pub fn print_why(&self, package_name: &str, target: Option<&str>, policy: &RelationPolicy) {
+        for name in std::iter::once(package_name).chain(target) {
+            if !self.package_exists(name) {
+                println!("no such package {}", name);
+                return;
+            }
+        }
+        let package_num = *self.get_package_num(package_name);
+        let chains = match target {
+            None => {
+                if !self.installed_debvers.contains_key(&package_num) {
+                    println!("{} is not installed", package_name);
+                    return;
+                }
+                if !self.auto_installed.contains(&package_num) {
+                    println!("{} was installed manually (it isn't marked Auto-Installed)", package_name);
+                    return;
+                }
+                let roots = self.installed_debvers.keys().copied().filter(|p| !self.auto_installed.contains(p)).collect_vec();
+                let chains = self.why_paths(&roots, package_num, None, policy, WHY_LIMIT);
+                if chains.is_empty() {
+                    println!("nothing installed manually needs {}", package_name);
+                    return;
+                }
+                chains
+            }
+            Some(target) => {
+                if target == package_name {
+                    println!("{} is the package being installed", package_name);
+                    return;
+                }
+                let mut plan: HashMap<i32, usize> = HashMap::new();
+                let target_num = *self.get_package_num(target);
+                for c in self.compute_how_to_install(target, policy, InstallStrategy::default()).into_iter().chain(self.best_candidate(target_num)) {
+                    plan.insert(self.get_candidate(c).package_num, c);
+                }
+                let chains = self.why_paths(&[target_num], package_num, Some(&plan), policy, WHY_LIMIT);
+                if chains.is_empty() {
+                    println!("installing {} doesn't install {}", target, package_name);
+                    return;
+                }
+                chains
+            }
+        };
+        println!("{} is needed because:", package_name);
+        self.print_why_paths(&chains);
+    }
+
+    pub fn print_why_not(&self, package_name: &str) {
+        if !self.package_exists(package_name) {
+            println!("no such package {}", package_name);
+            return;
+        }
+        match self.why_not(*self.get_package_num(package_name)) {
+            None => println!("{} can be installed", package_name),
+            Some(derivation) => {
+                println!("{}", derivation.line);
+                self.print_derivation(&derivation, "");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WHY_LIMIT;
+    use crate::packages::RelationPolicy;
+    use crate::Packages;
+
+    fn lines(packages: &Packages, package_name: &str) -> Option<Vec<String>> {
+        packages.why_not(*packages.get_package_num(package_name))
+            .map(|derivation| derivation.causes.iter().map(|cause| cause.line.clone()).collect())
+    }
+
+    #[test]
+    fn traces_unsatisfiable_dependencies() {
+        let packages = Packages::from_text("\
+Package: app
+Version: 1.0
+Depends: lib (>= 3.0), nothere
+", "");
+        assert_eq!(lines(&packages, "app").unwrap(), vec!["app 1.0 depends on lib (>= 3.0)", "app 1.0 depends on nothere"]);
+    }
+
+    #[test]
+    fn explains_conflicts_as_the_resolver_does() {
+        let text = "\
+Package: app
+Version: 1.0
+Depends: mid, lib
+
+Package: mid
+Version: 1.0
+Breaks: lib (<< 2.0)
+
+Package: lib
+Version: 1.5
+";
+        let packages = Packages::from_text(text, "");
+        assert!(packages.transitive_dep_solution("app", &RelationPolicy::default()).is_err());
+        assert_eq!(lines(&packages, "app").unwrap(), vec![
+            "app was requested",
+            "app (available 1.0) depends on mid",
+            "app (available 1.0) depends on lib",
+            "but mid (available 1.0) conflicts with lib (available 1.5)",
+        ]);
+        // once a version of lib that mid doesn't break is available, both agree that app can be installed
+        let packages = Packages::from_text(&format!("{}\nPackage: lib\nVersion: 2.0\n", text), "");
+        assert!(packages.transitive_dep_solution("app", &RelationPolicy::default()).is_ok());
+        assert!(lines(&packages, "app").is_none());
+    }
+
+    // app and hub were installed manually, everything else to satisfy them; unused isn't needed by anything
+    fn installed() -> Packages {
+        let mut status = String::from("\
+Package: app
+Status: install ok installed
+Version: 1
+Depends: libz | liby, tool, longer
+
+Package: libz
+Status: install ok installed
+Version: 1
+Pre-Depends: libc
+
+Package: liby
+Status: install ok installed
+Version: 1
+Depends: libc
+
+Package: tool
+Status: install ok installed
+Version: 1
+Depends: libc (>= 2)
+
+Package: longer
+Status: install ok installed
+Version: 1
+Depends: longest
+
+Package: longest
+Status: install ok installed
+Version: 1
+Depends: libc
+
+Package: libc
+Status: install ok installed
+Version: 2
+
+Package: unused
+Status: install ok installed
+Version: 1
+
+Package: hub
+Status: install ok installed
+Version: 1
+Depends: ");
+        status.push_str(&(1..=12).map(|i| format!("m{}", i)).collect::<Vec<_>>().join(", "));
+        status.push_str("\n\nPackage: leaf\nStatus: install ok installed\nVersion: 1\n");
+        for i in 1..=12 {
+            status.push_str(&format!("\nPackage: m{}\nStatus: install ok installed\nVersion: 1\nDepends: leaf\n", i));
+        }
+        let mut packages = Packages::from_text("", &status);
+        let auto = packages.installed_debvers.keys().copied()
+            .filter(|p| !["app", "hub"].contains(&packages.get_package_name(*p)))
+            .collect::<Vec<_>>();
+        packages.auto_installed.extend(auto);
+        packages
+    }
+
+    // the chains why prints for package_name, with at most limit of them
+    fn why(packages: &Packages, package_name: &str, limit: usize) -> Vec<String> {
+        let roots = packages.installed_debvers.keys().copied().filter(|p| !packages.auto_installed.contains(p)).collect::<Vec<_>>();
+        packages.why_paths(&roots, *packages.get_package_num(package_name), None, &RelationPolicy::default(), limit).iter()
+            .map(|chain| chain.iter()
+                .map(|step| format!("{} {} {}", packages.get_package_name(step.package_num), step.kind.verb(), packages.dep2str(step.dep)))
+                .collect::<Vec<_>>().join(", "))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_every_shortest_path_and_drops_longer_ones() {
+        let packages = installed();
+        assert_eq!(why(&packages, "libc", WHY_LIMIT), vec![
+            "app depends on libz | liby, libz pre-depends on libc",
+            "app depends on libz | liby, liby depends on libc",
+            "app depends on tool, tool depends on libc (>= 2)",
+        ]);
+        assert_eq!(why(&packages, "longest", WHY_LIMIT), vec!["app depends on longer, longer depends on longest"]);
+        assert_eq!(why(&packages, "tool", WHY_LIMIT), vec!["app depends on tool"]);
+    }
+
+    #[test]
+    fn lists_at_most_the_limit() {
+        let packages = installed();
+        let chains = why(&packages, "leaf", WHY_LIMIT);
+        assert_eq!(chains.iter().collect::<std::collections::HashSet<_>>().len(), WHY_LIMIT);
+        for chain in &chains {
+            let m = chain.strip_prefix("hub depends on ").unwrap().split(',').next().unwrap();
+            assert_eq!(*chain, format!("hub depends on {}, {} depends on leaf", m, m));
+        }
+        assert_eq!(why(&packages, "leaf", 20).len(), 12);
+    }
+
+    #[test]
+    fn finds_nothing_for_a_package_no_manual_package_needs() {
+        let packages = installed();
+        assert!(why(&packages, "unused", WHY_LIMIT).is_empty());
+    }
+}
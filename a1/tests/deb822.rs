@@ -0,0 +1,143 @@
+// Tests for rpkg::deb822: folded and multi-line fields, paragraph separation, and malformed paragraphs.
+
+use rpkg::deb822::{paragraphs, text_paragraphs, ParagraphError, Paragraph, ParseError};
+
+fn parse(text: &str) -> Vec<Result<Paragraph, ParseError>> {
+    paragraphs(text.as_bytes()).collect()
+}
+
+fn parse_ok(text: &str) -> Vec<Paragraph> {
+    parse(text).into_iter().map(|p| p.unwrap()).collect()
+}
+
+#[test]
+fn reads_fields_in_order() {
+    let ps = parse_ok("Package: foo\nVersion: 1.0-1\nArchitecture: amd64\n");
+    assert_eq!(ps.len(), 1);
+    let names: Vec<&str> = ps[0].fields.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, ["Package", "Version", "Architecture"]);
+    assert_eq!(ps[0].get("Version"), Some("1.0-1"));
+    assert_eq!(ps[0].field("Architecture").unwrap().line, 3);
+    assert_eq!(ps[0].get("Depends"), None);
+}
+
+#[test]
+fn field_names_are_case_insensitive() {
+    let ps = parse_ok("package: foo\nPRE-DEPENDS: bar\n");
+    assert_eq!(ps[0].get("Package"), Some("foo"));
+    assert_eq!(ps[0].get("Pre-Depends"), Some("bar"));
+}
+
+#[test]
+fn trims_values() {
+    let ps = parse_ok("Package:foo\nVersion:   2.0  \nEmpty:\n");
+    assert_eq!(ps[0].get("Package"), Some("foo"));
+    assert_eq!(ps[0].get("Version"), Some("2.0"));
+    assert_eq!(ps[0].get("Empty"), Some(""));
+}
+
+#[test]
+fn folds_continuation_lines() {
+    let text = "Package: foo\nDepends: libc6 (>= 2.36),\n libz1\n  | liby,\n\ttool\nVersion: 1\n";
+    let ps = parse_ok(text);
+    let depends = ps[0].field("Depends").unwrap();
+    assert_eq!(depends.value, "libc6 (>= 2.36),\n libz1\n  | liby,\n\ttool");
+    assert_eq!(depends.folded(), "libc6 (>= 2.36), libz1 | liby, tool");
+    assert_eq!(depends.line, 2);
+    assert_eq!(ps[0].field("Version").unwrap().line, 6);
+}
+
+#[test]
+fn keeps_multiline_fields() {
+    let text = "Package: foo\nDescription: short\n long line one\n .\n  indented\nConffiles:\n /etc/foo 0123abcd\n";
+    let ps = parse_ok(text);
+    assert_eq!(ps[0].get("Description"), Some("short\n long line one\n .\n  indented"));
+    assert_eq!(ps[0].get("Conffiles"), Some("\n /etc/foo 0123abcd"));
+}
+
+#[test]
+fn separates_paragraphs() {
+    // several blank lines, a line of whitespace, comments, and no newline at the end
+    let text = "\n\nPackage: a\n\n\n# comment\nPackage: b\n  \t\nPackage: c\n# inside\nVersion: 3";
+    let ps = parse_ok(text);
+    let names: Vec<&str> = ps.iter().map(|p| p.get("Package").unwrap()).collect();
+    assert_eq!(names, ["a", "b", "c"]);
+    let lines: Vec<usize> = ps.iter().map(|p| p.line).collect();
+    assert_eq!(lines, [3, 7, 9]);
+    assert_eq!(ps[2].get("Version"), Some("3"));
+}
+
+#[test]
+fn handles_crlf() {
+    let ps = parse_ok("Package: a\r\nDepends: b,\r\n c\r\n\r\nPackage: d\r\n");
+    assert_eq!(ps.len(), 2);
+    assert_eq!(ps[0].field("Depends").unwrap().folded(), "b, c");
+}
+
+#[test]
+fn empty_input_has_no_paragraphs() {
+    assert!(parse("").is_empty());
+    assert!(parse("\n\n# just a comment\n\n").is_empty());
+}
+
+// the line and error of each paragraph, or None for the good ones
+fn errors(text: &str) -> Vec<Option<(usize, String)>> {
+    parse(text).into_iter().map(|p| p.err().map(|e| (e.line, e.error.to_string()))).collect()
+}
+
+#[test]
+fn reports_malformed_paragraphs() {
+    let text = " starts indented\nPackage: a\n\nPackage: b\nnot a field\n\nPackage: c\nVersion: 1\nversion: 2\n\n: no name\n\nbad name: x\n";
+    assert_eq!(errors(text), [
+        Some((1, String::from("continuation line outside a field"))),
+        Some((5, String::from("expected a field, found \"not a field\""))),
+        Some((9, String::from("duplicate version field"))),
+        Some((11, String::from("bad field name \"\""))),
+        Some((13, String::from("bad field name \"bad name\""))),
+    ]);
+}
+
+#[test]
+fn skips_only_the_malformed_paragraph() {
+    let text = "Package: a\n\nPackage: b\noops\nVersion: 1\n continued\n\nPackage: c\n";
+    let ps = parse(text);
+    assert_eq!(ps.len(), 3);
+    assert_eq!(ps[0].as_ref().unwrap().get("Package"), Some("a"));
+    let e = ps[1].as_ref().unwrap_err();
+    assert_eq!(e.line, 4);
+    assert!(matches!(&e.error, ParagraphError::NoColon(l) if l == "oops"));
+    assert_eq!(ps[2].as_ref().unwrap().get("Package"), Some("c"));
+    assert_eq!(ps[2].as_ref().unwrap().line, 8);
+}
+
+#[test]
+fn comments_dont_continue_fields() {
+    let ps = parse_ok("Package: a\n# Depends: x\n b\n");
+    assert_eq!(ps[0].get("Package"), Some("a\n b"));
+    assert_eq!(ps[0].get("Depends"), None);
+}
+
+#[test]
+fn text_paragraphs_match_paragraphs() {
+    let text = "Package: a\nDepends: b,\n c\nDescription: x\n .\n  y\n\n# c\nPackage: b\noops\n\nPackage: c\nVersion: 1";
+    let borrowed: Vec<_> = text_paragraphs(text, 1).map(|p| p.map_err(|e| (e.line, e.error.to_string()))).collect();
+    let owned = parse(text);
+    assert_eq!(borrowed.len(), owned.len());
+    for (b, o) in borrowed.iter().zip(&owned) {
+        match (b, o) {
+            (Ok(b), Ok(o)) => assert_eq!(*b, o.borrow()),
+            (Err(b), Err(o)) => assert_eq!(*b, (o.line, o.error.to_string())),
+            _ => panic!("{:?} and {:?} differ", b, o)
+        }
+    }
+}
+
+#[test]
+fn text_paragraphs_count_lines_from_first_line() {
+    let ps: Vec<_> = text_paragraphs("\nPackage: a\nDepends: b,\n c  \n", 100).map(|p| p.unwrap()).collect();
+    assert_eq!(ps[0].line, 101);
+    let depends = ps[0].field("Depends").unwrap();
+    assert_eq!(depends.line, 102);
+    assert_eq!(depends.value, "b,\n c");
+    assert_eq!(depends.folded(), "b, c");
+}
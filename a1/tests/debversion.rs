@@ -0,0 +1,170 @@
+// Conformance tests for rpkg::debversion. Every ordering below agrees with dpkg --compare-versions.
+
+use std::cmp::Ordering;
+use std::cmp::Ordering::{Equal, Greater, Less};
+
+use rpkg::debversion::{cmp_debversion_with_op, DebianVersionNum, VersionError, VersionRelation, VersionSet};
+
+const VECTORS: &[(&str, &str, Ordering)] = &[
+// equality, epochs, and each part of the version (dpkg's lib/dpkg/t/t-version.c)
+    ("0", "0", Equal),
+    ("0", "00", Equal),
+    ("1:2-3", "1:2-3", Equal),
+    ("0-0", "1:0-0", Less),
+    ("0a-0", "0b-0", Less),
+    ("0-a", "0-b", Less),
+
+// dpkg --compare-versions cases (dpkg's scripts/t/Dpkg_Version.t)
+    ("1.0", "1.0", Equal),
+    ("1.0", "2.0", Less),
+    ("2.0", "1.0", Greater),
+    ("2.0.0", "2.0", Greater),
+    ("2.0", "2.0.0", Less),
+    ("2.0.0", "2.0.0", Equal),
+    ("1.0-1", "1.0-1", Equal),
+    ("1.0-1", "2.0-2", Less),
+    ("2.2~rc-4", "2.2-1", Less),
+    ("2.2-1", "2.2~rc-4", Greater),
+    ("1.0000-1", "1.0-1", Equal),
+    ("1", "0:1", Equal),
+    ("0", "0:0-0", Equal),
+    ("2:2.5", "1:7.5", Greater),
+    ("1:0foo", "0foo", Greater),
+    ("0:0foo", "0foo", Equal),
+    ("0foo", "0foo", Equal),
+    ("0foo-0", "0foo", Equal),
+    ("0foo", "0foo-0", Equal),
+    ("0foo", "0fo", Greater),
+    ("0foo-0", "0foo+", Less),
+    ("0foo~1", "0foo", Less),
+    ("0foo~foo+Bar", "0foo~foo+bar", Less),
+    ("0foo~~", "0foo~", Less),
+    ("1~", "1", Less),
+    ("12345+that-really-is-some-ver-0", "12345+that-really-is-some-ver-10", Less),
+    ("0foo-0", "0foo-01", Less),
+    ("0foo.bar", "0foobar", Greater),
+    ("0foo.bar", "0foo1bar", Greater),
+    ("0foo.bar", "0foo0bar", Greater),
+    ("0foo1bar-1", "0foobar-1", Less),
+    ("0foo2.0", "0foo2", Greater),
+    ("0foo2.0.0", "0foo2.10.0", Less),
+    ("0foo2.0", "0foo2.0.0", Less),
+    ("0foo2.0", "0foo2.10", Less),
+    ("0foo2.1", "0foo2.10", Less),
+    ("1.09", "1.9", Equal),
+    ("1.0.8+nmu1", "1.0.8", Greater),
+    ("3.11", "3.10+nmu1", Greater),
+    ("0.9j-20080306-4", "0.9i-20070324-2", Greater),
+    ("1.2.0~b7-1", "1.2.0~b6-1", Greater),
+    ("1.011-1", "1.06-2", Greater),
+    ("0.0.9+dfsg1-1", "0.0.8+dfsg1-3", Greater),
+    ("4.6.99+svn6582-1", "4.6.99+svn6496-1", Greater),
+    ("53", "52", Greater),
+    ("0.9.9~pre122-1", "0.9.9~pre111-1", Greater),
+    ("2:2.3.2-2+lenny2", "2:2.3.2-2", Greater),
+    ("1:3.8.1-1", "3.8.GA-1", Greater),
+    ("1.0.1+gpl-1", "1:1.0-1", Less),
+    ("1a", "1000a", Less),
+
+// numeric runs longer than any machine integer, as in date- and hash-based versions
+    ("20240101123456", "20231231235959", Greater),
+    ("2024010112345678901234567890", "2024010112345678901234567891", Less),
+    ("1.99999999999999999999", "1.100000000000000000000", Less),
+    ("0000000000000000000000001", "1", Equal),
+
+// the ordering of non-digit characters: ~ first, then the end of the part, then letters, then everything else
+    ("1.0~rc1~git123", "1.0~rc1", Less),
+    ("1.0~", "1.0~~", Greater),
+    ("1.0+", "1.0.", Less),
+    ("1.0a", "1.0+", Less),
+    ("1.0a", "1.0~", Greater),
+];
+
+fn v(s: &str) -> DebianVersionNum {
+    s.parse::<DebianVersionNum>().unwrap_or_else(|e| panic!("{:?}: {}", s, e))
+}
+
+#[test]
+fn compares_like_dpkg() {
+    for (a, b, expected) in VECTORS {
+        assert_eq!(v(a).cmp(&v(b)), *expected, "{} vs {}", a, b);
+        assert_eq!(v(b).cmp(&v(a)), expected.reverse(), "{} vs {}", b, a);
+        assert_eq!(v(a) == v(b), *expected == Equal, "{} == {}", a, b);
+    }
+}
+
+#[test]
+fn relations_follow_the_ordering() {
+    let cases = [
+        ("<<", "1.0~rc1", "1.0", true),
+        ("<<", "1.0", "1.0", false),
+        ("<=", "1.0", "1.00", true),
+        ("=", "0:1.0-1", "1.0-1", true),
+        (">=", "20240101123456", "20231231235959", true),
+        (">>", "1.0", "1.0+b1", false),
+    ];
+    for (op, a, b, expected) in cases {
+        let relation = op.parse::<VersionRelation>().unwrap();
+        assert_eq!(cmp_debversion_with_op(&relation, &v(a), &v(b)), expected, "{} {} {}", a, op, b);
+    }
+}
+
+#[test]
+fn displays_as_written() {
+    for s in ["1.0", "1:2.30-4+b1", "0:1.0", "2.2~rc-4", "1.0-1-2"] {
+        assert_eq!(v(s).to_string(), s);
+    }
+}
+
+#[test]
+fn rejects_invalid_versions() {
+    let cases = [
+        ("", VersionError::Empty),
+        ("x:1.0", VersionError::BadEpoch(String::from("x"))),
+        (":1.0", VersionError::BadEpoch(String::new())),
+        ("1:", VersionError::EmptyUpstream),
+        ("-1", VersionError::EmptyUpstream),
+        ("a1.0", VersionError::UpstreamStartsWithNonDigit(String::from("a1.0"))),
+        ("1.0_2", VersionError::BadCharacter('_', "upstream version")),
+        ("1.0 beta", VersionError::BadCharacter(' ', "upstream version")),
+        ("1.0-1_2", VersionError::BadCharacter('_', "Debian revision")),
+        ("1.0-", VersionError::EmptyRevision),
+    ];
+    for (s, expected) in cases {
+        assert_eq!(s.parse::<DebianVersionNum>().err(), Some(expected), "{:?}", s);
+    }
+    assert_eq!("<>".parse::<VersionRelation>().err(), Some(VersionError::BadRelation(String::from("<>"))));
+}
+
+fn set(op: &str, version: &str) -> VersionSet {
+    VersionSet::from_relation(&op.parse::<VersionRelation>().unwrap(), &v(version))
+}
+
+#[test]
+fn intersects_constraints() {
+    let range = set(">=", "1.2").intersect(&set("<<", "2.0"));
+    assert_eq!(range.to_string(), ">= 1.2, << 2.0");
+    assert!(range.contains(&v("1.5-1")) && range.contains(&v("1.2")) && !range.contains(&v("2.0")));
+    assert_eq!(range.intersect(&set("=", "1.5-1")).to_string(), "= 1.5-1");
+    assert!(range.intersect(&set("=", "2.0")).is_empty());
+    assert!(set(">>", "1.0").intersect(&set("<=", "1.0")).is_empty());
+    assert!(!set(">=", "1.0").intersect(&set("<=", "1.0")).is_empty());
+    assert!(set("<<", "1.0~").intersect(&set(">=", "1.0")).is_empty());
+    assert_eq!(set(">=", "1.0").intersect(&VersionSet::none()), VersionSet::none());
+}
+
+#[test]
+fn unions_constraints() {
+    let split = set("<<", "1.0").union(&set(">>", "2.0"));
+    assert_eq!(split.to_string(), "<< 1.0 | >> 2.0");
+    assert!(!split.contains(&v("1.5")) && split.contains(&v("0.9")) && split.contains(&v("2.0.1")));
+    assert_eq!(split.ranges().len(), 2);
+    assert!(split.intersect(&set("=", "1.5")).is_empty());
+    assert_eq!(split.intersect(&set(">=", "0.5")).to_string(), ">= 0.5, << 1.0 | >> 2.0");
+    // ranges that touch merge, but excluding the same version on both sides leaves a gap
+    assert_eq!(set("<<", "1.0").union(&set(">=", "1.0")), VersionSet::any());
+    assert_eq!(set("<=", "1.0").union(&set(">>", "1.0")).to_string(), "any");
+    assert_eq!(set("<<", "1.0").union(&set(">>", "1.0")).ranges().len(), 2);
+    assert_eq!(set("=", "1.0").union(&set("=", "1.00")).to_string(), "= 1.0");
+    assert!(VersionSet::none().union(&VersionSet::none()).is_empty());
+}